@@ -1,88 +1,737 @@
 #[cfg(target_arch = "x86_64")]
-use core::arch::x86_64::{_mm_cmpeq_epi8, _mm_loadu_si128, _mm_movemask_epi8, _mm_set1_epi8};
+use core::arch::x86_64::{
+    __m128i, _mm_broadcastb_epi8, _mm_cmpeq_epi8, _mm_cvtsi32_si128, _mm_loadu_si128,
+    _mm_movemask_epi8, _mm_set1_epi8,
+};
 
 #[cfg(target_arch = "x86")]
-use core::arch::x86::{_mm_cmpeq_epi8, _mm_loadu_si128, _mm_movemask_epi8, _mm_set1_epi8};
+use core::arch::x86::{
+    __m128i, _mm_broadcastb_epi8, _mm_cmpeq_epi8, _mm_cvtsi32_si128, _mm_loadu_si128,
+    _mm_movemask_epi8, _mm_set1_epi8,
+};
 
-use crate::art::ArtNodeType::{Node16, Node256, Node4, Node48};
-use std::u32;
+#[cfg(target_arch = "aarch64")]
+use core::arch::aarch64::{
+    vceqq_u8, vdupq_n_u8, vget_lane_u64, vld1q_u8, vreinterpret_u64_u8, vreinterpretq_u16_u8,
+    vshrn_n_u16,
+};
 
-const NODE4MIN: usize = 2;
-const NODE4MAX: usize = 4;
-const NODE4KEYS: usize = 4;
+use std::cmp::Ordering;
+use std::io;
+use std::mem::size_of;
+use std::ops::{Bound, Deref, RangeBounds};
+use std::sync::atomic::{AtomicU8, Ordering as AtomicOrdering};
+use std::sync::Arc;
 
-const NODE16MIN: usize = 5;
-const NODE16MAX: usize = 16;
-const NODE16KEYS: usize = 16;
+pub(crate) const NODE4MIN: usize = 2;
+pub(crate) const NODE4MAX: usize = 4;
+pub(crate) const NODE4KEYS: usize = 4;
 
-const NODE48MIN: usize = 17;
-const NODE48MAX: usize = 48;
-const NODE48KEYS: usize = 256;
+pub(crate) const NODE16MIN: usize = 5;
+pub(crate) const NODE16MAX: usize = 16;
+pub(crate) const NODE16KEYS: usize = 16;
 
-const NODE256MIN: usize = 49;
-const NODE256MAX: usize = 256;
-const NODE256KEYS: usize = 0;
+pub(crate) const NODE48MIN: usize = 17;
+pub(crate) const NODE48MAX: usize = 48;
+pub(crate) const NODE48KEYS: usize = 256;
 
-const PREFIX_LEN: usize = 10;
+pub(crate) const NODE256MIN: usize = 49;
+pub(crate) const NODE256MAX: usize = 256;
 
-pub(crate) struct ArtTree<'a> {
-    root: Option<&'a Node>,
+pub(crate) const PREFIX_LEN: usize = 10;
+
+// Nodes are shared by Arc, so that snapshot() takes a point-in-time view at once.
+// A writer copies only the nodes on its root-to-leaf path which a snapshot still holds.
+pub struct ArtTree<V> {
+    root: Option<Arc<Node<V>>>,
+    cache: NodeCache<V>,
 }
 
-impl<'a> Default for ArtTree<'a> {
+impl<V> Default for ArtTree<V> {
     #[inline]
     fn default() -> Self {
-        Self { root: None }
+        Self {
+            root: None,
+            cache: NodeCache::default(),
+        }
+    }
+}
+
+// A point-in-time view of ArtTree, it shares the nodes with the tree
+// and has only the reading methods of it.
+pub struct Snapshot<V> {
+    tree: ArtTree<V>,
+}
+
+impl<V> Clone for Snapshot<V> {
+    #[inline]
+    fn clone(&self) -> Self {
+        self.tree.snapshot()
     }
 }
 
-impl<'a> ArtTree<'a> {
+impl<V> Deref for Snapshot<V> {
+    type Target = ArtTree<V>;
+
+    #[inline]
+    fn deref(&self) -> &ArtTree<V> {
+        &self.tree
+    }
+}
+
+// Nodes from the node cache are not shared by any snapshot yet.
+#[inline]
+fn unique<V>(node: &mut Arc<Node<V>>) -> &mut Node<V> {
+    Arc::get_mut(node).unwrap()
+}
+
+impl<V> ArtTree<V> {
+    #[inline]
+    pub fn insert(&mut self, key: Vec<u8>, value_pos: V) -> Option<V>
+    where
+        V: Clone,
+    {
+        match &mut self.root {
+            Some(root) => {
+                let root = Arc::make_mut(root);
+                Self::insert_with_depth(root, &mut self.cache, key, value_pos, 0)
+            }
+            None => {
+                self.root = Some(self.cache.new_leaf(key, value_pos));
+                None
+            }
+        }
+    }
+
+    #[inline]
+    pub fn get(&self, key: &[u8]) -> Option<&V> {
+        match &self.root {
+            Some(root) => Self::get_with_depth(root, key, 0),
+            None => None,
+        }
+    }
+
+    #[inline]
+    pub fn remove(&mut self, key: &[u8]) -> Option<V>
+    where
+        V: Clone,
+    {
+        // A miss must not copy the path shared with snapshots.
+        self.get(key)?;
+        let root = self.root.as_mut()?;
+        let old = Self::remove_with_depth(Arc::make_mut(root), &mut self.cache, key, 0);
+        if root.is_empty() {
+            let root = self.root.take().unwrap();
+            self.cache.free_node(root);
+        }
+        old
+    }
+
+    // Builds the tree bottom-up from (key, value_pos) pairs in strictly ascending key order,
+    // each node is created with the type fitting its children, without growing.
+    pub fn from_sorted_iter<I>(iter: I) -> io::Result<ArtTree<V>>
+    where
+        I: IntoIterator<Item = (Vec<u8>, V)>,
+    {
+        let mut leaves: Vec<Option<Leaf<V>>> = Vec::new();
+        for (key, value_pos) in iter {
+            if let Some(Some(last)) = leaves.last() {
+                if key <= last.key {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "keys are not in strictly ascending order",
+                    ));
+                }
+            }
+            leaves.push(Some(Leaf { key, value_pos }));
+        }
+        let mut tree = ArtTree::default();
+        if !leaves.is_empty() {
+            tree.root = Some(Self::build(&mut tree.cache, &mut leaves, 0));
+        }
+        Ok(tree)
+    }
+
+    // Builds the subtree of the sorted leaves which share their first depth bytes.
+    fn build(
+        cache: &mut NodeCache<V>,
+        leaves: &mut [Option<Leaf<V>>],
+        depth: usize,
+    ) -> Arc<Node<V>> {
+        let mut node = cache.new_node();
+        let n = unique(&mut node);
+        n.count = leaves.len();
+        if leaves.len() == 1 {
+            n.leaf = leaves[0].take();
+            return node;
+        }
+
+        // The keys are sorted, so the first and the last ones share the fewest bytes.
+        let first = &leaves[0].as_ref().unwrap().key;
+        let last = &leaves[leaves.len() - 1].as_ref().unwrap().key;
+        let lcp = common_prefix_len(&first[depth..], &last[depth..]);
+        n.set_prefix(&first[depth..], lcp);
+        let depth = depth + lcp;
+        let ends_here = first.len() == depth;
+        let mut rest = leaves;
+        if ends_here {
+            n.leaf = rest[0].take();
+            rest = &mut rest[1..];
+        }
+
+        // Children are the runs of leaves with the same byte at depth.
+        let mut runs = Vec::new();
+        let mut start = 0;
+        for i in 1..=rest.len() {
+            let byte = |i: usize| rest[i].as_ref().unwrap().key[depth];
+            if i == rest.len() || byte(i) != byte(start) {
+                runs.push((byte(start), start..i));
+                start = i;
+            }
+        }
+        let typ = match runs.len() {
+            0..=NODE4MAX => ArtNodeType::Node4,
+            NODE16MIN..=NODE16MAX => ArtNodeType::Node16,
+            NODE48MIN..=NODE48MAX => ArtNodeType::Node48,
+            _ => ArtNodeType::Node256,
+        };
+        n.body = cache.new_body(typ);
+        n.size = runs.len() as u16;
+        for (i, (k, run)) in runs.into_iter().enumerate() {
+            let child = Self::build(cache, &mut rest[run], depth + 1);
+            unique(&mut node).body.put(i, k, child);
+        }
+        node
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.root.is_none()
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.root.as_ref().map_or(0, |root| root.count)
+    }
+
+    // Counts the keys less than the given key, from the leaf counts along its path.
+    pub fn rank(&self, key: &[u8]) -> usize {
+        let mut rank = 0;
+        let mut node = match self.root.as_deref() {
+            Some(root) => root,
+            None => return 0,
+        };
+        let mut depth = 0;
+        loop {
+            if node.is_pure_leaf() {
+                let less = node.leaf.as_ref().unwrap().key.as_slice() < key;
+                return rank + less as usize;
+            }
+            let p = node.prefix_mismatch(key, depth);
+            if p < node.prefix_len() {
+                // The key ends inside the compressed path or leaves it at p,
+                // either way the whole subtree is on one side of it.
+                if depth + p == key.len() {
+                    return rank;
+                }
+                let k = if p < PREFIX_LEN {
+                    node.prefix[p]
+                } else {
+                    node.minimum().key[depth + p]
+                };
+                return rank + if k < key[depth + p] { node.count } else { 0 };
+            }
+            depth += node.prefix_len();
+            if depth == key.len() {
+                return rank;
+            }
+            // The leaf of this node is a prefix of the key, so it is less.
+            rank += node.leaf.is_some() as usize;
+            rank += node.count_before(key[depth]);
+            match node.find_child(key[depth]) {
+                Some(child) => node = child,
+                None => return rank,
+            }
+            depth += 1;
+        }
+    }
+
+    // Finds the n-th key in lexicographic order, counting from 0.
+    pub fn nth(&self, mut n: usize) -> Option<(&[u8], &V)> {
+        let mut node = self.root.as_deref()?;
+        if n >= node.count {
+            return None;
+        }
+        loop {
+            if let Some(leaf) = &node.leaf {
+                if n == 0 {
+                    return Some((leaf.key.as_slice(), &leaf.value_pos));
+                }
+                n -= 1;
+            }
+            let mut pos = 0;
+            loop {
+                let (p, child) = node.next_child(pos)?;
+                if n < child.count {
+                    node = child;
+                    break;
+                }
+                n -= child.count;
+                pos = p + 1;
+            }
+        }
+    }
+
+    // Counts the keys in range without walking them.
+    pub fn count_range<R: RangeBounds<Vec<u8>>>(&self, range: R) -> usize {
+        let start = match range.start_bound() {
+            Bound::Included(start) => self.rank(start),
+            Bound::Excluded(start) => self.rank(start) + self.get(start).is_some() as usize,
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(end) => self.rank(end) + self.get(end).is_some() as usize,
+            Bound::Excluded(end) => self.rank(end),
+            Bound::Unbounded => self.len(),
+        };
+        end.saturating_sub(start)
+    }
+
+    // A read-only view of the tree as it is now, which later writes don't change.
+    #[inline]
+    pub fn snapshot(&self) -> Snapshot<V> {
+        Snapshot {
+            tree: ArtTree {
+                root: self.root.clone(),
+                cache: NodeCache::default(),
+            },
+        }
+    }
+
+    // Reports the memory held by the nodes, the leaf keys and the node cache.
+    // The heap memory owned by the values themselves is not counted.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let mut usage = MemoryUsage::default();
+        if let Some(root) = &self.root {
+            root.memory_usage(&mut usage);
+        }
+        usage.free_bytes = self.cache.free_bytes();
+        usage
+    }
+
+    // Walks all keys in lexicographic order.
     #[inline]
-    pub(crate) fn insert(&mut self, key: Vec<u8>, value_pos: (u8, u64, u64)) {
-        self.insert_with_depth(self.root, key, value_pos, &mut 0)
+    pub fn iter(&self) -> Iter<'_, V> {
+        let mut iter = Iter::default();
+        if let Some(root) = &self.root {
+            iter.push_all(root);
+        }
+        iter
     }
 
+    // Walks all keys in reverse lexicographic order.
     #[inline]
-    pub(crate) fn get(&self, key: Vec<u8>) -> Option<(u8, u64, u64)> {
-        self.get_with_depth(self.root, key, &mut 0)
+    pub fn iter_rev(&self) -> RevIter<'_, V> {
+        let mut iter = RevIter::default();
+        if let Some(root) = &self.root {
+            iter.push_all(root);
+        }
+        iter
     }
 
     #[inline]
-    pub(crate) fn remove(&mut self, key: Vec<u8>) {
-        self.remove_with_depth(self.root, key, &mut 0)
+    pub fn first(&self) -> Option<(&[u8], &V)> {
+        self.iter().next()
     }
 
     #[inline]
+    pub fn last(&self) -> Option<(&[u8], &V)> {
+        self.iter_rev().next()
+    }
+
+    // Finds the longest stored key which is a prefix of the given key.
+    // The leaves on the path are the only candidates, from the shortest to the longest.
+    pub fn longest_prefix_match(&self, key: &[u8]) -> Option<(&[u8], &V)> {
+        let mut best = None;
+        let mut node = self.root.as_deref()?;
+        let mut depth = 0;
+        loop {
+            // The compressed path is checked optimistically,
+            // so every candidate leaf is checked against the whole key.
+            if !node.is_pure_leaf() && !node.check_prefix(key, depth) {
+                return best;
+            }
+            if let Some(leaf) = &node.leaf {
+                if key.starts_with(&leaf.key) {
+                    best = Some((leaf.key.as_slice(), &leaf.value_pos));
+                }
+            }
+            if node.is_pure_leaf() {
+                return best;
+            }
+            depth += node.prefix_len();
+            if depth == key.len() {
+                return best;
+            }
+            match node.find_child(key[depth]) {
+                Some(child) => node = child,
+                None => return best,
+            }
+            depth += 1;
+        }
+    }
+
+    // Walks the keys in lexicographic order, from the first key >= the given key.
+    pub fn seek(&self, key: &[u8]) -> Iter<'_, V> {
+        let mut iter = Iter::default();
+        let mut node = match self.root.as_deref() {
+            Some(root) => root,
+            None => return iter,
+        };
+        let mut depth = 0;
+        loop {
+            if node.is_pure_leaf() {
+                if node.leaf.as_ref().unwrap().key.as_slice() >= key {
+                    iter.push_all(node);
+                }
+                return iter;
+            }
+
+            if node.prefix_len() > 0 {
+                let prefix = &node.minimum().key[depth..depth + node.prefix_len()];
+                let n = node.prefix_len().min(key.len() - depth);
+                match prefix[..n].cmp(&key[depth..depth + n]) {
+                    // The whole subtree is less than key.
+                    Ordering::Less => return iter,
+                    // The whole subtree is greater than key.
+                    Ordering::Greater => {
+                        iter.push_all(node);
+                        return iter;
+                    }
+                    Ordering::Equal if n < node.prefix_len() => {
+                        iter.push_all(node);
+                        return iter;
+                    }
+                    Ordering::Equal => {}
+                }
+            }
+            depth += node.prefix_len();
+
+            if depth == key.len() {
+                iter.push_all(node);
+                return iter;
+            }
+            // The leaf of node is less than key, and so are the children before key[depth].
+            let k = key[depth];
+            iter.stack.push((node, node.child_pos_after(k)));
+            match node.find_child(k) {
+                Some(child) => {
+                    node = child;
+                    depth += 1;
+                }
+                None => return iter,
+            }
+        }
+    }
+
+    // depth is the count of key bytes consumed before the prefix of node.
     fn insert_with_depth(
-        &mut self,
-        node: Option<&Node>,
+        node: &mut Node<V>,
+        cache: &mut NodeCache<V>,
         key: Vec<u8>,
-        value_pos: (u8, u64, u64),
-        depth: &mut usize,
-    ) {
-        *depth += 1;
-        self.insert_with_depth(node, key, value_pos, depth)
+        value_pos: V,
+        depth: usize,
+    ) -> Option<V>
+    where
+        V: Clone,
+    {
+        if node.is_pure_leaf() {
+            let leaf = node.leaf.as_mut().unwrap();
+            if leaf.key == key {
+                return Some(std::mem::replace(&mut leaf.value_pos, value_pos));
+            }
+            // Lazy expansion: the leaf was the only key of this subtree,
+            // now split it by the common bytes of the two keys.
+            let lcp = common_prefix_len(&leaf.key[depth..], &key[depth..]);
+            let mut old_leaf = cache.new_node();
+            std::mem::swap(node, unique(&mut old_leaf));
+            node.set_prefix(&key[depth..depth + lcp], lcp);
+            let depth = depth + lcp;
+            node.add_leaf(cache, old_leaf, depth);
+            let new_leaf = cache.new_leaf(key, value_pos);
+            node.add_leaf(cache, new_leaf, depth);
+            node.count = 2;
+            return None;
+        }
+
+        if node.prefix_len() > 0 {
+            let p = node.prefix_mismatch(&key, depth);
+            if p < node.prefix_len() {
+                // The key leaves the compressed path at p, split the path there.
+                let k = node.cut_prefix(p, depth);
+                let mut old_node = cache.new_node();
+                std::mem::swap(node, unique(&mut old_node));
+                node.set_prefix(&key[depth..depth + p], p);
+                node.count = old_node.count + 1;
+                node.add_child(cache, k, old_node);
+                let new_leaf = cache.new_leaf(key, value_pos);
+                node.add_leaf(cache, new_leaf, depth + p);
+                return None;
+            }
+        }
+        let depth = depth + node.prefix_len();
+
+        if depth == key.len() {
+            return match &mut node.leaf {
+                Some(leaf) => Some(std::mem::replace(&mut leaf.value_pos, value_pos)),
+                None => {
+                    node.leaf = Some(Leaf { key, value_pos });
+                    node.count += 1;
+                    None
+                }
+            };
+        }
+        let k = key[depth];
+        if let Some(child) = node.find_child_mut(k) {
+            let old = Self::insert_with_depth(child, cache, key, value_pos, depth + 1);
+            if old.is_none() {
+                node.count += 1;
+            }
+            return old;
+        }
+        let new_leaf = cache.new_leaf(key, value_pos);
+        node.add_child(cache, k, new_leaf);
+        node.count += 1;
+        None
+    }
+
+    fn get_with_depth<'a>(node: &'a Node<V>, key: &[u8], depth: usize) -> Option<&'a V> {
+        if node.is_pure_leaf() {
+            return node.leaf_value(key);
+        }
+        if !node.check_prefix(key, depth) {
+            return None;
+        }
+        let depth = depth + node.prefix_len();
+        if depth == key.len() {
+            return node.leaf_value(key);
+        }
+        match node.find_child(key[depth]) {
+            Some(child) => Self::get_with_depth(child, key, depth + 1),
+            None => None,
+        }
+    }
+
+    fn remove_with_depth(
+        node: &mut Node<V>,
+        cache: &mut NodeCache<V>,
+        key: &[u8],
+        depth: usize,
+    ) -> Option<V>
+    where
+        V: Clone,
+    {
+        if node.is_pure_leaf() {
+            node.leaf_value(key)?;
+            node.count = 0;
+            return node.leaf.take().map(|leaf| leaf.value_pos);
+        }
+        if !node.check_prefix(key, depth) {
+            return None;
+        }
+        let depth = depth + node.prefix_len();
+        let old = if depth == key.len() {
+            node.leaf_value(key)?;
+            node.leaf.take().map(|leaf| leaf.value_pos)
+        } else {
+            let k = key[depth];
+            let child = node.find_child_mut(k)?;
+            let old = Self::remove_with_depth(child, cache, key, depth + 1);
+            // Drop the branch which holds nothing anymore.
+            if child.is_empty() {
+                node.delete_child(cache, k);
+            }
+            old
+        };
+        if old.is_some() {
+            node.count -= 1;
+        }
+        node.collapse(cache);
+        old
+    }
+
+    // Walks the keys in reverse lexicographic order, from the last key <= the given key.
+    pub fn seek_for_prev(&self, key: &[u8]) -> RevIter<'_, V> {
+        let mut iter = RevIter::default();
+        let mut node = match self.root.as_deref() {
+            Some(root) => root,
+            None => return iter,
+        };
+        let mut depth = 0;
+        loop {
+            if node.is_pure_leaf() {
+                if node.leaf.as_ref().unwrap().key.as_slice() <= key {
+                    iter.push_all(node);
+                }
+                return iter;
+            }
+
+            if node.prefix_len() > 0 {
+                let prefix = &node.minimum().key[depth..depth + node.prefix_len()];
+                let n = node.prefix_len().min(key.len() - depth);
+                match prefix[..n].cmp(&key[depth..depth + n]) {
+                    // The whole subtree is less than key.
+                    Ordering::Less => {
+                        iter.push_all(node);
+                        return iter;
+                    }
+                    // The whole subtree is greater than key.
+                    Ordering::Greater => return iter,
+                    Ordering::Equal if n < node.prefix_len() => return iter,
+                    Ordering::Equal => {}
+                }
+            }
+            depth += node.prefix_len();
+
+            // The leaf of node is less than or equal to key,
+            // and so are the children before key[depth].
+            if depth == key.len() {
+                iter.stack.push((node, 0));
+                return iter;
+            }
+            let k = key[depth];
+            iter.stack.push((node, node.child_pos_before(k)));
+            match node.find_child(k) {
+                Some(child) => {
+                    node = child;
+                    depth += 1;
+                }
+                None => return iter,
+            }
+        }
     }
+}
 
+// Iterator over the keys and value_pos of ArtTree in lexicographic order.
+pub struct Iter<'a, V> {
+    // The nodes on the path and the position of the next child to walk in each one.
+    stack: Vec<(&'a Node<V>, usize)>,
+    // The leaf to yield before walking the children of the top node.
+    pending: Option<&'a Leaf<V>>,
+}
+
+impl<'a, V> Default for Iter<'a, V> {
     #[inline]
-    fn get_with_depth(
-        &self,
-        node: Option<&Node>,
-        key: Vec<u8>,
-        depth: &mut usize,
-    ) -> Option<(u8, u64, u64)> {
-        *depth += 1;
-        self.get_with_depth(node, key, depth)
+    fn default() -> Self {
+        Self {
+            stack: Vec::new(),
+            pending: None,
+        }
+    }
+}
+
+impl<'a, V> Iter<'a, V> {
+    // Walks the whole subtree of node, starting with its leaf.
+    #[inline]
+    fn push_all(&mut self, node: &'a Node<V>) {
+        self.stack.push((node, 0));
+        self.pending = node.leaf.as_ref();
+    }
+}
+
+impl<'a, V> Iterator for Iter<'a, V> {
+    type Item = (&'a [u8], &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(leaf) = self.pending.take() {
+            return Some((&leaf.key, &leaf.value_pos));
+        }
+        loop {
+            let (node, pos) = self.stack.last_mut()?;
+            match node.next_child(*pos) {
+                Some((next_pos, child)) => {
+                    *pos = next_pos + 1;
+                    self.stack.push((child, 0));
+                    if let Some(leaf) = &child.leaf {
+                        return Some((&leaf.key, &leaf.value_pos));
+                    }
+                }
+                None => {
+                    self.stack.pop();
+                }
+            }
+        }
+    }
+}
+
+// Iterator over the keys and value_pos of ArtTree in reverse lexicographic order.
+pub struct RevIter<'a, V> {
+    // The nodes on the path and the position of the last walked child in each one,
+    // a node yields its leaf after all of its children.
+    stack: Vec<(&'a Node<V>, usize)>,
+}
+
+impl<'a, V> Default for RevIter<'a, V> {
+    #[inline]
+    fn default() -> Self {
+        Self { stack: Vec::new() }
     }
+}
 
+impl<'a, V> RevIter<'a, V> {
+    // Walks the whole subtree of node, ending with its leaf.
     #[inline]
-    fn remove_with_depth(&mut self, node: Option<&Node>, key: Vec<u8>, depth: &mut usize) {
-        *depth += 1;
-        self.remove_with_depth(node, key, depth)
+    fn push_all(&mut self, node: &'a Node<V>) {
+        self.stack.push((node, node.end_pos()));
+    }
+}
+
+impl<'a, V> Iterator for RevIter<'a, V> {
+    type Item = (&'a [u8], &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (node, pos) = self.stack.last_mut()?;
+            match node.prev_child(*pos) {
+                Some((prev_pos, child)) => {
+                    *pos = prev_pos;
+                    self.push_all(child);
+                }
+                None => {
+                    let node = *node;
+                    self.stack.pop();
+                    if let Some(leaf) = &node.leaf {
+                        return Some((&leaf.key, &leaf.value_pos));
+                    }
+                }
+            }
+        }
     }
 }
 
-#[derive(PartialEq, Clone)]
+#[inline]
+pub(crate) fn common_prefix_len(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b.iter()).take_while(|(x, y)| x == y).count()
+}
+
+// Memory held by an ArtTree, see ArtTree::memory_usage.
+// Each node, inner node body and leaf key is a heap allocation of its own.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    // Count of inner nodes of each type.
+    pub node4: usize,
+    pub node16: usize,
+    pub node48: usize,
+    pub node256: usize,
+    // Count of keys.
+    pub leaves: usize,
+    // Bytes of the nodes, inner node bodies and leaf keys in the tree.
+    pub used_bytes: usize,
+    // Bytes of the freed nodes and bodies kept for reuse by the node cache.
+    pub free_bytes: usize,
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
 pub(crate) enum ArtNodeType {
     // key 4
     // children 4
@@ -100,257 +749,875 @@ pub(crate) enum ArtNodeType {
     Node256,
 }
 
+// How Node16 looks up a key byte among its 16 keys.
+// The fastest one supported by the CPU is detected at the first lookup.
+#[repr(u8)]
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub(crate) enum Node16Search {
+    Scalar = 1,
+    Sse2 = 2,
+    Avx2 = 3,
+    Neon = 4,
+}
+
+// 0 means not detected yet, it is only set by the detection.
+static NODE16_SEARCH: AtomicU8 = AtomicU8::new(0);
+
+impl Node16Search {
+    #[inline]
+    fn detect() -> Node16Search {
+        [Node16Search::Avx2, Node16Search::Sse2, Node16Search::Neon]
+            .iter()
+            .copied()
+            .find(|search| search.is_supported())
+            .unwrap_or(Node16Search::Scalar)
+    }
+
+    pub(crate) fn is_supported(self) -> bool {
+        match self {
+            Node16Search::Scalar => true,
+            #[cfg(any(target_arch = "x86_64", target_arch = "x86"))]
+            Node16Search::Sse2 => is_x86_feature_detected!("sse2"),
+            #[cfg(any(target_arch = "x86_64", target_arch = "x86"))]
+            Node16Search::Avx2 => is_x86_feature_detected!("avx2"),
+            #[cfg(target_arch = "aarch64")]
+            Node16Search::Neon => std::arch::is_aarch64_feature_detected!("neon"),
+            #[allow(unreachable_patterns)]
+            _ => false,
+        }
+    }
+}
+
+// The Node16 search in use.
+#[inline]
+pub(crate) fn node16_search() -> Node16Search {
+    match NODE16_SEARCH.load(AtomicOrdering::Relaxed) {
+        1 => Node16Search::Scalar,
+        2 => Node16Search::Sse2,
+        3 => Node16Search::Avx2,
+        4 => Node16Search::Neon,
+        _ => {
+            let search = Node16Search::detect();
+            NODE16_SEARCH.store(search as u8, AtomicOrdering::Relaxed);
+            search
+        }
+    }
+}
+
+// search must be supported by the CPU.
+#[inline]
+fn index16(search: Node16Search, keys: &[u8; NODE16KEYS], size: usize, k: u8) -> Option<usize> {
+    match search {
+        #[cfg(any(target_arch = "x86_64", target_arch = "x86"))]
+        Node16Search::Sse2 => unsafe { index16_sse2(keys, size, k) },
+        #[cfg(any(target_arch = "x86_64", target_arch = "x86"))]
+        Node16Search::Avx2 => unsafe { index16_avx2(keys, size, k) },
+        #[cfg(target_arch = "aarch64")]
+        Node16Search::Neon => unsafe { index16_neon(keys, size, k) },
+        _ => index16_scalar(keys, size, k),
+    }
+}
+
+#[inline]
+fn index16_scalar(keys: &[u8; NODE16KEYS], size: usize, k: u8) -> Option<usize> {
+    keys[..size].iter().position(|key| *key == k)
+}
+
+// bit_field has one bit for each key which equals the key byte,
+// the unused keys are masked out by size.
+#[inline]
+fn first_match(bit_field: u32, size: usize) -> Option<usize> {
+    let mask = (1_u32 << size) - 1;
+    match bit_field & mask {
+        0 => None,
+        bit_field => Some(bit_field.trailing_zeros() as usize),
+    }
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "x86"))]
+#[target_feature(enable = "sse2")]
+unsafe fn index16_sse2(keys: &[u8; NODE16KEYS], size: usize, k: u8) -> Option<usize> {
+    let key = _mm_set1_epi8(k as i8);
+    let key2 = _mm_loadu_si128(keys.as_ptr() as *const __m128i);
+    let cmp = _mm_cmpeq_epi8(key, key2);
+    first_match(_mm_movemask_epi8(cmp) as u32, size)
+}
+
+// The same compare as SSE2 in VEX encoding, with the key byte broadcast by AVX2.
+#[cfg(any(target_arch = "x86_64", target_arch = "x86"))]
+#[target_feature(enable = "avx2")]
+unsafe fn index16_avx2(keys: &[u8; NODE16KEYS], size: usize, k: u8) -> Option<usize> {
+    let key = _mm_broadcastb_epi8(_mm_cvtsi32_si128(k as i32));
+    let key2 = _mm_loadu_si128(keys.as_ptr() as *const __m128i);
+    let cmp = _mm_cmpeq_epi8(key, key2);
+    first_match(_mm_movemask_epi8(cmp) as u32, size)
+}
+
+// NEON has no movemask, the compare result is narrowed to 4 bits for each key.
+#[cfg(target_arch = "aarch64")]
+#[target_feature(enable = "neon")]
+unsafe fn index16_neon(keys: &[u8; NODE16KEYS], size: usize, k: u8) -> Option<usize> {
+    let cmp = vceqq_u8(vdupq_n_u8(k), vld1q_u8(keys.as_ptr()));
+    let narrowed = vshrn_n_u16::<4>(vreinterpretq_u16_u8(cmp));
+    first_nibble_match(vget_lane_u64::<0>(vreinterpret_u64_u8(narrowed)), size)
+}
+
+// nibbles has 4 bits for each key which equals the key byte,
+// the unused keys are masked out by size.
+#[cfg(any(target_arch = "aarch64", test))]
+#[inline]
+fn first_nibble_match(nibbles: u64, size: usize) -> Option<usize> {
+    let mask = u64::MAX.checked_shr(64 - 4 * size as u32).unwrap_or(0);
+    match nibbles & mask {
+        0 => None,
+        nibbles => Some(nibbles.trailing_zeros() as usize / 4),
+    }
+}
+
+// The NEON search in plain Rust: vceqq_u8, then vshrn_n_u16 by 4 of each pair of
+// compare bytes, read as one little-endian u64, and first_nibble_match as index16_neon.
+// The NEON path only runs on aarch64, so this is how the other targets test its logic.
+#[cfg(test)]
+fn index16_nibbles(keys: &[u8; NODE16KEYS], size: usize, k: u8) -> Option<usize> {
+    let mut narrowed = [0_u8; 8];
+    for (i, pair) in keys.chunks_exact(2).enumerate() {
+        let lo = if pair[0] == k { 0xff_u16 } else { 0 };
+        let hi = if pair[1] == k { 0xff_u16 } else { 0 };
+        narrowed[i] = ((lo | hi << 8) >> 4) as u8;
+    }
+    first_nibble_match(u64::from_le_bytes(narrowed), size)
+}
+
+type Child<V> = Option<Arc<Node<V>>>;
+
+// Node4, Node16: the sorted key bytes of children, and children in keys order.
+#[derive(Clone)]
+pub(crate) struct Body4<V> {
+    keys: [u8; NODE4KEYS],
+    children: [Child<V>; NODE4MAX],
+}
+
+#[derive(Clone)]
+pub(crate) struct Body16<V> {
+    keys: [u8; NODE16KEYS],
+    children: [Child<V>; NODE16MAX],
+}
+
+// Node48: key byte --> (slot of children + 1), 0 means empty.
 #[derive(Clone)]
-pub(crate) struct Node {
-    typ: ArtNodeType,
-    keys: Vec<u8>,
-    children: Vec<Node>,
+pub(crate) struct Body48<V> {
+    keys: [u8; NODE48KEYS],
+    children: [Child<V>; NODE48MAX],
+}
 
-    // only leaf node has it.
-    // (kv_log_index, value_offset, length)
-    value_pos: Option<(u8, u64, u64)>,
+// Node256: children are indexed by the key byte.
+#[derive(Clone)]
+pub(crate) struct Body256<V> {
+    children: [Child<V>; NODE256MAX],
 }
 
-impl Node {
+// The keys and children of a node, in fixed-size arrays of its type.
+// A node which never had children has no body.
+#[derive(Clone)]
+pub(crate) enum Body<V> {
+    Empty,
+    Node4(Box<Body4<V>>),
+    Node16(Box<Body16<V>>),
+    Node48(Box<Body48<V>>),
+    Node256(Box<Body256<V>>),
+}
+
+impl<V> Body<V> {
     #[inline]
-    pub(crate) fn new_node(typ: ArtNodeType) -> Node {
-        let (key_cap, children_cap) = match typ {
-            ArtNodeType::Node4 => (NODE4KEYS, NODE4MAX),
-            ArtNodeType::Node16 => (NODE16KEYS, NODE16MAX),
-            ArtNodeType::Node48 => (NODE48KEYS, NODE48MAX),
-            ArtNodeType::Node256 => (NODE256KEYS, NODE256MAX),
-        };
+    fn children(&self) -> &[Child<V>] {
+        match self {
+            Body::Empty => &[],
+            Body::Node4(b) => &b.children,
+            Body::Node16(b) => &b.children,
+            Body::Node48(b) => &b.children,
+            Body::Node256(b) => &b.children,
+        }
+    }
 
-        Node {
-            typ,
-            keys: Vec::with_capacity(key_cap),
-            children: Vec::with_capacity(children_cap),
-            value_pos: None,
+    #[inline]
+    fn children_mut(&mut self) -> &mut [Child<V>] {
+        match self {
+            Body::Empty => &mut [],
+            Body::Node4(b) => &mut b.children,
+            Body::Node16(b) => &mut b.children,
+            Body::Node48(b) => &mut b.children,
+            Body::Node256(b) => &mut b.children,
         }
     }
 
+    // Moves the first size children out in key order.
     #[inline]
-    fn index(&self, k: u8) -> Option<usize> {
-        match &self.typ {
-            ArtNodeType::Node4 => {
-                for i in 0..self.get_child_size() {
-                    if *self.keys.get(i).unwrap() == k {
-                        return Some(i);
+    fn drain(&mut self, size: usize, mut f: impl FnMut(u8, Arc<Node<V>>)) {
+        match self {
+            Body::Empty => {}
+            Body::Node4(b) => drain_sorted(&b.keys, &mut b.children, size, f),
+            Body::Node16(b) => drain_sorted(&b.keys, &mut b.children, size, f),
+            Body::Node48(b) => {
+                for k in 0..NODE48KEYS {
+                    let slot = std::mem::take(&mut b.keys[k]);
+                    if slot != 0 {
+                        if let Some(child) = b.children[slot as usize - 1].take() {
+                            f(k as u8, child);
+                        }
                     }
                 }
-                None
             }
-
-            ArtNodeType::Node16 => unsafe {
-                #[cfg(all(
-                    any(target_arch = "x86_64", target_arch = "x86"),
-                    target_feature = "sse2"
-                ))]
-                {
-                    let key = _mm_set1_epi8(k as i8);
-                    let key2 = _mm_loadu_si128(self.keys.as_ptr() as *const _);
-                    let cmp = _mm_cmpeq_epi8(key, key2);
-                    let mask = (1 << self.get_child_size()) - 1;
-                    let bit_field = _mm_movemask_epi8(cmp) & (mask as i32);
-                    if bit_field > 0 {
-                        let u32_bit_field = bit_field as u32;
-                        Some(u32_bit_field.trailing_zeros() as usize)
-                    } else {
-                        None
+            Body::Node256(b) => {
+                for (k, child) in b.children.iter_mut().enumerate() {
+                    if let Some(child) = child.take() {
+                        f(k as u8, child);
                     }
                 }
-            },
+            }
+        }
+    }
 
-            ArtNodeType::Node48 => {
-                if let Some(key) = self.keys.get(k as usize) {
-                    Some(*key as usize)
-                } else {
-                    None
+    // Puts the i-th child in key order, used to fill a new body.
+    #[inline]
+    fn put(&mut self, i: usize, k: u8, child: Arc<Node<V>>) {
+        match self {
+            Body::Empty => unreachable!("an empty body has no children"),
+            Body::Node4(b) => {
+                b.keys[i] = k;
+                b.children[i] = Some(child);
+            }
+            Body::Node16(b) => {
+                b.keys[i] = k;
+                b.children[i] = Some(child);
+            }
+            Body::Node48(b) => {
+                // i + 1 is safe as u8, because the most is 48.
+                b.keys[k as usize] = (i + 1) as u8;
+                b.children[i] = Some(child);
+            }
+            Body::Node256(b) => b.children[k as usize] = Some(child),
+        }
+    }
+}
+
+#[inline]
+fn drain_sorted<V>(
+    keys: &[u8],
+    children: &mut [Child<V>],
+    size: usize,
+    mut f: impl FnMut(u8, Arc<Node<V>>),
+) {
+    for (k, child) in keys.iter().zip(children[..size].iter_mut()) {
+        if let Some(child) = child.take() {
+            f(*k, child);
+        }
+    }
+}
+
+// Keeps keys sorted, so that children can be walked in order.
+#[inline]
+fn insert_sorted<V>(
+    keys: &mut [u8],
+    children: &mut [Child<V>],
+    size: usize,
+    key: u8,
+    node: Arc<Node<V>>,
+) {
+    let idx = keys[..size].iter().position(|k| key < *k).unwrap_or(size);
+    keys.copy_within(idx..size, idx + 1);
+    children[idx..=size].rotate_right(1);
+    keys[idx] = key;
+    children[idx] = Some(node);
+}
+
+#[inline]
+fn remove_sorted<V>(
+    keys: &mut [u8],
+    children: &mut [Child<V>],
+    size: usize,
+    idx: usize,
+) -> Child<V> {
+    let child = children[idx].take();
+    keys.copy_within(idx + 1..size, idx);
+    children[idx..size].rotate_left(1);
+    child
+}
+
+#[inline]
+fn empty_children<V, const N: usize>() -> [Child<V>; N] {
+    std::array::from_fn(|_| None)
+}
+
+// The most of freed nodes or bodies of each type kept by the cache.
+const CACHE_MAX: usize = 1024;
+
+// A reuse cache of freed nodes and bodies. It is not a slab or an arena, and nodes are not
+// allocated from one: every node and body is still its own allocation from the global allocator,
+// children are Arcs and not indices into a slab, and every leaf owns its key in a Vec.
+// The nodes are shared with the snapshots by Arc, a slab would need counts of its own
+// for its slots, and the nodes of a dropped snapshot freed from the thread which drops it.
+// Up to CACHE_MAX of each are kept when freed and handed out again, so that churn of splitting,
+// growing and shrinking nodes skips the allocator for them. It does not help a bulk load,
+// which frees few nodes, so that each of its new nodes comes from the allocator.
+// The boxes themselves are what gets reused, so they stay boxed in the free lists.
+#[allow(clippy::vec_box)]
+pub(crate) struct NodeCache<V> {
+    nodes: Vec<Arc<Node<V>>>,
+    node4: Vec<Box<Body4<V>>>,
+    node16: Vec<Box<Body16<V>>>,
+    node48: Vec<Box<Body48<V>>>,
+    node256: Vec<Box<Body256<V>>>,
+}
+
+impl<V> Default for NodeCache<V> {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            nodes: Vec::new(),
+            node4: Vec::new(),
+            node16: Vec::new(),
+            node48: Vec::new(),
+            node256: Vec::new(),
+        }
+    }
+}
+
+impl<V> NodeCache<V> {
+    // A node without prefix, leaf and children.
+    #[inline]
+    pub(crate) fn new_node(&mut self) -> Arc<Node<V>> {
+        self.nodes.pop().unwrap_or_else(|| {
+            Arc::new(Node {
+                prefix: [0; PREFIX_LEN],
+                prefix_len: 0,
+                size: 0,
+                count: 0,
+                leaf: None,
+                body: Body::Empty,
+            })
+        })
+    }
+
+    #[inline]
+    pub(crate) fn new_leaf(&mut self, key: Vec<u8>, value_pos: V) -> Arc<Node<V>> {
+        let mut node = self.new_node();
+        let n = unique(&mut node);
+        n.leaf = Some(Leaf { key, value_pos });
+        n.count = 1;
+        node
+    }
+
+    #[inline]
+    pub(crate) fn new_body(&mut self, typ: ArtNodeType) -> Body<V> {
+        match typ {
+            ArtNodeType::Node4 => Body::Node4(self.node4.pop().unwrap_or_else(|| {
+                Box::new(Body4 {
+                    keys: [0; NODE4KEYS],
+                    children: empty_children(),
+                })
+            })),
+            ArtNodeType::Node16 => Body::Node16(self.node16.pop().unwrap_or_else(|| {
+                Box::new(Body16 {
+                    keys: [0; NODE16KEYS],
+                    children: empty_children(),
+                })
+            })),
+            ArtNodeType::Node48 => Body::Node48(self.node48.pop().unwrap_or_else(|| {
+                Box::new(Body48 {
+                    keys: [0; NODE48KEYS],
+                    children: empty_children(),
+                })
+            })),
+            ArtNodeType::Node256 => Body::Node256(self.node256.pop().unwrap_or_else(|| {
+                Box::new(Body256 {
+                    children: empty_children(),
+                })
+            })),
+        }
+    }
+
+    // Takes back a node which has been unlinked from the tree, with its body.
+    // A node still held by a snapshot is left to it.
+    #[inline]
+    pub(crate) fn free_node(&mut self, mut node: Arc<Node<V>>) {
+        let n = match Arc::get_mut(&mut node) {
+            Some(n) => n,
+            None => return,
+        };
+        let body = std::mem::replace(&mut n.body, Body::Empty);
+        self.free_body(body);
+        n.leaf = None;
+        n.prefix_len = 0;
+        n.size = 0;
+        n.count = 0;
+        if self.nodes.len() < CACHE_MAX {
+            self.nodes.push(node);
+        }
+    }
+
+    // Bodies are reset before reuse, Node4 and Node16 keys are bounded by size,
+    // but a stale Node48 key would point to an empty slot.
+    #[inline]
+    pub(crate) fn free_body(&mut self, mut body: Body<V>) {
+        for child in body.children_mut() {
+            *child = None;
+        }
+        match body {
+            Body::Empty => {}
+            Body::Node4(b) => {
+                if self.node4.len() < CACHE_MAX {
+                    self.node4.push(b);
                 }
             }
+            Body::Node16(b) => {
+                if self.node16.len() < CACHE_MAX {
+                    self.node16.push(b);
+                }
+            }
+            Body::Node48(mut b) => {
+                if self.node48.len() < CACHE_MAX {
+                    b.keys = [0; NODE48KEYS];
+                    self.node48.push(b);
+                }
+            }
+            Body::Node256(b) => {
+                if self.node256.len() < CACHE_MAX {
+                    self.node256.push(b);
+                }
+            }
+        }
+    }
+
+    #[inline]
+    fn free_bytes(&self) -> usize {
+        self.nodes.len() * size_of::<Node<V>>()
+            + self.node4.len() * size_of::<Body4<V>>()
+            + self.node16.len() * size_of::<Body16<V>>()
+            + self.node48.len() * size_of::<Body48<V>>()
+            + self.node256.len() * size_of::<Body256<V>>()
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct Leaf<V> {
+    key: Vec<u8>,
+    value_pos: V,
+}
+
+#[derive(Clone)]
+pub(crate) struct Node<V> {
+    // The compressed path before this node, only the first PREFIX_LEN bytes are kept,
+    // the rest of them are checked with the full key in the leaf.
+    prefix: [u8; PREFIX_LEN],
+    prefix_len: u32,
+    size: u16,
+    // The count of leaves in this subtree, including the leaf of this node.
+    count: usize,
+
+    // A node without children is a leaf,
+    // otherwise the leaf is the key which ends right after the prefix of this node.
+    leaf: Option<Leaf<V>>,
+    body: Body<V>,
+}
+
+impl<V> Node<V> {
+    #[inline]
+    fn prefix_len(&self) -> usize {
+        self.prefix_len as usize
+    }
+
+    #[inline]
+    fn set_prefix(&mut self, prefix: &[u8], prefix_len: usize) {
+        let n = prefix_len.min(PREFIX_LEN).min(prefix.len());
+        self.prefix[..n].copy_from_slice(&prefix[..n]);
+        self.prefix_len = prefix_len as u32;
+    }
+
+    // Pessimistic check of the stored prefix bytes,
+    // the skipped ones are checked optimistically with the full key in the leaf.
+    #[inline]
+    fn check_prefix(&self, key: &[u8], depth: usize) -> bool {
+        if key.len() < depth + self.prefix_len() {
+            return false;
+        }
+        let n = self.prefix_len().min(PREFIX_LEN);
+        self.prefix[..n] == key[depth..depth + n]
+    }
 
-            ArtNodeType::Node256 => Some(k as usize),
+    // Returns how many bytes of the compressed path are matched by the key.
+    #[inline]
+    fn prefix_mismatch(&self, key: &[u8], depth: usize) -> usize {
+        let rest = key.len() - depth;
+        let n = self.prefix_len().min(PREFIX_LEN).min(rest);
+        let matched = common_prefix_len(&self.prefix[..n], &key[depth..depth + n]);
+        if matched < n || self.prefix_len() <= PREFIX_LEN {
+            return matched;
         }
+        // The bytes out of PREFIX_LEN are only in leaves.
+        let leaf_key = &self.minimum().key;
+        let n = self.prefix_len().min(rest);
+        matched
+            + common_prefix_len(
+                &leaf_key[depth + matched..depth + n],
+                &key[depth + matched..depth + n],
+            )
     }
 
+    // Removes the first p + 1 bytes of the compressed path,
+    // and returns the byte at p which this node is branched by.
     #[inline]
-    fn find_child(&self, k: u8) -> Option<&Node> {
-        if let Some(idx) = self.index(k) {
-            self.children.get(idx)
+    fn cut_prefix(&mut self, p: usize, depth: usize) -> u8 {
+        let prefix_len = self.prefix_len() - p - 1;
+        if self.prefix_len() <= PREFIX_LEN {
+            let k = self.prefix[p];
+            let end = self.prefix_len();
+            self.prefix.copy_within(p + 1..end, 0);
+            self.prefix_len = prefix_len as u32;
+            k
         } else {
-            None
+            let leaf_key = self.minimum().key.clone();
+            let start = depth + p + 1;
+            self.set_prefix(&leaf_key[start..], prefix_len);
+            leaf_key[depth + p]
         }
     }
 
+    // Adds a leaf under this node, depth is the count of key bytes matched by this node.
     #[inline]
-    fn find_child_mut(&mut self, k: u8) -> Option<&mut Node> {
-        if let Some(idx) = self.index(k) {
-            self.children.get_mut(idx)
+    fn add_leaf(&mut self, cache: &mut NodeCache<V>, mut leaf: Arc<Node<V>>, depth: usize) {
+        let key = &leaf.leaf.as_ref().unwrap().key;
+        if key.len() == depth {
+            self.leaf = unique(&mut leaf).leaf.take();
+            cache.free_node(leaf);
         } else {
-            None
+            let k = key[depth];
+            self.add_child(cache, k, leaf);
         }
     }
 
     #[inline]
-    fn add_child(&mut self, key: u8, node: Node) {
-        if self.is_full() {
-            self.grow();
-            self.add_child(key, node);
-            return;
+    fn leaf_value(&self, key: &[u8]) -> Option<&V> {
+        match &self.leaf {
+            Some(leaf) if leaf.key == key => Some(&leaf.value_pos),
+            _ => None,
         }
-        let size = self.get_child_size();
-        match &self.typ {
-            ArtNodeType::Node4 => {
-                let idx = 0;
-                for idx in 0..size {
-                    if key < *self.keys.get(idx).unwrap() {
-                        break;
-                    }
-                }
+    }
 
-                for i in size..idx {
-                    if *self.keys.get(i - 1).unwrap() > key {
-                        self.set_key(i, *self.keys.get(i - 1).unwrap());
-                        self.set_child(i, self.children.get(i - 1).unwrap().clone());
-                    }
-                }
+    // The leaf with the smallest key in this subtree.
+    #[inline]
+    fn minimum(&self) -> &Leaf<V> {
+        match &self.leaf {
+            Some(leaf) => leaf,
+            None => self.first_child().unwrap().1.minimum(),
+        }
+    }
 
-                self.set_key(idx, key);
-                self.set_child(idx, node);
-            }
+    #[inline]
+    fn first_child(&self) -> Option<(u8, &Node<V>)> {
+        let (pos, child) = self.next_child(0)?;
+        let k = match &self.body {
+            Body::Node4(b) => b.keys[pos],
+            Body::Node16(b) => b.keys[pos],
+            _ => pos as u8,
+        };
+        Some((k, child))
+    }
 
-            ArtNodeType::Node16 => {
-                self.children.push(node);
-                self.keys.push(key);
+    // Finds the first child at or after the position pos.
+    // Node4 and Node16 are positioned by the index of keys,
+    // Node48 and Node256 are positioned by the key byte.
+    #[inline]
+    fn next_child(&self, pos: usize) -> Option<(usize, &Node<V>)> {
+        match &self.body {
+            Body::Empty => None,
+            Body::Node4(b) => Some((pos, b.children.get(pos)?.as_deref()?)),
+            Body::Node16(b) => Some((pos, b.children.get(pos)?.as_deref()?)),
+            Body::Node48(b) => (pos..NODE48KEYS).find_map(|k| match b.keys[k] {
+                0 => None,
+                slot => Some((k, b.children[slot as usize - 1].as_deref()?)),
+            }),
+            Body::Node256(b) => {
+                (pos..NODE256MAX).find_map(|k| Some((k, b.children[k].as_deref()?)))
             }
+        }
+    }
 
-            ArtNodeType::Node48 => {
-                // size as u8 is safe
-                // because the most is 255. When size is 256, it turns grow().
-                self.set_key(key as usize, size as u8);
-                self.children.push(node);
+    // Finds the last child before the position pos, in the reverse order of next_child.
+    #[inline]
+    fn prev_child(&self, pos: usize) -> Option<(usize, &Node<V>)> {
+        match &self.body {
+            Body::Empty => None,
+            Body::Node4(b) => {
+                let pos = pos.checked_sub(1)?;
+                Some((pos, b.children[pos].as_deref()?))
             }
+            Body::Node16(b) => {
+                let pos = pos.checked_sub(1)?;
+                Some((pos, b.children[pos].as_deref()?))
+            }
+            Body::Node48(b) => (0..pos).rev().find_map(|k| match b.keys[k] {
+                0 => None,
+                slot => Some((k, b.children[slot as usize - 1].as_deref()?)),
+            }),
+            Body::Node256(b) => (0..pos)
+                .rev()
+                .find_map(|k| Some((k, b.children[k].as_deref()?))),
+        }
+    }
+
+    // The position after the last child.
+    #[inline]
+    fn end_pos(&self) -> usize {
+        match &self.body {
+            Body::Empty | Body::Node4(_) | Body::Node16(_) => self.get_child_size(),
+            Body::Node48(_) => NODE48KEYS,
+            Body::Node256(_) => NODE256MAX,
+        }
+    }
 
-            ArtNodeType::Node256 => {
-                self.set_child(key as usize, node);
+    // The position of the first child whose key byte is not less than k.
+    #[inline]
+    fn child_pos_before(&self, k: u8) -> usize {
+        match &self.body {
+            Body::Empty => 0,
+            Body::Node4(b) => self
+                .sorted_keys(&b.keys)
+                .take_while(|key| **key < k)
+                .count(),
+            Body::Node16(b) => self
+                .sorted_keys(&b.keys)
+                .take_while(|key| **key < k)
+                .count(),
+            Body::Node48(_) | Body::Node256(_) => k as usize,
+        }
+    }
+
+    // The position of the first child whose key byte is greater than k.
+    #[inline]
+    fn child_pos_after(&self, k: u8) -> usize {
+        match &self.body {
+            Body::Empty => 0,
+            Body::Node4(b) => self
+                .sorted_keys(&b.keys)
+                .take_while(|key| **key <= k)
+                .count(),
+            Body::Node16(b) => self
+                .sorted_keys(&b.keys)
+                .take_while(|key| **key <= k)
+                .count(),
+            Body::Node48(_) | Body::Node256(_) => k as usize + 1,
+        }
+    }
+
+    // The count of leaves under the children whose key byte is less than k.
+    #[inline]
+    fn count_before(&self, k: u8) -> usize {
+        let end = self.child_pos_before(k);
+        let mut count = 0;
+        let mut pos = 0;
+        while let Some((p, child)) = self.next_child(pos) {
+            if p >= end {
+                break;
             }
+            count += child.count;
+            pos = p + 1;
         }
+        count
     }
 
     #[inline]
-    fn delete_child(&mut self, key: u8) {
-        if let Some(idx) = self.index(key) {
-            match &self.typ {
-                ArtNodeType::Node4 | ArtNodeType::Node16 => {
-                    self.keys.remove(idx);
-                    self.children.remove(idx);
-                }
+    fn sorted_keys<'a>(&self, keys: &'a [u8]) -> std::slice::Iter<'a, u8> {
+        keys[..self.get_child_size()].iter()
+    }
 
-                ArtNodeType::Node48 => {
-                    if self.children.get(idx).is_some() {
-                        self.keys.remove(idx);
-                        self.children.remove(idx);
-                    }
-                }
+    // A node which has only one child and no leaf is merged with its child,
+    // so that the path is compressed again after deleting.
+    #[inline]
+    fn collapse(&mut self, cache: &mut NodeCache<V>)
+    where
+        V: Clone,
+    {
+        if self.leaf.is_some() || self.get_child_size() != 1 {
+            return;
+        }
+        let k = self.first_child().unwrap().0;
+        let idx = self.index(k).unwrap();
+        let mut child = self.body.children_mut()[idx].take().unwrap();
+        let child_node = Arc::make_mut(&mut child);
+        if !child_node.is_pure_leaf() {
+            let mut prefix = self.prefix[..self.prefix_len().min(PREFIX_LEN)].to_vec();
+            prefix.push(k);
+            prefix.extend_from_slice(&child_node.prefix[..child_node.prefix_len().min(PREFIX_LEN)]);
+            child_node.set_prefix(&prefix, self.prefix_len() + 1 + child_node.prefix_len());
+        }
+        // child takes the place of this node, and this node goes back to the cache.
+        std::mem::swap(self, child_node);
+        cache.free_node(child);
+    }
 
-                ArtNodeType::Node256 => {
-                    if self.children.get(idx).is_some() {
-                        self.children.remove(idx);
-                    }
-                }
+    #[inline]
+    fn index(&self, k: u8) -> Option<usize> {
+        match &self.body {
+            Body::Empty => None,
+
+            Body::Node4(b) => self.sorted_keys(&b.keys).position(|key| *key == k),
+
+            Body::Node16(b) => index16(node16_search(), &b.keys, self.get_child_size(), k),
+
+            Body::Node48(b) => match b.keys[k as usize] {
+                0 => None,
+                slot => Some(slot as usize - 1),
+            },
+
+            Body::Node256(_) => Some(k as usize),
+        }
+    }
+
+    #[inline]
+    fn find_child(&self, k: u8) -> Option<&Node<V>> {
+        let idx = self.index(k)?;
+        self.body.children()[idx].as_deref()
+    }
+
+    // Copies the child first if a snapshot holds it.
+    #[inline]
+    fn find_child_mut(&mut self, k: u8) -> Option<&mut Node<V>>
+    where
+        V: Clone,
+    {
+        let idx = self.index(k)?;
+        self.body.children_mut()[idx].as_mut().map(Arc::make_mut)
+    }
+
+    #[inline]
+    fn add_child(&mut self, cache: &mut NodeCache<V>, key: u8, node: Arc<Node<V>>) {
+        if self.is_full() {
+            self.grow(cache);
+        }
+        let size = self.get_child_size();
+        match &mut self.body {
+            Body::Empty => unreachable!("a full node grows a body"),
+
+            Body::Node4(b) => insert_sorted(&mut b.keys, &mut b.children, size, key, node),
+
+            Body::Node16(b) => insert_sorted(&mut b.keys, &mut b.children, size, key, node),
+
+            Body::Node48(b) => {
+                let slot = b.children.iter().position(|ch| ch.is_none()).unwrap();
+                b.children[slot] = Some(node);
+                // slot + 1 is safe as u8, because the most is 48.
+                b.keys[key as usize] = (slot + 1) as u8;
             }
 
+            Body::Node256(b) => b.children[key as usize] = Some(node),
+        }
+        self.size += 1;
+    }
+
+    #[inline]
+    fn delete_child(&mut self, cache: &mut NodeCache<V>, key: u8) {
+        let idx = match self.index(key) {
+            Some(idx) => idx,
+            None => return,
+        };
+        let size = self.get_child_size();
+        let child = match &mut self.body {
+            Body::Empty => None,
+            Body::Node4(b) => remove_sorted(&mut b.keys, &mut b.children, size, idx),
+            Body::Node16(b) => remove_sorted(&mut b.keys, &mut b.children, size, idx),
+            Body::Node48(b) => {
+                b.keys[key as usize] = 0;
+                b.children[idx].take()
+            }
+            Body::Node256(b) => b.children[idx].take(),
+        };
+        if let Some(child) = child {
+            cache.free_node(child);
+            self.size -= 1;
             if self.is_less() {
-                self.shrink();
+                self.shrink(cache);
             }
         }
     }
 
+    // None --> Node4
     // Node4 --> Node16
     // Node16 --> Node48
     // Node48 --> Node256
     #[inline]
-    fn grow(&mut self) {
-        match &self.typ {
-            ArtNodeType::Node4 => {
-                self.typ = Node16;
-                self.children.reserve_exact(12);
-                self.keys.reserve_exact(12);
-            }
-
-            ArtNodeType::Node16 => {
-                let mut new_node = Node::new_node(Node48);
-                let chsize = self.get_child_size();
-                for i in 0..chsize {
-                    if let Some(child) = self.children.get(i) {
-                        let mut idx: usize = 0;
-                        for j in 0..NODE48MAX {
-                            if new_node.children.get(idx).is_some() {
-                                idx += 1;
-                            } else {
-                                break;
-                            }
-                        }
-                        new_node.set_child(idx, child.clone());
-                        new_node.set_key(*self.keys.get(i).unwrap() as usize, (idx + 1) as u8);
-                    }
-                }
-                *self = new_node;
-            }
-
-            ArtNodeType::Node48 => {
-                let mut new_node = Node::new_node(Node256);
-                let ksize = self.get_keys_size();
-                for i in 0..ksize {
-                    if let Some(child) = self.find_child(i as u8) {
-                        new_node.set_child(i, child.clone());
-                    }
-                }
-                *self = new_node;
-            }
-            _ => {}
-        }
+    fn grow(&mut self, cache: &mut NodeCache<V>) {
+        let typ = match &self.body {
+            Body::Empty => ArtNodeType::Node4,
+            Body::Node4(_) => ArtNodeType::Node16,
+            Body::Node16(_) => ArtNodeType::Node48,
+            Body::Node48(_) => ArtNodeType::Node256,
+            Body::Node256(_) => return,
+        };
+        self.retype(cache, typ);
     }
 
     // Node256 --> Node48
     // Node48 --> Node16
     // Node16 --> Node4
+    // Node4 --> None, when it has no children left.
     #[inline]
-    fn shrink(&mut self) {
-        match &self.typ {
-            ArtNodeType::Node16 => {
-                self.typ = Node4;
-                self.keys.truncate(NODE4KEYS);
-                self.children.truncate(NODE4MAX);
-            }
-
-            ArtNodeType::Node48 => {
-                self.typ = Node16;
-                self.keys.truncate(NODE16KEYS);
-                self.children.truncate(NODE16MAX);
+    fn shrink(&mut self, cache: &mut NodeCache<V>) {
+        let typ = match &self.body {
+            Body::Node4(_) if self.get_child_size() == 0 => {
+                let body = std::mem::replace(&mut self.body, Body::Empty);
+                cache.free_body(body);
+                return;
             }
-
-            ArtNodeType::Node256 => {
-                self.typ = Node48;
-                self.keys.reserve_exact(NODE48KEYS);
-                self.children.truncate(NODE48MAX);
-            }
-
-            _ => {}
-        }
+            Body::Node16(_) => ArtNodeType::Node4,
+            Body::Node48(_) => ArtNodeType::Node16,
+            Body::Node256(_) => ArtNodeType::Node48,
+            _ => return,
+        };
+        self.retype(cache, typ);
     }
 
+    // Moves the children into a new body of typ, but keeps the prefix and leaf.
+    // The old body goes back to the cache.
     #[inline]
-    fn set_key(&mut self, i: usize, key: u8) {
-        if let Some(k) = self.keys.get_mut(i) {
-            *k = key;
-        }
+    fn retype(&mut self, cache: &mut NodeCache<V>, typ: ArtNodeType) {
+        let mut old = std::mem::replace(&mut self.body, cache.new_body(typ));
+        let body = &mut self.body;
+        let mut i = 0;
+        old.drain(self.size as usize, |k, child| {
+            body.put(i, k, child);
+            i += 1;
+        });
+        cache.free_body(old);
     }
 
     #[inline]
-    fn set_child(&mut self, i: usize, child: Node) {
-        if let Some(ch) = self.children.get_mut(i) {
-            *ch = child;
+    fn memory_usage(&self, usage: &mut MemoryUsage) {
+        usage.used_bytes += size_of::<Node<V>>();
+        if let Some(leaf) = &self.leaf {
+            usage.leaves += 1;
+            usage.used_bytes += leaf.key.capacity();
+        }
+        match &self.body {
+            Body::Empty => {}
+            Body::Node4(_) => {
+                usage.node4 += 1;
+                usage.used_bytes += size_of::<Body4<V>>();
+            }
+            Body::Node16(_) => {
+                usage.node16 += 1;
+                usage.used_bytes += size_of::<Body16<V>>();
+            }
+            Body::Node48(_) => {
+                usage.node48 += 1;
+                usage.used_bytes += size_of::<Body48<V>>();
+            }
+            Body::Node256(_) => {
+                usage.node256 += 1;
+                usage.used_bytes += size_of::<Body256<V>>();
+            }
+        }
+        for child in self.body.children().iter().flatten() {
+            child.memory_usage(usage);
         }
     }
 
@@ -366,36 +1633,123 @@ impl Node {
 
     #[inline]
     fn is_leaf(&self) -> bool {
-        self.value_pos.is_some()
+        self.leaf.is_some()
     }
 
     #[inline]
-    fn get_child_size(&self) -> usize {
-        self.children.len()
+    fn is_pure_leaf(&self) -> bool {
+        self.is_leaf() && self.get_child_size() == 0
+    }
+
+    // A node without leaf and children can be dropped from the tree.
+    #[inline]
+    fn is_empty(&self) -> bool {
+        !self.is_leaf() && self.get_child_size() == 0
     }
 
     #[inline]
-    fn get_keys_size(&self) -> usize {
-        self.keys.len()
+    fn get_child_size(&self) -> usize {
+        self.size as usize
     }
 
     #[inline]
     fn max_size(&self) -> usize {
-        match &self.typ {
-            ArtNodeType::Node4 => NODE4MAX,
-            ArtNodeType::Node16 => NODE16MAX,
-            ArtNodeType::Node48 => NODE48MAX,
-            ArtNodeType::Node256 => NODE256MAX,
+        match &self.body {
+            Body::Empty => 0,
+            Body::Node4(_) => NODE4MAX,
+            Body::Node16(_) => NODE16MAX,
+            Body::Node48(_) => NODE48MAX,
+            Body::Node256(_) => NODE256MAX,
         }
     }
 
     #[inline]
     fn min_size(&self) -> usize {
-        match &self.typ {
-            ArtNodeType::Node4 => NODE4MIN,
-            ArtNodeType::Node16 => NODE16MIN,
-            ArtNodeType::Node48 => NODE48MIN,
-            ArtNodeType::Node256 => NODE256MIN,
+        match &self.body {
+            Body::Empty => 0,
+            Body::Node4(_) => NODE4MIN,
+            Body::Node16(_) => NODE16MIN,
+            Body::Node48(_) => NODE48MIN,
+            Body::Node256(_) => NODE256MIN,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Random keys from few distinct bytes, so that some repeat and the first match counts,
+    // and keys at both ends of the byte range.
+    fn node16_keys() -> Vec<[u8; NODE16KEYS]> {
+        let mut seed = 0x0E0E_0E0E_u64;
+        let mut all: Vec<[u8; NODE16KEYS]> = (0..200)
+            .map(|_| {
+                std::array::from_fn(|_| {
+                    seed ^= seed << 13;
+                    seed ^= seed >> 7;
+                    seed ^= seed << 17;
+                    (seed % 24) as u8 * 11
+                })
+            })
+            .collect();
+        all.push(std::array::from_fn(|i| {
+            if i == 15 {
+                255
+            } else {
+                i as u8 * 16
+            }
+        }));
+        all
+    }
+
+    #[test]
+    fn node16_searches_agree() {
+        assert!(node16_search().is_supported());
+        #[cfg(target_arch = "x86_64")]
+        assert!(Node16Search::Sse2.is_supported() && !Node16Search::Neon.is_supported());
+        for search in [
+            Node16Search::Scalar,
+            Node16Search::Sse2,
+            Node16Search::Avx2,
+            Node16Search::Neon,
+        ] {
+            if !search.is_supported() {
+                continue;
+            }
+            for keys in node16_keys() {
+                for size in 0..=NODE16KEYS {
+                    for k in 0..=255_u8 {
+                        assert_eq!(
+                            index16(search, &keys, size, k),
+                            index16_scalar(&keys, size, k),
+                            "{:?} {:?} {}",
+                            search,
+                            keys,
+                            size
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    // Node16Search::Neon is only run by node16_searches_agree on aarch64,
+    // elsewhere its compare and narrowing is checked here against the scalar search.
+    #[test]
+    fn node16_neon_nibbles() {
+        for keys in node16_keys() {
+            for size in 0..=NODE16KEYS {
+                for k in 0..=255_u8 {
+                    assert_eq!(
+                        index16_nibbles(&keys, size, k),
+                        index16_scalar(&keys, size, k),
+                        "{:?} {}",
+                        keys,
+                        size
+                    );
+                }
+            }
         }
     }
 }
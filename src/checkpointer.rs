@@ -0,0 +1,192 @@
+use crate::art::Snapshot;
+use crate::image::write_image;
+use crate::storage::KVpos;
+use crate::wal::Wal;
+use parking_lot::{Condvar, Mutex};
+use std::fs::File;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+// What the checkpointer has done since the db was opened.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CheckpointStats {
+    // Checkpoints made durable.
+    pub checkpoints: u64,
+    // Checkpoints which failed, in the background or not.
+    pub failures: u64,
+    // WAL segments deleted after a checkpoint.
+    pub reclaimed_segments: u64,
+    pub last_error: Option<String>,
+}
+
+// Writes checkpoints in a background thread.
+//
+// The writer takes a snapshot of the index between two batches when a checkpoint is due,
+// and hands it over. The thread syncs the kv files, writes the snapshot as the index image,
+// then advances the durable checkpoint and deletes the WAL segments it covers.
+// Errors go to the callback and the stats, the next checkpoint tries again.
+pub(crate) struct Checkpointer {
+    shared: Arc<Shared>,
+    handle: Option<JoinHandle<()>>,
+}
+
+type Job = (Snapshot<KVpos>, u64);
+
+struct Shared {
+    state: Mutex<State>,
+    wake: Condvar,
+    // Set by the timer, taken by the writer.
+    due: AtomicBool,
+    durable: AtomicU64,
+    // Held while a checkpoint is written, so only one is written at a time.
+    writing: Mutex<()>,
+    wal: Arc<Wal>,
+    files: Vec<File>,
+    index_fpath: String,
+    on_error: Option<fn(&io::Error)>,
+    stats: Mutex<CheckpointStats>,
+}
+
+#[derive(Default)]
+struct State {
+    job: Option<Job>,
+    stop: bool,
+}
+
+impl Checkpointer {
+    // interval_ms of 0 starts no thread, checkpoints are only made by DB::checkpoint then.
+    pub(crate) fn new(
+        interval_ms: u64,
+        durable: u64,
+        wal: Arc<Wal>,
+        files: Vec<File>,
+        index_fpath: String,
+        on_error: Option<fn(&io::Error)>,
+    ) -> Self {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State::default()),
+            wake: Condvar::new(),
+            due: AtomicBool::new(false),
+            durable: AtomicU64::new(durable),
+            writing: Mutex::new(()),
+            wal,
+            files,
+            index_fpath,
+            on_error,
+            stats: Mutex::new(CheckpointStats::default()),
+        });
+        let handle = if interval_ms > 0 {
+            let shared = shared.clone();
+            let interval = Duration::from_millis(interval_ms);
+            Some(
+                thread::Builder::new()
+                    .name("tigadb-checkpointer".to_string())
+                    .spawn(move || shared.run(interval))
+                    .expect("spawn checkpointer error"),
+            )
+        } else {
+            None
+        };
+        Checkpointer { shared, handle }
+    }
+
+    // Whether the writer should take a snapshot for the background thread now.
+    // It is not due while the last one handed over is not written yet.
+    #[inline]
+    pub(crate) fn take_due(&self) -> bool {
+        self.shared.due.load(Ordering::Relaxed)
+            && self.shared.state.lock().job.is_none()
+            && self.shared.due.swap(false, Ordering::Relaxed)
+    }
+
+    // Hands a snapshot over to the background thread.
+    pub(crate) fn submit(&self, snapshot: Snapshot<KVpos>, checkpoint: u64) {
+        self.shared.state.lock().job = Some((snapshot, checkpoint));
+        self.shared.wake.notify_one();
+    }
+
+    // Writes a checkpoint in the calling thread, its error is returned and not reported.
+    pub(crate) fn write_now(&self, snapshot: Snapshot<KVpos>, checkpoint: u64) -> io::Result<()> {
+        let result = self.shared.write(&snapshot, checkpoint);
+        if let Err(e) = &result {
+            self.shared.count_failure(e);
+        }
+        result
+    }
+
+    // The last checkpoint whose kv data and index image are on disk.
+    #[inline]
+    pub(crate) fn durable(&self) -> u64 {
+        self.shared.durable.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn stats(&self) -> CheckpointStats {
+        self.shared.stats.lock().clone()
+    }
+}
+
+impl Shared {
+    fn run(&self, interval: Duration) {
+        let mut next_due = Instant::now() + interval;
+        let mut state = self.state.lock();
+        while !state.stop {
+            if let Some((snapshot, checkpoint)) = state.job.take() {
+                drop(state);
+                if let Err(e) = self.write(&snapshot, checkpoint) {
+                    self.count_failure(&e);
+                    if let Some(on_error) = self.on_error {
+                        on_error(&e);
+                    }
+                }
+                state = self.state.lock();
+                continue;
+            }
+            let now = Instant::now();
+            if now >= next_due {
+                self.due.store(true, Ordering::Relaxed);
+                next_due = now + interval;
+            }
+            self.wake
+                .wait_for(&mut state, next_due.saturating_duration_since(now));
+        }
+    }
+
+    fn write(&self, snapshot: &Snapshot<KVpos>, checkpoint: u64) -> io::Result<()> {
+        let _writing = self.writing.lock();
+        // A later checkpoint was written already, this one has nothing to add.
+        if checkpoint <= self.durable.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        // The kv files may hold data of batches after the snapshot too, over blocks
+        // the image still points to. Those batches must be replayable, so the WAL goes first.
+        self.wal.sync()?;
+        for file in self.files.iter() {
+            file.sync_all()?;
+        }
+        write_image(snapshot, checkpoint, &self.index_fpath)?;
+        self.durable.store(checkpoint, Ordering::SeqCst);
+        self.stats.lock().checkpoints += 1;
+        let reclaimed = self.wal.reclaim(checkpoint)?;
+        self.stats.lock().reclaimed_segments += reclaimed as u64;
+        Ok(())
+    }
+
+    fn count_failure(&self, e: &io::Error) {
+        let mut stats = self.stats.lock();
+        stats.failures += 1;
+        stats.last_error = Some(e.to_string());
+    }
+}
+
+impl Drop for Checkpointer {
+    fn drop(&mut self) {
+        self.shared.state.lock().stop = true;
+        self.shared.wake.notify_one();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
@@ -0,0 +1,943 @@
+// ConcurrentArtTree is the ART-tree shared by one or more writers and many readers,
+// synchronized by optimistic lock coupling.
+//
+// Every node has a version. Readers never write to shared memory, they remember the
+// version of a node, read the node, and check the version again before trusting what
+// they have read, restarting from the root when it changed. Writers lock only the node
+// they modify, and its parent when the node is replaced, by setting the lock bit of
+// the version they have read. Replaced nodes are marked obsolete and freed by
+// crossbeam-epoch after no reader can see them anymore.
+//
+// Unlike ArtTree, the prefix of a node is fully stored, a compressed path longer than
+// PREFIX_LEN is split into a chain of nodes, so that no leaf is read to check a prefix.
+// After a remove, the nodes on the path of the key are compacted as ArtTree does:
+// a node left with too few children for its type is replaced by a smaller copy,
+// and one left with nothing, only its leaf or only one child is replaced by that.
+
+use crate::art::{
+    common_prefix_len, ArtNodeType, MemoryUsage, NODE16MAX, NODE16MIN, NODE256MAX, NODE256MIN,
+    NODE48KEYS, NODE48MAX, NODE48MIN, NODE4MAX, PREFIX_LEN,
+};
+use crossbeam_epoch::{self as epoch, Atomic, Guard, Owned, Pointer, Shared};
+use std::hint::spin_loop;
+use std::mem::size_of;
+use std::sync::atomic::{fence, AtomicU16, AtomicU64, AtomicU8, Ordering};
+
+// The lowest two bits of a version, the rest of it counts the writes.
+const OBSOLETE: u64 = 1;
+const LOCKED: u64 = 2;
+
+// The version of a node has changed since it was read, the operation starts again.
+struct Restart;
+
+type Step<T> = Result<T, Restart>;
+
+pub struct ConcurrentArtTree<V> {
+    // The root is a Node256 without prefix which is never replaced,
+    // so that every other node has a parent to lock.
+    root: Atomic<Node<V>>,
+}
+
+impl<V> Default for ConcurrentArtTree<V> {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            root: Atomic::new(Node::new_inner(ArtNodeType::Node256)),
+        }
+    }
+}
+
+impl<V> Drop for ConcurrentArtTree<V> {
+    fn drop(&mut self) {
+        // No other thread can access the tree anymore.
+        unsafe {
+            let guard = epoch::unprotected();
+            free_subtree(self.root.load(Ordering::Relaxed, guard), guard);
+        }
+    }
+}
+
+unsafe fn free_subtree<V>(node: Shared<'_, Node<V>>, guard: &Guard) {
+    let n = node.deref();
+    n.for_each_child(guard, |_, child| free_subtree(child, guard));
+    let leaf = n.leaf.load(Ordering::Relaxed, guard);
+    if !leaf.is_null() {
+        drop(leaf.into_owned());
+    }
+    drop(node.into_owned());
+}
+
+impl<V: Clone> ConcurrentArtTree<V> {
+    // Returns a copy of the old value_pos if the key exists.
+    pub fn insert(&self, key: Vec<u8>, value_pos: V) -> Option<V> {
+        let guard = &epoch::pin();
+        let mut leaf = Some(Owned::new(Leaf { key, value_pos }));
+        loop {
+            if let Ok(old) = self.insert_optimistic(&mut leaf, guard) {
+                return old;
+            }
+            spin_loop();
+        }
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<V> {
+        let guard = &epoch::pin();
+        loop {
+            if let Ok(value_pos) = self.get_optimistic(key, guard) {
+                return value_pos;
+            }
+            spin_loop();
+        }
+    }
+
+    pub fn remove(&self, key: &[u8]) -> Option<V> {
+        let guard = &epoch::pin();
+        let old = loop {
+            if let Ok(old) = self.remove_optimistic(key, guard) {
+                break old;
+            }
+            spin_loop();
+        };
+        if old.is_some() {
+            // Fixes the nodes on the path one at a time, until none of them needs it.
+            while self.compact_optimistic(key, guard).unwrap_or(true) {
+                spin_loop();
+            }
+        }
+        old
+    }
+
+    // Reports the memory held by the nodes and the leaves, as ArtTree::memory_usage.
+    // It is only exact while no writer changes the tree.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let guard = &epoch::pin();
+        let mut usage = MemoryUsage::default();
+        let root = unsafe { self.root.load(Ordering::Acquire, guard).deref() };
+        root.memory_usage(&mut usage, guard);
+        usage
+    }
+
+    fn get_optimistic(&self, key: &[u8], guard: &Guard) -> Step<Option<V>> {
+        let mut node = unsafe { self.root.load(Ordering::Acquire, guard).deref() };
+        let mut version = node.read_lock()?;
+        let mut depth = 0;
+        loop {
+            if node.is_pure_leaf() {
+                let value_pos = node.leaf_value(key, guard);
+                node.check(version)?;
+                return Ok(value_pos);
+            }
+
+            let (prefix, prefix_len) = node.prefix();
+            if key.len() < depth + prefix_len
+                || prefix[..prefix_len] != key[depth..depth + prefix_len]
+            {
+                node.check(version)?;
+                return Ok(None);
+            }
+            depth += prefix_len;
+            if depth == key.len() {
+                let value_pos = node.leaf_value(key, guard);
+                node.check(version)?;
+                return Ok(value_pos);
+            }
+
+            let child = node.find_child(key[depth], guard);
+            node.check(version)?;
+            let child = match unsafe { child.as_ref() } {
+                Some(child) => child,
+                None => return Ok(None),
+            };
+            let child_version = child.read_lock()?;
+            node.check(version)?;
+            node = child;
+            version = child_version;
+            depth += 1;
+        }
+    }
+
+    // new_leaf is taken only when it's linked into the tree,
+    // the key is borrowed from it until then.
+    fn insert_optimistic(
+        &self,
+        new_leaf: &mut Option<Owned<Leaf<V>>>,
+        guard: &Guard,
+    ) -> Step<Option<V>> {
+        let key = &new_leaf.as_ref().unwrap().key[..];
+        // The parent, its version and the key byte by which it points to node.
+        let mut parent: Option<(&Node<V>, u64, u8)> = None;
+        let mut node_ptr = self.root.load(Ordering::Acquire, guard);
+        let mut node = unsafe { node_ptr.deref() };
+        let mut version = node.read_lock()?;
+        let mut depth = 0;
+        loop {
+            if node.is_pure_leaf() {
+                let old = unsafe { node.leaf.load(Ordering::Acquire, guard).deref() };
+                node.check(version)?;
+                if old.key == key {
+                    node.upgrade(version)?;
+                    return Ok(node.swap_leaf(new_leaf.take().unwrap(), guard));
+                }
+
+                // The leaf moves down into a new branch with the new key.
+                let lcp = common_prefix_len(&old.key[depth..], &key[depth..]);
+                let old_k = old.key.get(depth + lcp).copied();
+                let new_k = key.get(depth + lcp).copied();
+                let (parent, parent_version, parent_k) = parent.unwrap();
+                lock_both(parent, parent_version, node, version)?;
+
+                let branch = Node::new_inner(ArtNodeType::Node4);
+                match old_k {
+                    Some(k) => branch.add_child(k, node_ptr),
+                    None => branch
+                        .leaf
+                        .store(node.leaf.load(Ordering::Relaxed, guard), Ordering::Relaxed),
+                }
+                branch.add_leaf(new_k, new_leaf.take().unwrap());
+                let branch = Node::under_path(&old.key[depth..depth + lcp], Owned::new(branch));
+                parent.replace_child(parent_k, branch);
+                parent.unlock();
+                if old_k.is_some() {
+                    node.unlock();
+                } else {
+                    // The leaf has moved into the branch, only the node is freed.
+                    node.unlock_obsolete();
+                    unsafe { guard.defer_destroy(node_ptr) };
+                }
+                return Ok(None);
+            }
+
+            let (prefix, prefix_len) = node.prefix();
+            let p = common_prefix_len(&prefix[..prefix_len], &key[depth..]);
+            if p < prefix_len {
+                // The key leaves the compressed path at p, split the path there.
+                let new_k = key.get(depth + p).copied();
+                let (parent, parent_version, parent_k) = parent.unwrap();
+                lock_both(parent, parent_version, node, version)?;
+
+                let branch = Node::new_inner(ArtNodeType::Node4);
+                branch.set_prefix(&prefix[..p]);
+                branch.add_child(prefix[p], node_ptr);
+                branch.add_leaf(new_k, new_leaf.take().unwrap());
+                node.set_prefix(&prefix[p + 1..prefix_len]);
+                parent.replace_child(parent_k, Owned::new(branch));
+                node.unlock();
+                parent.unlock();
+                return Ok(None);
+            }
+            depth += prefix_len;
+
+            if depth == key.len() {
+                node.upgrade(version)?;
+                return Ok(node.swap_leaf(new_leaf.take().unwrap(), guard));
+            }
+
+            let k = key[depth];
+            let child = node.find_child(k, guard);
+            node.check(version)?;
+            if child.is_null() {
+                if node.is_full() {
+                    // The root never gets full, so node has a parent.
+                    let (parent, parent_version, parent_k) = parent.unwrap();
+                    lock_both(parent, parent_version, node, version)?;
+                    let grown = node.grow(guard);
+                    grown.add_leaf(Some(k), new_leaf.take().unwrap());
+                    parent.replace_child(parent_k, Owned::new(grown));
+                    node.unlock_obsolete();
+                    parent.unlock();
+                    unsafe { guard.defer_destroy(node_ptr) };
+                } else {
+                    node.upgrade(version)?;
+                    node.add_leaf(Some(k), new_leaf.take().unwrap());
+                    node.unlock();
+                }
+                return Ok(None);
+            }
+
+            let child_version = unsafe { child.deref() }.read_lock()?;
+            node.check(version)?;
+            parent = Some((node, version, k));
+            node_ptr = child;
+            node = unsafe { child.deref() };
+            version = child_version;
+            depth += 1;
+        }
+    }
+
+    fn remove_optimistic(&self, key: &[u8], guard: &Guard) -> Step<Option<V>> {
+        let mut parent: Option<(&Node<V>, u64, u8)> = None;
+        let mut node_ptr = self.root.load(Ordering::Acquire, guard);
+        let mut node = unsafe { node_ptr.deref() };
+        let mut version = node.read_lock()?;
+        let mut depth = 0;
+        loop {
+            if node.is_pure_leaf() {
+                let leaf_ptr = node.leaf.load(Ordering::Acquire, guard);
+                if unsafe { leaf_ptr.deref() }.key != key {
+                    node.check(version)?;
+                    return Ok(None);
+                }
+                let (parent, parent_version, parent_k) = parent.unwrap();
+                lock_both(parent, parent_version, node, version)?;
+                parent.remove_child(parent_k);
+                parent.unlock();
+                node.unlock_obsolete();
+                let old = unsafe { leaf_ptr.deref() }.value_pos.clone();
+                unsafe {
+                    guard.defer_destroy(leaf_ptr);
+                    guard.defer_destroy(node_ptr);
+                }
+                return Ok(Some(old));
+            }
+
+            let (prefix, prefix_len) = node.prefix();
+            if key.len() < depth + prefix_len
+                || prefix[..prefix_len] != key[depth..depth + prefix_len]
+            {
+                node.check(version)?;
+                return Ok(None);
+            }
+            depth += prefix_len;
+
+            if depth == key.len() {
+                if node.leaf.load(Ordering::Acquire, guard).is_null() {
+                    node.check(version)?;
+                    return Ok(None);
+                }
+                node.upgrade(version)?;
+                let leaf_ptr = node.leaf.swap(Shared::null(), Ordering::AcqRel, guard);
+                node.unlock();
+                let old = unsafe { leaf_ptr.deref() }.value_pos.clone();
+                unsafe { guard.defer_destroy(leaf_ptr) };
+                return Ok(Some(old));
+            }
+
+            let k = key[depth];
+            let child = node.find_child(k, guard);
+            node.check(version)?;
+            if child.is_null() {
+                return Ok(None);
+            }
+            let child_version = unsafe { child.deref() }.read_lock()?;
+            node.check(version)?;
+            parent = Some((node, version, k));
+            node_ptr = child;
+            node = unsafe { child.deref() };
+            version = child_version;
+            depth += 1;
+        }
+    }
+
+    // Fixes the first node on the path of key which needs it, and returns whether one did.
+    fn compact_optimistic(&self, key: &[u8], guard: &Guard) -> Step<bool> {
+        // The root is never replaced, so the nodes below it are the ones fixed.
+        let mut parent: Option<(&Node<V>, u64, u8)> = None;
+        let mut node_ptr = self.root.load(Ordering::Acquire, guard);
+        let mut node = unsafe { node_ptr.deref() };
+        let mut version = node.read_lock()?;
+        let mut depth = 0;
+        loop {
+            if node.is_pure_leaf() {
+                return Ok(false);
+            }
+            if let Some((parent, parent_version, parent_k)) = parent {
+                let fix = node.fix(guard)?;
+                node.check(version)?;
+                if !matches!(fix, Fix::Keep) {
+                    lock_both(parent, parent_version, node, version)?;
+                    if let Fix::Child(_, child, Some(child_version)) = fix {
+                        if let Err(restart) = unsafe { child.deref() }.upgrade(child_version) {
+                            node.unlock();
+                            parent.unlock();
+                            return Err(restart);
+                        }
+                    }
+                    parent.apply_fix(parent_k, node_ptr, fix, guard);
+                    parent.unlock();
+                    return Ok(true);
+                }
+            }
+
+            let (prefix, prefix_len) = node.prefix();
+            if key.len() <= depth + prefix_len
+                || prefix[..prefix_len] != key[depth..depth + prefix_len]
+            {
+                node.check(version)?;
+                return Ok(false);
+            }
+            depth += prefix_len;
+
+            let k = key[depth];
+            let child = node.find_child(k, guard);
+            node.check(version)?;
+            if child.is_null() {
+                return Ok(false);
+            }
+            let child_version = unsafe { child.deref() }.read_lock()?;
+            node.check(version)?;
+            parent = Some((node, version, k));
+            node_ptr = child;
+            node = unsafe { child.deref() };
+            version = child_version;
+            depth += 1;
+        }
+    }
+}
+
+// What becomes of an inner node below the root, see Node::fix.
+enum Fix<'g, V> {
+    Keep,
+    // Nothing is left under it, it is unlinked.
+    Unlink,
+    // Only its leaf is left, a pure leaf node takes its place.
+    Leaf,
+    // Only the child of the byte is left and takes its place. An inner child gets
+    // the prefix of the node and the byte in front of its own, under the lock
+    // of the version, while a pure leaf needs no lock.
+    Child(u8, Shared<'g, Node<V>>, Option<u64>),
+    // It has too few children for its type, a smaller copy takes its place.
+    Shrink,
+}
+
+// Locks the parent and then node, or neither of them.
+#[inline]
+fn lock_both<V>(parent: &Node<V>, parent_version: u64, node: &Node<V>, version: u64) -> Step<()> {
+    parent.upgrade(parent_version)?;
+    if let Err(restart) = node.upgrade(version) {
+        parent.unlock();
+        return Err(restart);
+    }
+    Ok(())
+}
+
+// Leaves are never changed after they are linked into the tree,
+// an update links a new leaf in place of the old one.
+pub(crate) struct Leaf<V> {
+    key: Vec<u8>,
+    value_pos: V,
+}
+
+// The type of a node never changes, a full node is replaced by a bigger one.
+// Readers may see the keys and children while they are written,
+// so all of them are atomics, checked with the version after reading.
+enum Body<V> {
+    // A node which holds only a leaf.
+    Leaf,
+    Node4(Box<Keyed<V, NODE4MAX>>),
+    Node16(Box<Keyed<V, NODE16MAX>>),
+    Node48(Box<Body48<V>>),
+    // children are indexed by the key byte.
+    Node256(Box<[Atomic<Node<V>>; NODE256MAX]>),
+}
+
+// keys of Node4 and Node16 are not sorted, they are walked by size.
+struct Keyed<V, const N: usize> {
+    keys: [AtomicU8; N],
+    children: [Atomic<Node<V>>; N],
+}
+
+// key byte --> (slot of children + 1), 0 means empty.
+struct Body48<V> {
+    keys: [AtomicU8; NODE48KEYS],
+    children: [Atomic<Node<V>>; NODE48MAX],
+}
+
+impl<V> Body<V> {
+    // keys and children of Node4 and Node16.
+    #[inline]
+    fn unsorted(&self) -> (&[AtomicU8], &[Atomic<Node<V>>]) {
+        match self {
+            Body::Node4(b) => (&b.keys, &b.children),
+            Body::Node16(b) => (&b.keys, &b.children),
+            _ => unreachable!("only Node4 and Node16 are walked by size"),
+        }
+    }
+}
+
+pub(crate) struct Node<V> {
+    version: AtomicU64,
+    prefix: [AtomicU8; PREFIX_LEN],
+    prefix_len: AtomicU8,
+    size: AtomicU16,
+    // The key which ends right after the prefix of this node.
+    leaf: Atomic<Leaf<V>>,
+    body: Body<V>,
+}
+
+impl<V> Node<V> {
+    #[inline]
+    fn new(body: Body<V>) -> Node<V> {
+        Node {
+            version: AtomicU64::new(0),
+            prefix: atomic_bytes(),
+            prefix_len: AtomicU8::new(0),
+            size: AtomicU16::new(0),
+            leaf: Atomic::null(),
+            body,
+        }
+    }
+
+    #[inline]
+    fn new_inner(typ: ArtNodeType) -> Node<V> {
+        Node::new(match typ {
+            ArtNodeType::Node4 => Body::Node4(Box::new(Keyed {
+                keys: atomic_bytes(),
+                children: null_children(),
+            })),
+            ArtNodeType::Node16 => Body::Node16(Box::new(Keyed {
+                keys: atomic_bytes(),
+                children: null_children(),
+            })),
+            ArtNodeType::Node48 => Body::Node48(Box::new(Body48 {
+                keys: atomic_bytes(),
+                children: null_children(),
+            })),
+            ArtNodeType::Node256 => Body::Node256(Box::new(null_children())),
+        })
+    }
+
+    #[inline]
+    fn new_leaf(leaf: Owned<Leaf<V>>) -> Node<V> {
+        let node = Node::new(Body::Leaf);
+        node.leaf.store(leaf, Ordering::Relaxed);
+        node
+    }
+
+    // Puts node under a chain of new nodes which covers path,
+    // each of them keeps at most PREFIX_LEN bytes of it and branches by the next byte.
+    fn under_path(path: &[u8], node: Owned<Node<V>>) -> Owned<Node<V>> {
+        let mut end = path.len();
+        let n = end.min(PREFIX_LEN);
+        node.set_prefix(&path[end - n..end]);
+        end -= n;
+        let mut node = node;
+        while end > 0 {
+            let k = path[end - 1];
+            end -= 1;
+            let n = end.min(PREFIX_LEN);
+            let parent = Node::new_inner(ArtNodeType::Node4);
+            parent.set_prefix(&path[end - n..end]);
+            parent.add_child(k, node);
+            node = Owned::new(parent);
+            end -= n;
+        }
+        node
+    }
+
+    #[inline]
+    fn read_lock(&self) -> Step<u64> {
+        let version = self.version.load(Ordering::Acquire);
+        if version & (LOCKED | OBSOLETE) != 0 {
+            return Err(Restart);
+        }
+        Ok(version)
+    }
+
+    // Checks that nothing read since read_lock has been changed.
+    #[inline]
+    fn check(&self, version: u64) -> Step<()> {
+        fence(Ordering::Acquire);
+        if self.version.load(Ordering::Relaxed) != version {
+            return Err(Restart);
+        }
+        Ok(())
+    }
+
+    // Locks the node if it is still at version.
+    #[inline]
+    fn upgrade(&self, version: u64) -> Step<()> {
+        self.version
+            .compare_exchange(
+                version,
+                version + LOCKED,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
+            .map_err(|_| Restart)?;
+        // Readers who see any of the following writes must see the lock as well.
+        fence(Ordering::Release);
+        Ok(())
+    }
+
+    // Clears the lock bit and counts one more write.
+    #[inline]
+    fn unlock(&self) {
+        self.version.fetch_add(LOCKED, Ordering::Release);
+    }
+
+    #[inline]
+    fn unlock_obsolete(&self) {
+        self.version.fetch_add(LOCKED + OBSOLETE, Ordering::Release);
+    }
+
+    #[inline]
+    fn prefix(&self) -> ([u8; PREFIX_LEN], usize) {
+        let mut prefix = [0; PREFIX_LEN];
+        for (b, atomic) in prefix.iter_mut().zip(self.prefix.iter()) {
+            *b = atomic.load(Ordering::Relaxed);
+        }
+        let prefix_len = (self.prefix_len.load(Ordering::Relaxed) as usize).min(PREFIX_LEN);
+        (prefix, prefix_len)
+    }
+
+    #[inline]
+    fn set_prefix(&self, prefix: &[u8]) {
+        for (atomic, b) in self.prefix.iter().zip(prefix.iter()) {
+            atomic.store(*b, Ordering::Relaxed);
+        }
+        self.prefix_len.store(prefix.len() as u8, Ordering::Relaxed);
+    }
+
+    #[inline]
+    fn leaf_value(&self, key: &[u8], guard: &Guard) -> Option<V>
+    where
+        V: Clone,
+    {
+        let leaf = unsafe { self.leaf.load(Ordering::Acquire, guard).as_ref() }?;
+        if leaf.key == key {
+            Some(leaf.value_pos.clone())
+        } else {
+            None
+        }
+    }
+
+    // Links leaf in place of the old one and unlocks the node, the old leaf is freed later.
+    #[inline]
+    fn swap_leaf(&self, leaf: Owned<Leaf<V>>, guard: &Guard) -> Option<V>
+    where
+        V: Clone,
+    {
+        let old = self.leaf.swap(leaf, Ordering::AcqRel, guard);
+        self.unlock();
+        let old_value = unsafe { old.as_ref() }.map(|leaf| leaf.value_pos.clone());
+        if !old.is_null() {
+            unsafe { guard.defer_destroy(old) };
+        }
+        old_value
+    }
+
+    // Adds leaf as the child of byte k, or as the leaf of this node if k is None.
+    #[inline]
+    fn add_leaf(&self, k: Option<u8>, leaf: Owned<Leaf<V>>) {
+        match k {
+            Some(k) => self.add_child(k, Owned::new(Node::new_leaf(leaf))),
+            None => self.leaf.store(leaf, Ordering::Release),
+        }
+    }
+
+    #[inline]
+    fn is_pure_leaf(&self) -> bool {
+        matches!(self.body, Body::Leaf)
+    }
+
+    #[inline]
+    fn get_child_size(&self) -> usize {
+        self.size.load(Ordering::Relaxed) as usize
+    }
+
+    #[inline]
+    fn is_full(&self) -> bool {
+        let max_size = match &self.body {
+            Body::Leaf => 0,
+            Body::Node4(_) => NODE4MAX,
+            Body::Node16(_) => NODE16MAX,
+            Body::Node48(_) => NODE48MAX,
+            Body::Node256(_) => NODE256MAX,
+        };
+        self.get_child_size() >= max_size
+    }
+
+    // The slot of the child of byte k in children.
+    #[inline]
+    fn index(&self, k: u8) -> Option<usize> {
+        match &self.body {
+            Body::Leaf => None,
+            Body::Node4(b) => self.scan_index(&b.keys, k),
+            Body::Node16(b) => self.scan_index(&b.keys, k),
+            Body::Node48(b) => match b.keys[k as usize].load(Ordering::Relaxed) {
+                0 => None,
+                slot => Some(slot as usize - 1),
+            },
+            Body::Node256(_) => Some(k as usize),
+        }
+    }
+
+    #[inline]
+    fn scan_index(&self, keys: &[AtomicU8], k: u8) -> Option<usize> {
+        let size = self.get_child_size().min(keys.len());
+        keys[..size]
+            .iter()
+            .position(|key| key.load(Ordering::Relaxed) == k)
+    }
+
+    #[inline]
+    fn children(&self) -> &[Atomic<Node<V>>] {
+        match &self.body {
+            Body::Leaf => &[],
+            Body::Node4(b) => &b.children,
+            Body::Node16(b) => &b.children,
+            Body::Node48(b) => &b.children,
+            Body::Node256(children) => &children[..],
+        }
+    }
+
+    #[inline]
+    fn find_child<'g>(&self, k: u8, guard: &'g Guard) -> Shared<'g, Node<V>> {
+        match self.index(k) {
+            Some(idx) => self.children()[idx].load(Ordering::Acquire, guard),
+            None => Shared::null(),
+        }
+    }
+
+    // The node must be locked or not linked into the tree yet, and not full.
+    #[inline]
+    fn add_child<P: Pointer<Node<V>>>(&self, k: u8, child: P) {
+        let size = self.get_child_size();
+        match &self.body {
+            Body::Leaf => unreachable!("a pure leaf has no children"),
+            Body::Node4(_) | Body::Node16(_) => {
+                let (keys, children) = self.body.unsorted();
+                keys[size].store(k, Ordering::Relaxed);
+                children[size].store(child, Ordering::Release);
+            }
+            Body::Node48(b) => {
+                let Body48 { keys, children } = &**b;
+                let guard = unsafe { epoch::unprotected() };
+                let slot = children
+                    .iter()
+                    .position(|ch| ch.load(Ordering::Relaxed, guard).is_null())
+                    .unwrap();
+                children[slot].store(child, Ordering::Release);
+                // slot + 1 is safe as u8, because the most is 48.
+                keys[k as usize].store((slot + 1) as u8, Ordering::Release);
+            }
+            Body::Node256(children) => children[k as usize].store(child, Ordering::Release),
+        }
+        self.size.store((size + 1) as u16, Ordering::Release);
+    }
+
+    // The node must be locked.
+    #[inline]
+    fn replace_child<P: Pointer<Node<V>>>(&self, k: u8, child: P) {
+        let idx = self.index(k).unwrap();
+        self.children()[idx].store(child, Ordering::Release);
+    }
+
+    // The node must be locked.
+    #[inline]
+    fn remove_child(&self, k: u8) {
+        let idx = match self.index(k) {
+            Some(idx) => idx,
+            None => return,
+        };
+        let size = self.get_child_size();
+        let guard = unsafe { epoch::unprotected() };
+        match &self.body {
+            Body::Leaf => return,
+            Body::Node4(_) | Body::Node16(_) => {
+                let (keys, children) = self.body.unsorted();
+                // The last child fills the hole.
+                let last = size - 1;
+                keys[idx].store(keys[last].load(Ordering::Relaxed), Ordering::Relaxed);
+                children[idx].store(
+                    children[last].load(Ordering::Relaxed, guard),
+                    Ordering::Release,
+                );
+                children[last].store(Shared::null(), Ordering::Release);
+            }
+            Body::Node48(b) => {
+                let Body48 { keys, children } = &**b;
+                keys[k as usize].store(0, Ordering::Release);
+                children[idx].store(Shared::null(), Ordering::Release);
+            }
+            Body::Node256(children) => children[idx].store(Shared::null(), Ordering::Release),
+        }
+        self.size.store((size - 1) as u16, Ordering::Release);
+    }
+
+    fn for_each_child<'g>(&self, guard: &'g Guard, mut f: impl FnMut(u8, Shared<'g, Node<V>>))
+    where
+        V: 'g,
+    {
+        match &self.body {
+            Body::Leaf => {}
+            Body::Node4(_) | Body::Node16(_) => {
+                let (keys, children) = self.body.unsorted();
+                let size = self.get_child_size().min(keys.len());
+                for (k, child) in keys.iter().zip(children.iter()).take(size) {
+                    f(
+                        k.load(Ordering::Relaxed),
+                        child.load(Ordering::Acquire, guard),
+                    );
+                }
+            }
+            Body::Node48(b) => {
+                let Body48 { keys, children } = &**b;
+                for (k, slot) in keys.iter().enumerate() {
+                    match slot.load(Ordering::Relaxed) {
+                        0 => {}
+                        slot => f(
+                            k as u8,
+                            children[slot as usize - 1].load(Ordering::Acquire, guard),
+                        ),
+                    }
+                }
+            }
+            Body::Node256(children) => {
+                for (k, child) in children.iter().enumerate() {
+                    let child = child.load(Ordering::Acquire, guard);
+                    if !child.is_null() {
+                        f(k as u8, child);
+                    }
+                }
+            }
+        }
+    }
+
+    // A copy of this locked node in the next bigger type,
+    // Node4 --> Node16 --> Node48 --> Node256.
+    fn grow(&self, guard: &Guard) -> Node<V> {
+        let typ = match &self.body {
+            Body::Node4(_) => ArtNodeType::Node16,
+            Body::Node16(_) => ArtNodeType::Node48,
+            _ => ArtNodeType::Node256,
+        };
+        self.copy_as(typ, guard)
+    }
+
+    // A copy of this locked node in the next smaller type,
+    // Node256 --> Node48 --> Node16 --> Node4.
+    fn shrink(&self, guard: &Guard) -> Node<V> {
+        let typ = match &self.body {
+            Body::Node256(_) => ArtNodeType::Node48,
+            Body::Node48(_) => ArtNodeType::Node16,
+            _ => ArtNodeType::Node4,
+        };
+        self.copy_as(typ, guard)
+    }
+
+    fn copy_as(&self, typ: ArtNodeType, guard: &Guard) -> Node<V> {
+        let node = Node::new_inner(typ);
+        let (prefix, prefix_len) = self.prefix();
+        node.set_prefix(&prefix[..prefix_len]);
+        node.leaf
+            .store(self.leaf.load(Ordering::Relaxed, guard), Ordering::Relaxed);
+        self.for_each_child(guard, |k, child| node.add_child(k, child));
+        node
+    }
+
+    // What this inner node needs, read optimistically, so the caller checks the version.
+    fn fix<'g>(&self, guard: &'g Guard) -> Step<Fix<'g, V>> {
+        let size = self.get_child_size();
+        let has_leaf = !self.leaf.load(Ordering::Acquire, guard).is_null();
+        let min_size = match &self.body {
+            Body::Node16(_) => NODE16MIN,
+            Body::Node48(_) => NODE48MIN,
+            Body::Node256(_) => NODE256MIN,
+            _ => 0,
+        };
+        match (size, has_leaf) {
+            (0, false) => Ok(Fix::Unlink),
+            (0, true) => Ok(Fix::Leaf),
+            (1, false) => {
+                let mut only = None;
+                self.for_each_child(guard, |k, child| only = Some((k, child)));
+                let (k, child_ptr) = match only {
+                    Some((k, child)) if !child.is_null() => (k, child),
+                    _ => return Err(Restart),
+                };
+                let child = unsafe { child_ptr.deref() };
+                if child.is_pure_leaf() {
+                    return Ok(Fix::Child(k, child_ptr, None));
+                }
+                let child_version = child.read_lock()?;
+                let fits = self.prefix().1 + 1 + child.prefix().1 <= PREFIX_LEN;
+                child.check(child_version)?;
+                if fits {
+                    Ok(Fix::Child(k, child_ptr, Some(child_version)))
+                } else {
+                    // A path too long for one node stays a chain of nodes.
+                    Ok(Fix::Keep)
+                }
+            }
+            _ if size < min_size => Ok(Fix::Shrink),
+            _ => Ok(Fix::Keep),
+        }
+    }
+
+    // Puts what fix says in place of the child of byte k, which is node.
+    // This node, node and an inner child of Fix::Child must be locked,
+    // node is freed later and the child is unlocked.
+    fn apply_fix<'g>(&self, k: u8, node_ptr: Shared<'g, Node<V>>, fix: Fix<'g, V>, guard: &Guard) {
+        let node = unsafe { node_ptr.deref() };
+        match fix {
+            Fix::Keep => {
+                node.unlock();
+                return;
+            }
+            Fix::Unlink => self.remove_child(k),
+            Fix::Leaf => {
+                let leaf = Node::new(Body::Leaf);
+                leaf.leaf
+                    .store(node.leaf.load(Ordering::Relaxed, guard), Ordering::Relaxed);
+                self.replace_child(k, Owned::new(leaf));
+            }
+            Fix::Child(child_k, child_ptr, locked) => {
+                if locked.is_some() {
+                    let child = unsafe { child_ptr.deref() };
+                    let (prefix, prefix_len) = node.prefix();
+                    let (child_prefix, child_prefix_len) = child.prefix();
+                    let mut path = prefix[..prefix_len].to_vec();
+                    path.push(child_k);
+                    path.extend_from_slice(&child_prefix[..child_prefix_len]);
+                    child.set_prefix(&path);
+                    child.unlock();
+                }
+                self.replace_child(k, child_ptr);
+            }
+            Fix::Shrink => self.replace_child(k, Owned::new(node.shrink(guard))),
+        }
+        node.unlock_obsolete();
+        unsafe { guard.defer_destroy(node_ptr) };
+    }
+
+    fn memory_usage(&self, usage: &mut MemoryUsage, guard: &Guard) {
+        usage.used_bytes += size_of::<Node<V>>();
+        if let Some(leaf) = unsafe { self.leaf.load(Ordering::Acquire, guard).as_ref() } {
+            usage.leaves += 1;
+            usage.used_bytes += size_of::<Leaf<V>>() + leaf.key.capacity();
+        }
+        match &self.body {
+            Body::Leaf => {}
+            Body::Node4(_) => {
+                usage.node4 += 1;
+                usage.used_bytes += size_of::<Keyed<V, NODE4MAX>>();
+            }
+            Body::Node16(_) => {
+                usage.node16 += 1;
+                usage.used_bytes += size_of::<Keyed<V, NODE16MAX>>();
+            }
+            Body::Node48(_) => {
+                usage.node48 += 1;
+                usage.used_bytes += size_of::<Body48<V>>();
+            }
+            Body::Node256(_) => {
+                usage.node256 += 1;
+                usage.used_bytes += size_of::<[Atomic<Node<V>>; NODE256MAX]>();
+            }
+        }
+        self.for_each_child(guard, |_, child| {
+            unsafe { child.deref() }.memory_usage(usage, guard)
+        });
+    }
+}
+
+#[inline]
+fn atomic_bytes<const N: usize>() -> [AtomicU8; N] {
+    std::array::from_fn(|_| AtomicU8::new(0))
+}
+
+#[inline]
+fn null_children<V, const N: usize>() -> [Atomic<Node<V>>; N] {
+    std::array::from_fn(|_| Atomic::null())
+}
@@ -1,46 +1,779 @@
-use crate::art::ArtTree;
+use crate::art::{ArtTree, Snapshot};
+use crate::checkpointer::{CheckpointStats, Checkpointer};
+use crate::flusher::{Flusher, SyncStats};
+use crate::image::load_image;
 use crate::option::Option;
-use std::sync::atomic::AtomicUsize;
-use std::sync::{Arc, RwLock};
-use std::time::SystemTime;
+use crate::storage::{Blocks, KVpos, Storage};
+use crate::wal::{BatchOps, Wal};
+use parking_lot::{Condvar, Mutex, RwLock};
+use std::collections::{BTreeMap, VecDeque};
+use std::fs;
+use std::io;
+use std::iter::Rev;
+use std::ops::{Bound, RangeBounds};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
 
-pub struct DB<'a> {
+// The changes a reader subscribed to get, and the stats of the WAL.
+pub use crate::wal::{Change, Changes, Operate, Ops, WalStats, DELETE, INSERT};
+
+const KV_FILE: &str = "kv.data";
+const META_FILE: &str = "kv.meta";
+const INDEX_FILE: &str = "kv.index";
+const WAL_DIR: &str = "wal";
+
+// How many kv pairs are looked at to estimate the size under a prefix.
+const SIZE_SAMPLES: usize = 64;
+
+// How many kv pairs a scan reads under one read lock of the index.
+const SCAN_CHUNK: usize = 64;
+
+pub struct DB {
     opt: Option,
 
     // write-transaction id
     // I give each write-txn an ID by txn_id
     // and spawn one thread to execute all concurrent writing transactions.
-    txn_id: AtomicUsize,
+    // The ID is the sequence number the WAL gives the batch of the write-txn,
+    // txn_id is the last one logged.
+    txn_id: AtomicU64,
+
+    core: Arc<Core>,
+    flusher: Flusher,
+    checkpointer: Checkpointer,
+}
 
-    // When data has been stored in disk and apply into ART-tree, it would be recorded two timestamps
-    // because now the data can be read for user but the data maybe not in ART-tree yet.
-    /// commit_ts is the last timestamp when the data are stored in disk.
-    commit_ts: SystemTime,
+// What the batches are logged and applied by, shared with the shippers of the WAL
+// as they take a checkpoint for a follower the WAL can't bring up to date any more.
+pub(crate) struct Core {
+    // Writers log their batches into the WAL at once, so that concurrent ones share
+    // an append and an fsync, then apply them into state one by one in sequence order.
+    state: RwLock<State>,
+    // The sequence number of the last batch applied into state.
+    applied: Mutex<u64>,
+    // Signalled when a batch is applied.
+    applied_cond: Condvar,
+    // Held shared from giving a batch its checkpoint until it is logged,
+    // and exclusively while a checkpoint is taken, so the WAL stays in checkpoint order.
+    logging: RwLock<()>,
 
-    /// apply_ts is the last timestamp when the data are applied into ART-tree.
-    apply_ts: SystemTime,
-    // When apply_ts < reading_request_ts <= commit_ts and reading_key is in key_cache,
-    // the reading ops will wait until reading_request_ts reaches apply_ts.
-    // Otherwise, just read in ART-tree.
+    wal: Arc<Wal>,
 
-    // key_cache is already in disk and going to apply into ART-tree.
-    key_cache: Arc<Vec<u8>>,
+    // The last checkpoint taken, new batches are covered by the one after it.
+    // Once its image is written everything before it is synced in disk and in the index image,
+    // so recovery only replays the WAL written after it.
+    // A read-only db logs the batches of its leader under the leader's checkpoints,
+    // so its own checkpoints take the number of the last one of the leader it has whole.
+    ckpt: AtomicU64,
+    // Whether the db is read-only, for the checkpoints the shippers take.
+    read_only: AtomicBool,
+}
 
-    tree: ArtTree<'a>,
-    //disk: Arc<RwLock<KV>>,
+// The index and the kv files it points to, changed only by applying batches.
+struct State {
+    tree: ArtTree<KVpos>,
+    disk: Storage,
+    // The seqs of the exports being read, with how many there are of each.
+    // The blocks freed after the oldest one are not reused, so its values stay readable.
+    held: Mutex<BTreeMap<u64, usize>>,
 }
 
-impl<'a> DB<'a> {
-    pub fn new(opt: Option) -> DB<'a> {
-        let now = SystemTime::now();
-        DB {
+impl DB {
+    // Opens the db in the dirs of opt, from its index image and the WAL after it.
+    // Fails if they can't be read, or if the WAL is broken under WalRecovery::Fail.
+    pub fn new(opt: Option) -> io::Result<DB> {
+        fs::create_dir_all(opt.kv_dir)?;
+        fs::create_dir_all(opt.meta_dir)?;
+        let disk = Storage::new(
+            &format!("{}/{}", opt.kv_dir, KV_FILE),
+            &format!("{}/{}", opt.meta_dir, META_FILE),
+        )?;
+        let (tree, ckpt) =
+            load_image(&format!("{}/{}", opt.meta_dir, INDEX_FILE))?.unwrap_or_default();
+        let wal = Wal::new(
+            &format!("{}/{}", opt.meta_dir, WAL_DIR),
+            opt.limit_per_file,
+            opt.wal_recovery,
+        )?;
+        let batches = wal.recover(ckpt)?;
+        let last_seq = wal.last_seq();
+        let wal = Arc::new(wal);
+        let flusher = Flusher::new(
+            opt.sync,
+            wal.clone(),
+            disk.try_clone_files()?,
+            opt.on_sync_error,
+        );
+        let checkpointer = Checkpointer::new(
+            opt.checkpoint_interval_ms,
+            ckpt,
+            wal.clone(),
+            disk.try_clone_files()?,
+            format!("{}/{}", opt.meta_dir, INDEX_FILE),
+            opt.on_checkpoint_error,
+        );
+        // Checkpoints taken before the restart may not have been written,
+        // the numbers go on after them so that the WAL stays in checkpoint order.
+        let last_ckpt = batches
+            .iter()
+            .map(|batch| batch.checkpoint())
+            .fold(ckpt, u64::max);
+        // A follower logs the batches under the checkpoints of its leader, and the leader
+        // may log more batches of the checkpoint of the last one. So the follower only has
+        // the checkpoints before that one whole.
+        let ckpt = if opt.read_only {
+            ckpt.max(last_ckpt.saturating_sub(1))
+        } else {
+            last_ckpt
+        };
+
+        let mut state = State {
+            tree,
+            disk,
+            held: Mutex::new(BTreeMap::new()),
+        };
+        // The blocks the index image does not take are free, the replayed batches may use them.
+        let live = state.tree.iter().map(|(_, kv_pos)| *kv_pos);
+        state.disk.rebuild(live)?;
+        // The index image is at ckpt, the batches after it are applied again.
+        // They are durable, as the WAL is synced when it is opened.
+        for batch in batches.iter() {
+            state.apply(batch, batch.id(), wal.durable_seq())?;
+        }
+
+        Ok(DB {
             opt,
-            txn_id: AtomicUsize::new(0),
-            commit_ts: now,
-            key_cache: Arc::new(Vec::new()),
-            apply_ts: now,
-            //disk: KV::new(opt.meta_dir, opt.kv_dir, opt.limit_per_file),
+            txn_id: AtomicU64::new(last_seq),
+            core: Arc::new(Core {
+                state: RwLock::new(state),
+                applied: Mutex::new(last_seq),
+                applied_cond: Condvar::new(),
+                logging: RwLock::new(()),
+                wal,
+                ckpt: AtomicU64::new(ckpt),
+                read_only: AtomicBool::new(opt.read_only),
+            }),
+            flusher,
+            checkpointer,
+        })
+    }
+
+    // Syncs the kv data and writes the index image at a new checkpoint,
+    // then deletes the WAL segments it covers. Returns the new checkpoint number.
+    // It waits for a background checkpoint being written.
+    // On a follower it is the last checkpoint of the leader it has whole,
+    // the image is not written again while that stays the same.
+    pub fn checkpoint(&self) -> io::Result<u64> {
+        let (snapshot, ckpt, _) = self.core.take_checkpoint(false);
+        self.checkpointer.write_now(snapshot, ckpt)?;
+        Ok(ckpt)
+    }
+
+    // Syncs the WAL and the kv files now, whatever the sync policy is.
+    // Once it returns, every write before it survives a power loss.
+    pub fn sync(&self) -> io::Result<()> {
+        self.flusher.sync()
+    }
+
+    // Streams every insert and delete logged from the batch from_seq on, in order,
+    // each with the sequence number of its batch. It keeps tailing the WAL for new writes.
+    // A batch shows up only once its commit is durable, so with a lazy sync policy
+    // it waits for the next sync. Checkpoints keep the WAL segments it has still to read.
+    pub fn subscribe_changes(&self, from_seq: u64) -> io::Result<Changes> {
+        self.core.wal.subscribe(from_seq)
+    }
+
+    // The sequence number of the last logged batch.
+    pub fn last_seq(&self) -> u64 {
+        self.txn_id.load(Ordering::SeqCst)
+    }
+
+    // The last checkpoint whose index image is written.
+    pub fn last_checkpoint(&self) -> u64 {
+        self.checkpointer.durable()
+    }
+
+    pub fn checkpoint_stats(&self) -> CheckpointStats {
+        self.checkpointer.stats()
+    }
+
+    // The syncs of the WAL and the kv files, and the ones which failed.
+    pub fn sync_stats(&self) -> SyncStats {
+        self.flusher.stats()
+    }
+
+    // How many batches the WAL logged, in how many appends and syncs.
+    pub fn wal_stats(&self) -> WalStats {
+        self.core.wal.stats()
+    }
+
+    pub fn put(&self, key: Vec<u8>, value: Vec<u8>) -> io::Result<()> {
+        self.check_writable()?;
+        if key.len() + value.len() > u16::MAX as usize {
+            return Err(io::Error::other("kv data is too large"));
+        }
+        let mut batch = self.new_batch();
+        batch.insert(key, value);
+        self.commit(batch)
+    }
+
+    pub fn delete(&self, key: &[u8]) -> io::Result<()> {
+        self.check_writable()?;
+        if self.core.state.read().tree.get(key).is_none() {
+            return Ok(());
+        }
+        let mut batch = self.new_batch();
+        batch.delete(key.to_vec());
+        self.commit(batch)
+    }
+
+    pub fn get(&self, key: &[u8]) -> io::Result<std::option::Option<Vec<u8>>> {
+        let state = self.core.state.read();
+        match state.tree.get(key) {
+            Some(kv_pos) => state.disk.read_kv(*kv_pos).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    // Finds the kv pair whose key is the longest stored prefix of the given key.
+    pub fn longest_prefix_match(
+        &self,
+        key: &[u8],
+    ) -> io::Result<std::option::Option<(Vec<u8>, Vec<u8>)>> {
+        let state = self.core.state.read();
+        match state.tree.longest_prefix_match(key) {
+            Some((key, kv_pos)) => Ok(Some((key.to_vec(), state.disk.read_kv(*kv_pos)?))),
+            None => Ok(None),
+        }
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.opt.read_only
+    }
+
+    // A promoted follower takes writes from then on,
+    // its checkpoints go on after the last one of the leader.
+    pub(crate) fn set_read_only(&mut self, read_only: bool) {
+        self.opt.read_only = read_only;
+        self.core.read_only.store(read_only, Ordering::SeqCst);
+    }
+
+    // The last checkpoint all of whose batches are applied.
+    #[inline]
+    pub(crate) fn applied_checkpoint(&self) -> u64 {
+        self.core.ckpt.load(Ordering::SeqCst)
+    }
+
+    // Logs and applies a batch of the leader. The follower logs it under the same
+    // sequence number, so it must be the one after the last logged batch.
+    pub(crate) fn replicate(&self, batch: BatchOps) -> io::Result<()> {
+        let expected = self.last_seq() + 1;
+        if batch.id() != expected {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "follower expects seq {} but got seq {}",
+                    expected,
+                    batch.id()
+                ),
+            ));
+        }
+        // The batch keeps the checkpoint of the leader.
+        self.commit(batch)
+    }
+
+    #[inline]
+    pub(crate) fn wal(&self) -> Arc<Wal> {
+        self.core.wal.clone()
+    }
+
+    #[inline]
+    pub(crate) fn core(&self) -> Arc<Core> {
+        self.core.clone()
+    }
+
+    // Starts to restore the kv pairs of the leader as of its batch seq, the last one of checkpoint.
+    pub(crate) fn start_restore(&self, seq: u64, checkpoint: u64) -> Restore {
+        Restore {
+            seq,
+            checkpoint,
             tree: ArtTree::default(),
         }
     }
+
+    // Writes a chunk of the restored kv pairs next to the ones the follower has,
+    // which are still read until the restore is finished.
+    pub(crate) fn restore_chunk(&self, restore: &mut Restore, pairs: &BatchOps) -> io::Result<()> {
+        let mut state = self.core.state.write();
+        for ops in pairs.ops() {
+            if ops.op() != INSERT {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "a restored chunk holds only inserts",
+                ));
+            }
+            let kv_pos = state.write_pair(ops.key(), ops.value(), None)?;
+            restore.tree.insert(ops.key().to_vec(), kv_pos);
+        }
+        Ok(())
+    }
+
+    // Puts the restored kv pairs in the place of the ones the follower had.
+    // They are written as a checkpoint before the WAL goes on from the batch after the restored one,
+    // so a crash in between leaves the restored pairs under the old last seq,
+    // and the follower is restored again once it comes back.
+    pub(crate) fn finish_restore(&self, restore: Restore) -> io::Result<()> {
+        let Restore {
+            seq,
+            checkpoint,
+            tree,
+        } = restore;
+        if seq <= self.last_seq() || checkpoint <= self.applied_checkpoint() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "follower at seq {} and checkpoint {} can't be restored to seq {} and checkpoint {}",
+                    self.last_seq(),
+                    self.applied_checkpoint(),
+                    seq,
+                    checkpoint
+                ),
+            ));
+        }
+        let _logging = self.core.logging.write();
+        let mut applied = self.core.applied.lock();
+        let mut state = self.core.state.write();
+        let old = std::mem::replace(&mut state.tree, tree);
+        // The old index image still points at the old kvs, their blocks are freed
+        // once the WAL goes on after seq, which is after the new image is written.
+        for (_, kv_pos) in old.iter() {
+            let mut blocks = kv_pos.blocks();
+            state.disk.delete_kv(&mut blocks);
+            state.disk.free_after(seq, blocks);
+            state.disk.delete_meta(*kv_pos)?;
+        }
+        let snapshot = state.tree.snapshot();
+        drop(state);
+        // The batches the follower logged are all in the checkpoint of the leader.
+        self.core.ckpt.store(checkpoint, Ordering::SeqCst);
+        self.checkpointer.write_now(snapshot, checkpoint)?;
+        self.core.wal.restart_at(seq + 1)?;
+        self.core.wal.reclaim(checkpoint)?;
+        *applied = seq;
+        self.txn_id.store(seq, Ordering::SeqCst);
+        Ok(())
+    }
+
+    #[inline]
+    fn check_writable(&self) -> io::Result<()> {
+        if self.opt.read_only {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "db is read-only",
+            ));
+        }
+        Ok(())
+    }
+
+    // The id of the batch is given by the WAL when it is logged, the checkpoint by commit.
+    #[inline]
+    fn new_batch(&self) -> BatchOps {
+        BatchOps::new(0, 0, 0)
+    }
+
+    // Logs the batch into the WAL before applying it,
+    // so that it is replayed if the process dies in the middle of applying.
+    fn commit(&self, mut batch: BatchOps) -> io::Result<()> {
+        let logging = self.core.logging.read();
+        // A read-only db only logs the batches of its leader, with their checkpoint.
+        let read_only = self.is_read_only();
+        if !read_only {
+            batch.set_checkpoint(self.core.ckpt.load(Ordering::SeqCst) + 1);
+        }
+        let seq = self
+            .core
+            .wal
+            .append_wal(&batch, self.flusher.sync_on_append())?;
+        if read_only {
+            // The leader logs no batch of a checkpoint before the one it took.
+            self.core
+                .ckpt
+                .fetch_max(batch.checkpoint().saturating_sub(1), Ordering::SeqCst);
+        }
+        drop(logging);
+        self.txn_id.fetch_max(seq, Ordering::SeqCst);
+
+        // The batches are applied in the order they are logged.
+        let mut applied = self.core.applied.lock();
+        while *applied + 1 < seq {
+            self.core.applied_cond.wait(&mut applied);
+        }
+        let result = self
+            .core
+            .state
+            .write()
+            .apply(&batch, seq, self.core.wal.durable_seq());
+        // A failed batch is still passed, so the ones after it are not stuck.
+        *applied = seq;
+        self.core.applied_cond.notify_all();
+        drop(applied);
+        result?;
+
+        // Between two batches the tree is a whole checkpoint.
+        if self.checkpointer.take_due() {
+            let (snapshot, ckpt, _) = self.core.take_checkpoint(false);
+            self.checkpointer.submit(snapshot, ckpt);
+        }
+        self.flusher.logged();
+        Ok(())
+    }
+
+    // Iterates the kv pairs whose key is in range, in key order.
+    // Values are read from disk when the iterator gets to them.
+    // It reads SCAN_CHUNK pairs at a time, so writes between the chunks may show up in it.
+    pub fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Scan<'_> {
+        Scan {
+            db: self,
+            start: range.start_bound().cloned(),
+            end: range.end_bound().cloned(),
+            front: VecDeque::new(),
+            back: VecDeque::new(),
+        }
+    }
+
+    // Iterates the kv pairs whose key is in range, in reverse key order.
+    pub fn scan_rev<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Rev<Scan<'_>> {
+        self.scan(range).rev()
+    }
+
+    // Iterates the kv pairs whose key starts with prefix, in key order.
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Scan<'_> {
+        self.scan(prefix_range(prefix))
+    }
+
+    // Counts the keys in range from the leaf counts of the index, without reading them.
+    pub fn count_range<R: RangeBounds<Vec<u8>>>(&self, range: R) -> usize {
+        self.core.state.read().tree.count_range(range)
+    }
+
+    // Estimates the total size of the values whose key starts with prefix.
+    // Up to SIZE_SAMPLES keys evenly spread over the prefix are looked at,
+    // the sum is exact when there are not more keys than that.
+    pub fn approximate_size(&self, prefix: &[u8]) -> u64 {
+        let range = prefix_range(prefix);
+        let tree = &self.core.state.read().tree;
+        let first = tree.rank(prefix);
+        let count = tree.count_range(range);
+        if count == 0 {
+            return 0;
+        }
+        let samples = count.min(SIZE_SAMPLES);
+        let sampled: u64 = (0..samples)
+            .filter_map(|i| tree.nth(first + i * count / samples))
+            .map(|(_, kv_pos)| kv_pos.value_size() as u64)
+            .sum();
+        sampled * count as u64 / samples as u64
+    }
+}
+
+impl Core {
+    // Starts a new checkpoint once every batch logged before it is applied,
+    // and returns the snapshot of the index it covers with the seq of its last batch.
+    // hold keeps the blocks freed after that batch, until unhold.
+    // A read-only db takes no new number, see ckpt.
+    fn take_checkpoint(&self, hold: bool) -> (Snapshot<KVpos>, u64, u64) {
+        let _logging = self.logging.write();
+        let last_seq = self.wal.last_seq();
+        let mut applied = self.applied.lock();
+        while *applied < last_seq {
+            self.applied_cond.wait(&mut applied);
+        }
+        let state = self.state.read();
+        if hold {
+            *state.held.lock().entry(last_seq).or_insert(0) += 1;
+        }
+        let snapshot = state.tree.snapshot();
+        let ckpt = if self.read_only.load(Ordering::SeqCst) {
+            self.ckpt.load(Ordering::SeqCst)
+        } else {
+            self.ckpt.fetch_add(1, Ordering::SeqCst) + 1
+        };
+        (snapshot, ckpt, last_seq)
+    }
+
+    fn unhold(&self, seq: u64) {
+        let state = self.state.read();
+        let mut held = state.held.lock();
+        if let Some(count) = held.get_mut(&seq) {
+            *count -= 1;
+            if *count == 0 {
+                held.remove(&seq);
+            }
+        }
+    }
+
+    // Takes a checkpoint to read its kv pairs out, for a follower the WAL can't bring up to date.
+    pub(crate) fn export(self: &Arc<Self>) -> Export {
+        let (tree, checkpoint, seq) = self.take_checkpoint(true);
+        Export {
+            core: self.clone(),
+            tree,
+            checkpoint,
+            seq,
+            last: None,
+        }
+    }
+}
+
+impl State {
+    // Applies batch seq, the blocks freed by the batches up to durable_seq can be reused by it.
+    fn apply(&mut self, batch: &BatchOps, seq: u64, durable_seq: u64) -> io::Result<()> {
+        let held = self.held.lock().keys().next().copied();
+        self.disk
+            .release(held.map_or(durable_seq, |held| held.min(durable_seq)));
+        for ops in batch.ops() {
+            match ops.op() {
+                INSERT => self.apply_put(seq, ops.key().to_vec(), ops.value())?,
+                DELETE => self.apply_delete(seq, ops.key())?,
+                _ => unreachable!("ops are checked when decoded"),
+            }
+        }
+        Ok(())
+    }
+
+    // Writes the kv data and its meta entry, the old blocks are kept until they are freed.
+    fn write_pair(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        old_blocks: std::option::Option<&mut Blocks>,
+    ) -> io::Result<KVpos> {
+        // kv data is stored as key followed by value.
+        let value_pos = key.len() as u16;
+        let mut data = key.to_vec();
+        data.extend_from_slice(value);
+        let blocks = self.disk.write_kv(&mut data, old_blocks)?;
+        let kv_pos = KVpos::new(blocks, value_pos, data.len() as u16);
+        self.disk.write_meta(kv_pos)?;
+        Ok(kv_pos)
+    }
+
+    fn apply_put(&mut self, seq: u64, key: Vec<u8>, value: &[u8]) -> io::Result<()> {
+        let old_kv_pos = self.tree.get(&key).copied();
+        let mut old_blocks = old_kv_pos.map(|kv_pos| kv_pos.blocks());
+        let kv_pos = self.write_pair(&key, value, old_blocks.as_mut())?;
+        if let Some(old_kv_pos) = old_kv_pos {
+            self.disk.delete_meta(old_kv_pos)?;
+        }
+
+        self.tree.insert(key, kv_pos);
+        // The old blocks can be reused once this batch is durable.
+        if let Some(blocks) = old_blocks {
+            self.disk.free_after(seq, blocks);
+        }
+        Ok(())
+    }
+
+    fn apply_delete(&mut self, seq: u64, key: &[u8]) -> io::Result<()> {
+        if let Some(kv_pos) = self.tree.remove(key) {
+            let mut blocks = kv_pos.blocks();
+            self.disk.delete_kv(&mut blocks);
+            self.disk.free_after(seq, blocks);
+            self.disk.delete_meta(kv_pos)?;
+        }
+        Ok(())
+    }
+}
+
+// The kv pairs of a checkpoint, read out a chunk at a time in key order.
+// Its values stay readable while it lives, as the blocks freed after it are not reused.
+pub(crate) struct Export {
+    core: Arc<Core>,
+    tree: Snapshot<KVpos>,
+    checkpoint: u64,
+    // The last batch the checkpoint covers.
+    seq: u64,
+    // The last key read out.
+    last: std::option::Option<Vec<u8>>,
+}
+
+impl Export {
+    #[inline]
+    pub(crate) fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    #[inline]
+    pub(crate) fn checkpoint(&self) -> u64 {
+        self.checkpoint
+    }
+
+    // Reads the next SCAN_CHUNK kv pairs as a batch of inserts, None once all are read.
+    pub(crate) fn next_chunk(&mut self) -> io::Result<std::option::Option<BatchOps>> {
+        let state = self.core.state.read();
+        let iter = match &self.last {
+            Some(last) => self.tree.seek(last),
+            None => self.tree.iter(),
+        };
+        let mut pairs = BatchOps::new(self.seq, 0, self.checkpoint);
+        for (key, kv_pos) in iter {
+            if pairs.ops().len() >= SCAN_CHUNK {
+                break;
+            }
+            if self.last.as_deref() == Some(key) {
+                continue;
+            }
+            pairs.insert(key.to_vec(), state.disk.read_kv(*kv_pos)?);
+        }
+        match pairs.ops().last() {
+            Some(ops) => {
+                self.last = Some(ops.key().to_vec());
+                Ok(Some(pairs))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+impl Drop for Export {
+    fn drop(&mut self) {
+        self.core.unhold(self.seq);
+    }
+}
+
+// The kv pairs of the leader a follower restores, indexed apart until they take the place of its own.
+pub(crate) struct Restore {
+    // The last batch of the leader they cover, and the leader checkpoint it is the last one of.
+    seq: u64,
+    checkpoint: u64,
+    tree: ArtTree<KVpos>,
+}
+
+impl Drop for DB {
+    fn drop(&mut self) {
+        // The last sync goes first, so the change readers get every write before they end.
+        self.flusher.stop();
+        self.core.wal.close();
+    }
+}
+
+// The range of all keys starting with prefix.
+fn prefix_range(prefix: &[u8]) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    let start = Bound::Included(prefix.to_vec());
+    match prefix_end(prefix) {
+        Some(end) => (start, Bound::Excluded(end)),
+        None => (start, Bound::Unbounded),
+    }
+}
+
+// The smallest key which is greater than all keys starting with prefix.
+// None means there is no such key, e.g. the prefix is empty or all 0xff.
+fn prefix_end(prefix: &[u8]) -> std::option::Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+// Scan walks from both ends of the range.
+// Each end reads a chunk of pairs ahead and narrows the range past them,
+// so the two ends never read the same key. Once the range is empty,
+// each end goes on with what the other end has read ahead.
+pub struct Scan<'a> {
+    db: &'a DB,
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    // Read ahead from the front in key order, and from the back in reverse key order.
+    front: VecDeque<io::Result<(Vec<u8>, Vec<u8>)>>,
+    back: VecDeque<io::Result<(Vec<u8>, Vec<u8>)>>,
+}
+
+impl<'a> Scan<'a> {
+    #[inline]
+    fn after_start(&self, key: &[u8]) -> bool {
+        match &self.start {
+            Bound::Included(start) => key >= start.as_slice(),
+            Bound::Excluded(start) => key > start.as_slice(),
+            Bound::Unbounded => true,
+        }
+    }
+
+    #[inline]
+    fn before_end(&self, key: &[u8]) -> bool {
+        match &self.end {
+            Bound::Included(end) => key <= end.as_slice(),
+            Bound::Excluded(end) => key < end.as_slice(),
+            Bound::Unbounded => true,
+        }
+    }
+
+    fn fill_front(&mut self) {
+        let state = self.db.core.state.read();
+        let iter = match &self.start {
+            Bound::Included(start) | Bound::Excluded(start) => state.tree.seek(start),
+            Bound::Unbounded => state.tree.iter(),
+        };
+        for (key, kv_pos) in iter {
+            if !self.after_start(key) {
+                continue;
+            }
+            if !self.before_end(key) {
+                break;
+            }
+            let value = state.disk.read_kv(*kv_pos);
+            self.front
+                .push_back(value.map(|value| (key.to_vec(), value)));
+            self.start = Bound::Excluded(key.to_vec());
+            if self.front.len() >= SCAN_CHUNK {
+                break;
+            }
+        }
+    }
+
+    fn fill_back(&mut self) {
+        let state = self.db.core.state.read();
+        let iter = match &self.end {
+            Bound::Included(end) | Bound::Excluded(end) => state.tree.seek_for_prev(end),
+            Bound::Unbounded => state.tree.iter_rev(),
+        };
+        for (key, kv_pos) in iter {
+            if !self.before_end(key) {
+                continue;
+            }
+            if !self.after_start(key) {
+                break;
+            }
+            let value = state.disk.read_kv(*kv_pos);
+            self.back
+                .push_back(value.map(|value| (key.to_vec(), value)));
+            self.end = Bound::Excluded(key.to_vec());
+            if self.back.len() >= SCAN_CHUNK {
+                break;
+            }
+        }
+    }
+}
+
+impl<'a> Iterator for Scan<'a> {
+    type Item = io::Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> std::option::Option<Self::Item> {
+        if self.front.is_empty() {
+            self.fill_front();
+        }
+        self.front.pop_front().or_else(|| self.back.pop_back())
+    }
+}
+
+impl<'a> DoubleEndedIterator for Scan<'a> {
+    fn next_back(&mut self) -> std::option::Option<Self::Item> {
+        if self.back.is_empty() {
+            self.fill_back();
+        }
+        self.back.pop_front().or_else(|| self.front.pop_back())
+    }
 }
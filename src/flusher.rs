@@ -0,0 +1,198 @@
+use crate::option::SyncPolicy;
+use crate::wal::Wal;
+use parking_lot::{Condvar, Mutex};
+use std::fs::File;
+use std::io;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+// How long the background thread waits after a failed sync.
+const RETRY_MS: u64 = 100;
+
+// What the flusher has synced since the db was opened.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SyncStats {
+    // Syncs of the WAL and the kv files, in the background or by DB::sync.
+    pub syncs: u64,
+    // Syncs which failed, in the background or not.
+    pub failures: u64,
+    pub last_error: Option<String>,
+}
+
+// Syncs the WAL and the kv files in a background thread, as the sync policy asks.
+// The kv files may get to disk before the batches that wrote them, that is fine as they are replayed.
+// Nothing the index image or the WAL still points at is written over, as the blocks a batch
+// frees are only reused once the batch is durable.
+// A failed background sync goes to the callback and the stats, not to the writers:
+// their batches are logged and applied already, the next sync tries again.
+pub(crate) struct Flusher {
+    policy: SyncPolicy,
+    shared: Arc<Shared>,
+    handle: Option<JoinHandle<()>>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    wake: Condvar,
+    wal: Arc<Wal>,
+    files: Vec<File>,
+    on_error: Option<fn(&io::Error)>,
+    stats: Mutex<SyncStats>,
+}
+
+#[derive(Default)]
+struct State {
+    // Batches logged since the last sync.
+    unsynced: u64,
+    stop: bool,
+}
+
+impl Flusher {
+    pub(crate) fn new(
+        policy: SyncPolicy,
+        wal: Arc<Wal>,
+        files: Vec<File>,
+        on_error: Option<fn(&io::Error)>,
+    ) -> Self {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State::default()),
+            wake: Condvar::new(),
+            wal,
+            files,
+            on_error,
+            stats: Mutex::new(SyncStats::default()),
+        });
+        let handle = match policy {
+            SyncPolicy::EveryN(_) | SyncPolicy::Interval(_) => {
+                let shared = shared.clone();
+                Some(
+                    thread::Builder::new()
+                        .name("tigadb-flusher".to_string())
+                        .spawn(move || shared.run(policy))
+                        .expect("spawn flusher error"),
+                )
+            }
+            SyncPolicy::Always | SyncPolicy::Never => None,
+        };
+        Flusher {
+            policy,
+            shared,
+            handle,
+        }
+    }
+
+    // Whether the WAL is synced by each append.
+    #[inline]
+    pub(crate) fn sync_on_append(&self) -> bool {
+        self.policy == SyncPolicy::Always
+    }
+
+    // Called after each batch is logged.
+    pub(crate) fn logged(&self) {
+        let mut state = self.shared.state.lock();
+        state.unsynced += 1;
+        if let SyncPolicy::EveryN(n) = self.policy {
+            if state.unsynced >= n {
+                self.shared.wake.notify_one();
+            }
+        }
+    }
+
+    // Syncs in the calling thread, its error is returned rather than passed to the callback.
+    pub(crate) fn sync(&self) -> io::Result<()> {
+        self.shared.sync()
+    }
+
+    pub(crate) fn stats(&self) -> SyncStats {
+        self.shared.stats.lock().clone()
+    }
+
+    // Stops the background thread once it synced what was logged.
+    pub(crate) fn stop(&mut self) {
+        self.shared.state.lock().stop = true;
+        self.shared.wake.notify_one();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Shared {
+    fn run(&self, policy: SyncPolicy) {
+        let mut state = self.state.lock();
+        while !state.stop {
+            match policy {
+                SyncPolicy::EveryN(n) => {
+                    if state.unsynced < n.max(1) {
+                        self.wake.wait(&mut state);
+                        continue;
+                    }
+                }
+                SyncPolicy::Interval(ms) => {
+                    self.wake
+                        .wait_for(&mut state, Duration::from_millis(ms.max(1)));
+                    if state.stop || state.unsynced == 0 {
+                        continue;
+                    }
+                }
+                SyncPolicy::Always | SyncPolicy::Never => return,
+            }
+            drop(state);
+            let synced = self.sync_in_background();
+            state = self.state.lock();
+            if !synced {
+                // The batches are left unsynced, they are tried again after a while
+                // rather than at once on a disk which keeps failing.
+                self.wake
+                    .wait_for(&mut state, Duration::from_millis(RETRY_MS));
+            }
+        }
+        // What was logged before the db is dropped is synced too.
+        if state.unsynced > 0 {
+            drop(state);
+            self.sync_in_background();
+        }
+    }
+
+    // Returns whether the sync went well.
+    fn sync_in_background(&self) -> bool {
+        match self.sync() {
+            Ok(()) => true,
+            Err(e) => {
+                error!("background sync error: {}", e);
+                if let Some(on_error) = self.on_error {
+                    on_error(&e);
+                }
+                false
+            }
+        }
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        let unsynced = std::mem::take(&mut self.state.lock().unsynced);
+        let result = self.sync_files();
+        let mut stats = self.stats.lock();
+        stats.syncs += 1;
+        if let Err(e) = &result {
+            stats.failures += 1;
+            stats.last_error = Some(e.to_string());
+            self.state.lock().unsynced += unsynced;
+        }
+        result
+    }
+
+    fn sync_files(&self) -> io::Result<()> {
+        self.wal.sync()?;
+        for file in self.files.iter() {
+            file.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Flusher {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
@@ -0,0 +1,204 @@
+use crate::art::ArtTree;
+use crate::storage::{KVpos, KV_POS_SIZE};
+use crate::util::{
+    bytes_to_u16, bytes_to_u32, bytes_to_u64, bytes_to_u8, u16_to_bytes, u32_to_bytes,
+    u64_to_bytes, u8_to_bytes,
+};
+use mmap::{MapOption, MemoryMap};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::os::unix::io::AsRawFd;
+use std::slice;
+
+// The index image is the ART-tree dumped in key order, so it can be bulk loaded on restart.
+//
+// | magic (4) | version (1) | checkpoint (8) | count (8) | entry ... | crc32c (4) |
+//
+// Keys are sorted, so each entry stores only what differs from the key before it:
+//
+// | shared len (2) | suffix len (2) | suffix | kv_pos (KV_POS_SIZE) |
+//
+// The crc32c covers everything before it, so a torn or rotten image fails to load.
+const MAGIC: &[u8; 4] = b"TGIX";
+const VERSION: u8 = 2;
+const HEADER_SIZE: usize = 4 + 1 + 8 + 8;
+const CRC_SIZE: usize = 4;
+
+// Writes the tree as the image of checkpoint into fpath.
+// The image is written aside and renamed over the old one once synced,
+// so a crash leaves either the old image or the new one.
+pub(crate) fn write_image(tree: &ArtTree<KVpos>, checkpoint: u64, fpath: &str) -> io::Result<()> {
+    let tmp_fpath = format!("{}.tmp", fpath);
+    let file = File::create(&tmp_fpath)?;
+    let mut w = CrcWriter {
+        inner: BufWriter::new(file),
+        crc: 0,
+    };
+
+    let count = tree.len() as u64;
+    w.write_all(MAGIC)?;
+    w.write_all(&u8_to_bytes(VERSION))?;
+    w.write_all(&u64_to_bytes(checkpoint))?;
+    w.write_all(&u64_to_bytes(count))?;
+
+    let mut prev: &[u8] = &[];
+    for (key, kv_pos) in tree.iter() {
+        if key.len() > u16::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "key is too large",
+            ));
+        }
+        let shared = prev
+            .iter()
+            .zip(key.iter())
+            .take_while(|(a, b)| a == b)
+            .count();
+        w.write_all(&u16_to_bytes(shared as u16))?;
+        w.write_all(&u16_to_bytes((key.len() - shared) as u16))?;
+        w.write_all(&key[shared..])?;
+        w.write_all(&kv_pos.encode())?;
+        prev = key;
+    }
+
+    let crc = w.crc;
+    let mut w = w.inner;
+    w.write_all(&u32_to_bytes(crc))?;
+    let file = w.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    fs::rename(&tmp_fpath, fpath)?;
+    // Make the rename itself durable.
+    if let Some(dir) = std::path::Path::new(fpath).parent() {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+// Maps the image in fpath and rebuilds the tree from it.
+// Returns the tree and the checkpoint it was written at, or None if there is no image yet.
+// The map is only how the file is read: every key is copied out of it into
+// the Vec its leaf owns, so the tree does not borrow the map.
+pub(crate) fn load_image(fpath: &str) -> io::Result<Option<(ArtTree<KVpos>, u64)>> {
+    let file = match File::open(fpath) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let len = file.metadata()?.len() as usize;
+    if len < HEADER_SIZE + CRC_SIZE {
+        return Err(corrupt("index image is truncated"));
+    }
+    let map = MemoryMap::new(
+        len,
+        &[MapOption::MapReadable, MapOption::MapFd(file.as_raw_fd())],
+    )
+    .map_err(|e| io::Error::other(format!("mmap index image error: {}", e)))?;
+    // The map lives until the end of this function and the file is not written meanwhile.
+    let data = unsafe { slice::from_raw_parts(map.data() as *const u8, len) };
+
+    let (data, crc) = data.split_at(len - CRC_SIZE);
+    let (header, body) = data.split_at(HEADER_SIZE);
+    if &header[..4] != MAGIC {
+        return Err(corrupt("bad index image magic"));
+    }
+    if bytes_to_u8(&header[4..5]) != VERSION {
+        return Err(corrupt("unknown index image version"));
+    }
+    if crc32c::crc32c(data) != bytes_to_u32(crc) {
+        return Err(corrupt("index image checksum mismatch"));
+    }
+    let checkpoint = bytes_to_u64(&header[5..13]);
+    let count = bytes_to_u64(&header[13..21]);
+
+    let mut entries = Entries {
+        data: body,
+        key: Vec::new(),
+        left: count,
+        err: None,
+    };
+    let tree = ArtTree::from_sorted_iter(&mut entries)?;
+    if let Some(e) = entries.err {
+        return Err(e);
+    }
+    if entries.left != 0 || !entries.data.is_empty() {
+        return Err(corrupt("index image count does not match its entries"));
+    }
+    Ok(Some((tree, checkpoint)))
+}
+
+#[inline]
+fn corrupt(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// Keeps the crc32c of everything written through it.
+struct CrcWriter<W> {
+    inner: W,
+    crc: u32,
+}
+
+impl<W: Write> Write for CrcWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.crc = crc32c::crc32c_append(self.crc, &buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Decodes the entries one by one and stops at the first broken one, keeping its error.
+struct Entries<'a> {
+    data: &'a [u8],
+    key: Vec<u8>,
+    left: u64,
+    err: Option<io::Error>,
+}
+
+impl<'a> Entries<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.data.len() < n {
+            return Err(corrupt("index image is truncated"));
+        }
+        let (head, tail) = self.data.split_at(n);
+        self.data = tail;
+        Ok(head)
+    }
+
+    fn decode(&mut self) -> io::Result<(Vec<u8>, KVpos)> {
+        let shared = bytes_to_u16(self.take(2)?) as usize;
+        let suffix_len = bytes_to_u16(self.take(2)?) as usize;
+        if shared > self.key.len() {
+            return Err(corrupt(
+                "index image entry shares more than the key before it",
+            ));
+        }
+        let suffix = self.take(suffix_len)?;
+        self.key.truncate(shared);
+        self.key.extend_from_slice(suffix);
+        let kv_pos = KVpos::decode(&mut self.take(KV_POS_SIZE)?.to_vec());
+        Ok((self.key.clone(), kv_pos))
+    }
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = (Vec<u8>, KVpos);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.left == 0 || self.err.is_some() {
+            return None;
+        }
+        match self.decode() {
+            Ok(entry) => {
+                self.left -= 1;
+                Some(entry)
+            }
+            Err(e) => {
+                self.err = Some(e);
+                None
+            }
+        }
+    }
+}
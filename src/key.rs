@@ -0,0 +1,217 @@
+use crate::util::{
+    bytes_to_u16, bytes_to_u32, bytes_to_u64, bytes_to_u8, u16_to_bytes, u32_to_bytes,
+    u64_to_bytes, u8_to_bytes,
+};
+use std::io;
+
+// Encodes typed keys into bytes whose memcmp order is the logical order of the keys,
+// so that the ART-tree keeps them sorted and range scans over them are correct.
+//
+// unsigned ints: big-endian.
+// signed ints:   big-endian with the sign bit flipped, so negatives come first.
+// floats:        IEEE-754 bits, all flipped for negatives and the sign bit flipped otherwise.
+//                -0.0 sorts before 0.0 and NaNs sort after the infinities of their sign.
+// strings/bytes: 0x00 is escaped as 0x00 0xff and the end is 0x00 0x01,
+//                so a string sorts before all the strings it is a prefix of.
+// tuples:        the parts one after another. Every part is self-delimiting.
+pub trait KeyEncode {
+    fn encode_key(&self, buf: &mut Vec<u8>);
+}
+
+pub trait KeyDecode: Sized {
+    // Decodes one value from the front of data and moves data past it.
+    fn decode_key(data: &mut &[u8]) -> io::Result<Self>;
+}
+
+pub fn encode_key<T: KeyEncode + ?Sized>(key: &T) -> Vec<u8> {
+    let mut buf = Vec::new();
+    key.encode_key(&mut buf);
+    buf
+}
+
+pub fn decode_key<T: KeyDecode>(mut data: &[u8]) -> io::Result<T> {
+    let key = T::decode_key(&mut data)?;
+    if !data.is_empty() {
+        return Err(invalid("trailing bytes after key"));
+    }
+    Ok(key)
+}
+
+const ESCAPE: u8 = 0x00;
+const ESCAPED_ZERO: u8 = 0xff;
+const TERMINATOR: u8 = 0x01;
+
+#[inline]
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[inline]
+fn take<'a>(data: &mut &'a [u8], n: usize) -> io::Result<&'a [u8]> {
+    if data.len() < n {
+        return Err(invalid("key is truncated"));
+    }
+    let (head, tail) = data.split_at(n);
+    *data = tail;
+    Ok(head)
+}
+
+macro_rules! unsigned_key {
+    ($t:ty, $size:expr, $to_bytes:ident, $from_bytes:ident) => {
+        impl KeyEncode for $t {
+            #[inline]
+            fn encode_key(&self, buf: &mut Vec<u8>) {
+                buf.extend_from_slice(&$to_bytes(*self));
+            }
+        }
+
+        impl KeyDecode for $t {
+            #[inline]
+            fn decode_key(data: &mut &[u8]) -> io::Result<Self> {
+                Ok($from_bytes(take(data, $size)?))
+            }
+        }
+    };
+}
+
+unsigned_key!(u8, 1, u8_to_bytes, bytes_to_u8);
+unsigned_key!(u16, 2, u16_to_bytes, bytes_to_u16);
+unsigned_key!(u32, 4, u32_to_bytes, bytes_to_u32);
+unsigned_key!(u64, 8, u64_to_bytes, bytes_to_u64);
+
+macro_rules! signed_key {
+    ($t:ty, $u:ty) => {
+        impl KeyEncode for $t {
+            #[inline]
+            fn encode_key(&self, buf: &mut Vec<u8>) {
+                ((*self as $u) ^ (1 << (<$u>::BITS - 1))).encode_key(buf);
+            }
+        }
+
+        impl KeyDecode for $t {
+            #[inline]
+            fn decode_key(data: &mut &[u8]) -> io::Result<Self> {
+                Ok((<$u>::decode_key(data)? ^ (1 << (<$u>::BITS - 1))) as $t)
+            }
+        }
+    };
+}
+
+signed_key!(i8, u8);
+signed_key!(i16, u16);
+signed_key!(i32, u32);
+signed_key!(i64, u64);
+
+macro_rules! float_key {
+    ($t:ty, $u:ty) => {
+        impl KeyEncode for $t {
+            #[inline]
+            fn encode_key(&self, buf: &mut Vec<u8>) {
+                let bits = self.to_bits();
+                let sign = 1 << (<$u>::BITS - 1);
+                let bits = if bits & sign != 0 { !bits } else { bits ^ sign };
+                bits.encode_key(buf);
+            }
+        }
+
+        impl KeyDecode for $t {
+            #[inline]
+            fn decode_key(data: &mut &[u8]) -> io::Result<Self> {
+                let bits = <$u>::decode_key(data)?;
+                let sign = 1 << (<$u>::BITS - 1);
+                let bits = if bits & sign != 0 { bits ^ sign } else { !bits };
+                Ok(<$t>::from_bits(bits))
+            }
+        }
+    };
+}
+
+float_key!(f32, u32);
+float_key!(f64, u64);
+
+impl KeyEncode for [u8] {
+    fn encode_key(&self, buf: &mut Vec<u8>) {
+        for b in self.iter() {
+            buf.push(*b);
+            if *b == ESCAPE {
+                buf.push(ESCAPED_ZERO);
+            }
+        }
+        buf.push(ESCAPE);
+        buf.push(TERMINATOR);
+    }
+}
+
+impl KeyEncode for Vec<u8> {
+    #[inline]
+    fn encode_key(&self, buf: &mut Vec<u8>) {
+        self.as_slice().encode_key(buf)
+    }
+}
+
+impl KeyDecode for Vec<u8> {
+    fn decode_key(data: &mut &[u8]) -> io::Result<Self> {
+        let mut bytes = Vec::new();
+        loop {
+            let b = take(data, 1)?[0];
+            if b != ESCAPE {
+                bytes.push(b);
+                continue;
+            }
+            match take(data, 1)?[0] {
+                ESCAPED_ZERO => bytes.push(ESCAPE),
+                TERMINATOR => return Ok(bytes),
+                _ => return Err(invalid("bad escape in key")),
+            }
+        }
+    }
+}
+
+impl KeyEncode for str {
+    #[inline]
+    fn encode_key(&self, buf: &mut Vec<u8>) {
+        self.as_bytes().encode_key(buf)
+    }
+}
+
+impl KeyEncode for String {
+    #[inline]
+    fn encode_key(&self, buf: &mut Vec<u8>) {
+        self.as_bytes().encode_key(buf)
+    }
+}
+
+impl KeyDecode for String {
+    fn decode_key(data: &mut &[u8]) -> io::Result<Self> {
+        String::from_utf8(Vec::decode_key(data)?).map_err(|_| invalid("key is not utf-8"))
+    }
+}
+
+impl<T: KeyEncode + ?Sized> KeyEncode for &T {
+    #[inline]
+    fn encode_key(&self, buf: &mut Vec<u8>) {
+        (**self).encode_key(buf)
+    }
+}
+
+macro_rules! tuple_key {
+    ($($t:ident $i:tt),+) => {
+        impl<$($t: KeyEncode),+> KeyEncode for ($($t,)+) {
+            fn encode_key(&self, buf: &mut Vec<u8>) {
+                $(self.$i.encode_key(buf);)+
+            }
+        }
+
+        impl<$($t: KeyDecode),+> KeyDecode for ($($t,)+) {
+            fn decode_key(data: &mut &[u8]) -> io::Result<Self> {
+                Ok(($($t::decode_key(data)?,)+))
+            }
+        }
+    };
+}
+
+tuple_key!(A 0);
+tuple_key!(A 0, B 1);
+tuple_key!(A 0, B 1, C 2);
+tuple_key!(A 0, B 1, C 2, D 3);
+tuple_key!(A 0, B 1, C 2, D 3, E 4);
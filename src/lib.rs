@@ -1,5 +1,15 @@
+#[macro_use]
+extern crate log;
+
 pub mod art;
+pub mod checkpointer;
+pub mod concurrent_art;
 pub mod db;
+pub mod flusher;
+mod image;
+pub mod key;
 pub mod option;
+pub mod replication;
 pub mod storage;
 pub mod util;
+mod wal;
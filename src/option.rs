@@ -1,18 +1,62 @@
+use std::io;
+
 #[derive(Copy, Clone)]
 pub struct Option {
-    pub fsync: bool,
+    pub sync: SyncPolicy,
     pub limit_per_file: u64,
     pub meta_dir: &'static str,
     pub kv_dir: &'static str,
+    pub wal_recovery: WalRecovery,
+    // How often the background checkpointer makes a checkpoint, in milliseconds.
+    // 0 turns it off, checkpoints are only made by DB::checkpoint then.
+    pub checkpoint_interval_ms: u64,
+    // Called with the error of a failed background checkpoint.
+    // The failures are counted in DB::checkpoint_stats as well.
+    pub on_checkpoint_error: std::option::Option<fn(&io::Error)>,
+    // Called with the error of a failed background sync, the writes still return Ok.
+    // The failures are counted in DB::sync_stats as well.
+    pub on_sync_error: std::option::Option<fn(&io::Error)>,
+    // A read-only db refuses puts and deletes, it is changed only by replaying
+    // the WAL of a leader as a replication::Follower.
+    pub read_only: bool,
+}
+
+// What to do on open when the WAL ends with a record which is torn or fails its checksum.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WalRecovery {
+    // Drop the broken record and everything after it, then go on.
+    TruncateTail,
+    // Refuse to open, so that the files can be looked at.
+    Fail,
+}
+
+// When the WAL and the kv files are synced to disk.
+// A synced batch survives a power loss, the batches after the last sync may not.
+// DB::sync syncs at once whatever the policy is.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SyncPolicy {
+    // Sync the WAL before a write returns. Concurrent writes share one sync.
+    Always,
+    // Sync in the background once n batches were logged since the last sync.
+    EveryN(u64),
+    // Sync in the background every so many milliseconds.
+    Interval(u64),
+    // Only sync on DB::sync and at checkpoints.
+    Never,
 }
 
 impl Default for Option {
     fn default() -> Self {
         Self {
-            fsync: true,
+            sync: SyncPolicy::Always,
             limit_per_file: 2 * 1024 * 1024 * 1024,
             meta_dir: "tigadb/meta",
             kv_dir: "tigadb/kv",
+            wal_recovery: WalRecovery::TruncateTail,
+            checkpoint_interval_ms: 0,
+            on_checkpoint_error: None,
+            on_sync_error: None,
+            read_only: false,
         }
     }
 }
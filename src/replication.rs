@@ -0,0 +1,350 @@
+use crate::db::{Core, Restore, DB};
+use crate::util::{bytes_to_u32, bytes_to_u64, u32_to_bytes, u64_to_bytes};
+use crate::wal::{BatchOps, Batches, Wal};
+use std::io::{self, ErrorKind, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+// Carries whole messages between a leader and a follower, in order.
+pub trait Transport: Send {
+    fn send(&mut self, msg: &[u8]) -> io::Result<()>;
+
+    // Waits up to timeout for the next message, None if none came.
+    fn recv(&mut self, timeout: Duration) -> io::Result<Option<Vec<u8>>>;
+}
+
+// The follower says where to start, then the leader sends the batches from there:
+//
+// | HELLO (1) | from seq (8) |
+// | BATCH (1) | encoded batch, its id is its sequence number |
+//
+// If the leader has reclaimed some of those batches, it first sends the kv pairs
+// of a new checkpoint, then the batches after it:
+//
+// | SNAPSHOT (1) | seq of the last batch it covers (8) | checkpoint (8) |
+// | PAIRS (1) | encoded batch of inserts, the chunks come in key order |
+// | SNAPSHOT_END (1) |
+//
+// A follower which has batches the leader has not is refused, their histories differ:
+//
+// | REFUSED (1) | why, in UTF-8 |
+const HELLO: u8 = 1;
+const BATCH: u8 = 2;
+const SNAPSHOT: u8 = 3;
+const PAIRS: u8 = 4;
+const SNAPSHOT_END: u8 = 5;
+const REFUSED: u8 = 6;
+
+// How long the shipper waits before it looks whether the leader is closed.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[inline]
+fn bad_message(msg: &[u8]) -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidData,
+        format!("bad replication message of {} bytes", msg.len()),
+    )
+}
+
+// Ships the WAL of db to the follower at the other end of transport, from a new thread.
+// Only durable batches are sent, so a follower is never ahead of what the leader keeps.
+// A follower which asks for batches the leader has reclaimed already gets a snapshot first.
+// One which asks for batches after the next one of the leader is refused, and the thread fails.
+// The thread ends once the db is dropped and everything is sent, or when the transport fails.
+pub fn ship<T: Transport + 'static>(db: &DB, mut transport: T) -> JoinHandle<io::Result<()>> {
+    let wal = db.wal();
+    let core = db.core();
+    thread::Builder::new()
+        .name("tigadb-shipper".to_string())
+        .spawn(move || {
+            let from_seq = loop {
+                match transport.recv(POLL_INTERVAL)? {
+                    Some(msg) if msg.len() == 9 && msg[0] == HELLO => {
+                        break bytes_to_u64(&msg[1..]);
+                    }
+                    Some(msg) => return Err(bad_message(&msg)),
+                    None if wal.is_closed() => return Ok(()),
+                    None => {}
+                }
+            };
+            // A follower ahead of the leader has batches the leader never logged,
+            // as when the leader came back from an older backup.
+            if from_seq > wal.last_seq() + 1 {
+                let why = format!(
+                    "follower asks for seq {} but the leader is at seq {}",
+                    from_seq,
+                    wal.last_seq()
+                );
+                let mut msg = vec![REFUSED];
+                msg.extend_from_slice(why.as_bytes());
+                transport.send(&msg)?;
+                return Err(io::Error::new(ErrorKind::InvalidInput, why));
+            }
+            let mut batches = match wal.subscribe_batches(from_seq) {
+                Err(e) if e.kind() == ErrorKind::NotFound => {
+                    send_snapshot(&core, &wal, &mut transport)?
+                }
+                result => result?,
+            };
+            loop {
+                match batches.poll(POLL_INTERVAL)? {
+                    Some(batch) => {
+                        let mut msg = vec![BATCH];
+                        msg.append(&mut batch.encode());
+                        transport.send(&msg)?;
+                    }
+                    None if batches.is_done() => return Ok(()),
+                    None => {}
+                }
+            }
+        })
+        .expect("spawn shipper error")
+}
+
+// Sends the kv pairs of a new checkpoint, and returns the reader of the batches after it.
+fn send_snapshot<T: Transport>(
+    core: &Arc<Core>,
+    wal: &Arc<Wal>,
+    transport: &mut T,
+) -> io::Result<Batches> {
+    loop {
+        let mut export = core.export();
+        // Once subscribed the batches after the checkpoint are kept,
+        // but a later checkpoint may reclaim them before that.
+        let batches = match wal.subscribe_batches(export.seq() + 1) {
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            result => result?,
+        };
+        let mut msg = vec![SNAPSHOT];
+        msg.append(&mut u64_to_bytes(export.seq()));
+        msg.append(&mut u64_to_bytes(export.checkpoint()));
+        transport.send(&msg)?;
+        while let Some(pairs) = export.next_chunk()? {
+            let mut msg = vec![PAIRS];
+            msg.append(&mut pairs.encode());
+            transport.send(&msg)?;
+        }
+        transport.send(&[SNAPSHOT_END])?;
+        return Ok(batches);
+    }
+}
+
+// A read-only db which replays the batches a leader ships to it.
+// The batches are logged in its own WAL under the leader's sequence numbers and checkpoints,
+// so a restarted follower asks for the batches after its last one.
+// A snapshot of the leader takes the place of what the follower has once it is all read.
+pub struct Follower<T: Transport> {
+    db: DB,
+    transport: T,
+    // The snapshot being read.
+    restore: Option<Restore>,
+}
+
+impl<T: Transport> Follower<T> {
+    pub fn new(db: DB, mut transport: T) -> io::Result<Self> {
+        if !db.is_read_only() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "a follower needs a read-only db",
+            ));
+        }
+        let mut hello = vec![HELLO];
+        hello.append(&mut u64_to_bytes(db.last_seq() + 1));
+        transport.send(&hello)?;
+        Ok(Follower {
+            db,
+            transport,
+            restore: None,
+        })
+    }
+
+    // Applies the batches which come within timeout, and returns how many.
+    pub fn replay(&mut self, timeout: Duration) -> io::Result<usize> {
+        let deadline = Instant::now() + timeout;
+        let mut applied = 0;
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            let msg = match self.transport.recv(timeout)? {
+                Some(msg) => msg,
+                None => return Ok(applied),
+            };
+            match (msg.first(), &mut self.restore) {
+                (Some(&BATCH), None) => {
+                    self.db.replicate(BatchOps::decode(&msg[1..])?)?;
+                    applied += 1;
+                }
+                (Some(&SNAPSHOT), None) if msg.len() == 17 => {
+                    self.restore = Some(
+                        self.db
+                            .start_restore(bytes_to_u64(&msg[1..9]), bytes_to_u64(&msg[9..])),
+                    );
+                }
+                (Some(&PAIRS), Some(restore)) => {
+                    self.db
+                        .restore_chunk(restore, &BatchOps::decode(&msg[1..])?)?;
+                }
+                (Some(&SNAPSHOT_END), Some(_)) if msg.len() == 1 => {
+                    self.db.finish_restore(self.restore.take().unwrap())?;
+                }
+                (Some(&REFUSED), _) => {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidInput,
+                        String::from_utf8_lossy(&msg[1..]).into_owned(),
+                    ));
+                }
+                _ => return Err(bad_message(&msg)),
+            }
+        }
+    }
+
+    // Replays until the follower has the batch seq, or fails after timeout.
+    pub fn catch_up(&mut self, seq: u64, timeout: Duration) -> io::Result<()> {
+        let deadline = Instant::now() + timeout;
+        while self.db.last_seq() < seq {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(io::Error::new(
+                    ErrorKind::TimedOut,
+                    format!("follower is at seq {}, not at {}", self.db.last_seq(), seq),
+                ));
+            }
+            self.replay((deadline - now).min(POLL_INTERVAL))?;
+        }
+        Ok(())
+    }
+
+    // The sequence number of the last applied batch.
+    pub fn applied_seq(&self) -> u64 {
+        self.db.last_seq()
+    }
+
+    // The last leader checkpoint all of whose batches are applied.
+    // It is kept in the follower's WAL and index image, so it survives a restart.
+    pub fn applied_checkpoint(&self) -> u64 {
+        self.db.applied_checkpoint()
+    }
+
+    // Reads go to the db as on a leader.
+    pub fn db(&self) -> &DB {
+        &self.db
+    }
+
+    // Stops following and makes the db take writes. The sequence numbers go on
+    // from the last applied batch, so it can be the leader of other followers.
+    pub fn promote(self) -> DB {
+        let mut db = self.db;
+        db.set_read_only(false);
+        db
+    }
+}
+
+// Both ends of an in-process transport, for a follower in the same process.
+pub fn channel_pair() -> (ChannelTransport, ChannelTransport) {
+    let (tx1, rx1) = mpsc::channel();
+    let (tx2, rx2) = mpsc::channel();
+    (
+        ChannelTransport { tx: tx1, rx: rx2 },
+        ChannelTransport { tx: tx2, rx: rx1 },
+    )
+}
+
+pub struct ChannelTransport {
+    tx: Sender<Vec<u8>>,
+    rx: Receiver<Vec<u8>>,
+}
+
+impl Transport for ChannelTransport {
+    fn send(&mut self, msg: &[u8]) -> io::Result<()> {
+        self.tx
+            .send(msg.to_vec())
+            .map_err(|_| io::Error::new(ErrorKind::BrokenPipe, "the other end is gone"))
+    }
+
+    fn recv(&mut self, timeout: Duration) -> io::Result<Option<Vec<u8>>> {
+        match self.rx.recv_timeout(timeout) {
+            Ok(msg) => Ok(Some(msg)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => Err(io::Error::new(
+                ErrorKind::ConnectionAborted,
+                "the other end is gone",
+            )),
+        }
+    }
+}
+
+// A transport over a Unix socket, each message is sent as | len (4) | msg |.
+pub struct UnixTransport {
+    stream: UnixStream,
+    // What is read of the messages not returned yet.
+    buf: Vec<u8>,
+}
+
+impl UnixTransport {
+    pub fn new(stream: UnixStream) -> Self {
+        UnixTransport {
+            stream,
+            buf: Vec::new(),
+        }
+    }
+
+    // Takes the first message out of buf if it is whole.
+    fn take_message(&mut self) -> Option<Vec<u8>> {
+        if self.buf.len() < 4 {
+            return None;
+        }
+        let len = bytes_to_u32(&self.buf[..4]) as usize;
+        if self.buf.len() - 4 < len {
+            return None;
+        }
+        let msg = self.buf[4..4 + len].to_vec();
+        self.buf.drain(..4 + len);
+        Some(msg)
+    }
+}
+
+impl Transport for UnixTransport {
+    fn send(&mut self, msg: &[u8]) -> io::Result<()> {
+        if msg.len() > u32::MAX as usize {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "replication message is too large",
+            ));
+        }
+        self.stream.write_all(&u32_to_bytes(msg.len() as u32))?;
+        self.stream.write_all(msg)
+    }
+
+    fn recv(&mut self, timeout: Duration) -> io::Result<Option<Vec<u8>>> {
+        let deadline = Instant::now() + timeout;
+        let mut chunk = [0_u8; 64 << 10];
+        loop {
+            if let Some(msg) = self.take_message() {
+                return Ok(Some(msg));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            // A zero timeout would mean no timeout at all.
+            let wait = (deadline - now).max(Duration::from_millis(1));
+            self.stream.set_read_timeout(Some(wait))?;
+            match self.stream.read(&mut chunk) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        ErrorKind::ConnectionAborted,
+                        "the other end is gone",
+                    ))
+                }
+                Ok(n) => self.buf.extend_from_slice(&chunk[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                    return Ok(None)
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
@@ -4,22 +4,22 @@ use crate::util::{
 };
 use std::borrow::BorrowMut;
 use std::cmp::Ordering;
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::fs::File;
 use std::io;
 use std::io::Read;
-use std::u32;
-use std::u8;
 
-const MAX_KV_SIZE: usize = BLOCK_SIZE as usize * u8::max_value() as usize;
-const MAX_BLOCK_ID: BlockId = u32::max_value();
-const BLOCKS_MAX_COUNT: BlocksLen = u8::max_value();
+const MAX_KV_SIZE: usize = BLOCK_SIZE * BLOCKS_MAX_COUNT as usize;
+const MAX_BLOCK_ID: BlockId = u32::MAX;
+const BLOCKS_MAX_COUNT: BlocksLen = u8::MAX;
 
 const SIZE_OF_BLOCK_ID: usize = 4; // BlockID is u32.
 
 pub(crate) struct Storage {
     // kv_pos hashmap : map<KVpos, offset in meta_file>
     kv_pos_map: HashMap<KVpos, u64>,
+    // Offsets of the meta entries which were dropped, new entries are written over them.
+    free_meta_offsets: Vec<u64>,
     meta_file: File,
 
     min_blocks_id_can_use: BlockId,
@@ -33,53 +33,74 @@ pub(crate) struct Storage {
     // FREE means that blocks can be read or written directly.
     // USED means that kv data which in this blocks is written into other blocks BUT NOT COMMITTED yet.
     chink_blocks: BTreeMap<Blocks, BlocksState>,
+    // Blocks freed by a batch and the seq of that batch, in seq order.
+    // They stay USED until the batch is durable in the WAL: kv data may get to disk before it,
+    // and after a crash the index image and the replayed WAL still point at these blocks.
+    pending_free: VecDeque<(u64, Blocks)>,
 }
 
 impl Storage {
-    pub(crate) fn new(data_fpath: &'static str, meta_fpath: &'static str) -> Self {
-        let mut meta_file = open_or_create_file(meta_fpath);
-        let data_file = open_or_create_file(data_fpath);
+    pub(crate) fn new(data_fpath: &str, meta_fpath: &str) -> io::Result<Self> {
+        let mut meta_file = open_or_create_file(meta_fpath)?;
+        let data_file = open_or_create_file(data_fpath)?;
 
         let mut kv_pos_map = HashMap::new();
+        let mut free_meta_offsets = Vec::new();
         let chink_blocks = BTreeMap::new();
         let chink_blocks_start = BTreeMap::new();
         let chink_blocks_end = BTreeMap::new();
-        let mut min_blocks_id_can_use = 0;
+        let min_blocks_id_can_use;
 
         let meta_data_bytes: &mut Vec<u8> = &mut Vec::new();
-        meta_file
-            .read_to_end(meta_data_bytes)
-            .expect("read meta file error");
+        meta_file.read_to_end(meta_data_bytes)?;
 
-        if !meta_data_bytes.is_empty() {
+        if meta_data_bytes.len() >= SIZE_OF_BLOCK_ID {
             let (min_blocks_id_can_use_bytes, all_kv_pos_bytes) =
                 meta_data_bytes.split_at(SIZE_OF_BLOCK_ID);
             min_blocks_id_can_use = bytes_to_u32(min_blocks_id_can_use_bytes);
 
-            let mut iter = all_kv_pos_bytes.chunks(KV_POS_SIZE);
             let mut offset = SIZE_OF_BLOCK_ID as u64;
-            while let Some(kv_pos_bytes) = iter.next() {
+            let entries = all_kv_pos_bytes.chunks_exact(KV_POS_SIZE);
+            let torn = !entries.remainder().is_empty();
+            for kv_pos_bytes in entries {
                 let kv_pos = KVpos::decode(kv_pos_bytes.to_owned().borrow_mut());
-                kv_pos_map.insert(kv_pos, offset);
+                if kv_pos.blocks.count() > 0 {
+                    kv_pos_map.insert(kv_pos, offset);
+                } else {
+                    free_meta_offsets.push(offset);
+                }
                 offset += KV_POS_SIZE as u64;
             }
+            // A crash can leave the last entry half written, it is dropped
+            // so that the next entry is written where a whole one starts.
+            if torn {
+                meta_file.set_len(offset)?;
+            }
+        } else {
+            // A new meta file, or one whose header a crash cut short,
+            // the kv file then tells which blocks were ever taken.
+            min_blocks_id_can_use =
+                data_file.metadata()?.len().div_ceil(BLOCK_SIZE as u64) as BlockId;
+            write_at(&mut meta_file, &mut u32_to_bytes(min_blocks_id_can_use), 0)?;
         }
 
-        Self {
+        Ok(Self {
             kv_pos_map,
+            free_meta_offsets,
             meta_file,
             min_blocks_id_can_use,
             data_file,
             chink_blocks_start,
             chink_blocks_end,
             chink_blocks,
-        }
+            pending_free: VecDeque::new(),
+        })
     }
 
     pub(crate) fn read_kv(&self, kv_pos: KVpos) -> io::Result<Vec<u8>> {
         let offset =
-            (kv_pos.blocks.start_block_id * BLOCK_SIZE as u32 + kv_pos.value_pos as u32) as u64;
-        let len = (kv_pos.kv_size - kv_pos.value_pos) as usize;
+            kv_pos.blocks.start_block_id as u64 * BLOCK_SIZE as u64 + kv_pos.value_pos as u64;
+        let len = kv_pos.value_size() as usize;
         read_at(&self.data_file, offset, len)
     }
 
@@ -88,11 +109,11 @@ impl Storage {
         data: &mut Vec<u8>,
         old_blocks: Option<&mut Blocks>,
     ) -> io::Result<Blocks> {
-        let needed_blocks = data.len() / BLOCK_SIZE;
-        if needed_blocks > BLOCKS_MAX_COUNT as usize {
-            return Err(io::Error::new(io::ErrorKind::Other, "kv data is too large"));
+        if data.len() > MAX_KV_SIZE {
+            return Err(io::Error::other("kv data is too large"));
         }
-        if let Some(blocks) = self.alloc_blocks(needed_blocks as BlocksLen) {
+        let needed_blocks = data.len().div_ceil(BLOCK_SIZE).max(1);
+        if let Some(blocks) = self.alloc_blocks(needed_blocks as BlocksLen)? {
             if let Some(ob) = old_blocks {
                 self.insert_chink_blocks(ob, USED);
             }
@@ -103,23 +124,80 @@ impl Storage {
             )?;
             Ok(blocks)
         } else {
-            Err(io::Error::new(io::ErrorKind::Other, "disk use up"))
+            Err(io::Error::other("disk use up"))
         }
     }
 
-    pub(crate) fn write_meta(
-        &mut self,
-        meta_data: KVpos,
-        old_meta_data: Option<KVpos>,
-    ) -> io::Result<usize> {
-        let mut offset;
-        if let Some(off) = self.kv_pos_map.get(&meta_data) {
-            offset = *off;
-        } else {
-            offset = self.meta_file.metadata()?.len();
+    // Writes the meta entry of a kv, over a dropped entry if there is one.
+    pub(crate) fn write_meta(&mut self, meta_data: KVpos) -> io::Result<usize> {
+        let offset = match self.kv_pos_map.get(&meta_data) {
+            Some(off) => *off,
+            None => match self.free_meta_offsets.pop() {
+                Some(off) => off,
+                None => self.meta_file.metadata()?.len(),
+            },
+        };
+        let mut meta_data_bytes = meta_data.encode();
+        let n = write_at(&mut self.meta_file, meta_data_bytes.as_mut_slice(), offset)?;
+        self.kv_pos_map.insert(meta_data, offset);
+        Ok(n)
+    }
+
+    // Drops the meta entry of a kv which is overwritten or deleted.
+    // The entry is zeroed, a kv takes at least one block so it is never taken for a live one.
+    pub(crate) fn delete_meta(&mut self, meta_data: KVpos) -> io::Result<()> {
+        if let Some(offset) = self.kv_pos_map.remove(&meta_data) {
+            write_at(&mut self.meta_file, &mut [0_u8; KV_POS_SIZE], offset)?;
+            self.free_meta_offsets.push(offset);
         }
-        let mut meta_data_bytes = meta_dataencode(();
-        write_at(&mut self.meta_file, meta_data_bytes.as_mut_slice(), offset)
+        Ok(())
+    }
+
+    // Rebuilds the meta entries and the free blocks from the kvs the index holds,
+    // as they are only kept in memory and the meta file may hold entries of lost writes.
+    // Every block below min_blocks_id_can_use which no kv takes is free.
+    pub(crate) fn rebuild(&mut self, live: impl Iterator<Item = KVpos>) -> io::Result<()> {
+        let mut live: Vec<KVpos> = live.collect();
+        live.sort_by_key(|kv_pos| kv_pos.blocks.first_block_id());
+
+        let mut meta_data = u32_to_bytes(self.min_blocks_id_can_use);
+        self.kv_pos_map.clear();
+        self.free_meta_offsets.clear();
+        for kv_pos in live.iter() {
+            self.kv_pos_map.insert(*kv_pos, meta_data.len() as u64);
+            meta_data.append(&mut kv_pos.encode());
+        }
+        write_at(&mut self.meta_file, meta_data.as_mut_slice(), 0)?;
+        self.meta_file.set_len(meta_data.len() as u64)?;
+
+        self.chink_blocks.clear();
+        self.chink_blocks_start.clear();
+        self.chink_blocks_end.clear();
+        self.pending_free.clear();
+        let mut next: BlockId = 0;
+        for kv_pos in live.iter() {
+            self.free_range(next, kv_pos.blocks.first_block_id());
+            next = next.max(kv_pos.blocks.last_block_id());
+        }
+        self.free_range(next, self.min_blocks_id_can_use);
+        Ok(())
+    }
+
+    // Marks the blocks in [start, end) free, in runs no longer than a kv may take.
+    fn free_range(&mut self, mut start: BlockId, end: BlockId) {
+        while start < end {
+            let count = (end - start).min(BLOCKS_MAX_COUNT as BlockId);
+            self.insert_chink_blocks(&mut Blocks::new(start, count as BlocksLen), FREE);
+            start += count;
+        }
+    }
+
+    // Handles of the kv data and meta files, so they can be synced from another thread.
+    pub(crate) fn try_clone_files(&self) -> io::Result<Vec<File>> {
+        Ok(vec![
+            self.data_file.try_clone()?,
+            self.meta_file.try_clone()?,
+        ])
     }
 
     pub(crate) fn delete_kv(&mut self, old_blocks: &mut Blocks) {
@@ -135,41 +213,61 @@ impl Storage {
         write_at(&mut self.meta_file, min_blocks_id_bytes.as_mut_slice(), 0)
     }
 
-    fn alloc_blocks(&mut self, needed_blocks: BlocksLen) -> Option<Blocks> {
-        let chink_blocks = self.take_free_chink_blocks(needed_blocks);
-        if let Some(blocks) = chink_blocks {
-            Some(*blocks)
-        } else {
-            if needed_blocks as BlockId + self.min_blocks_id_can_use > MAX_BLOCK_ID {
-                return None;
+    fn alloc_blocks(&mut self, needed_blocks: BlocksLen) -> io::Result<Option<Blocks>> {
+        if let Some(blocks) = self.take_free_chink_blocks(needed_blocks).copied() {
+            self.remove_chink_blocks(blocks);
+            // Give back the blocks which are not needed.
+            if blocks.count() > needed_blocks {
+                let mut left = Blocks::new(
+                    blocks.first_block_id() + needed_blocks as BlockId,
+                    blocks.count() - needed_blocks,
+                );
+                self.insert_chink_blocks(&mut left, FREE);
             }
-            let new_blocks = Blocks::new(self.min_blocks_id_can_use, needed_blocks);
-            Some(new_blocks)
+            return Ok(Some(Blocks::new(blocks.first_block_id(), needed_blocks)));
         }
+        if needed_blocks as BlockId > MAX_BLOCK_ID - self.min_blocks_id_can_use {
+            return Ok(None);
+        }
+        let new_blocks = Blocks::new(self.min_blocks_id_can_use, needed_blocks);
+        self.update_min_blocks_id_can_use(needed_blocks as BlockId)?;
+        Ok(Some(new_blocks))
     }
 
     // When update or delete KV, disk will make chink blocks.
     fn insert_chink_blocks(&mut self, blocks: &mut Blocks, blocks_state: BlocksState) {
+        // last_block_id is the end of blocks (exclusive),
+        // so the blocks just before ends at first and the blocks just after starts at last.
         let first = blocks.first_block_id();
         let last = blocks.last_block_id();
-        if let Some(pblocks) = self.chink_blocks_end.remove(&(first - 1)) {
-            blocks.merge_to_head(&pblocks);
-            self.chink_blocks.remove(&pblocks);
+        if let Some(pblocks) = self.chink_blocks_end.get(&first).copied() {
+            if self.can_merge(blocks, &pblocks, blocks_state) {
+                self.remove_chink_blocks(pblocks);
+                blocks.merge_to_head(&pblocks);
+            }
         }
-        if let Some(pblocks) = self.chink_blocks_start.remove(&(last + 1)) {
-            blocks.merge_to_tail(&pblocks);
-            self.chink_blocks.remove(&pblocks);
+        if let Some(pblocks) = self.chink_blocks_start.get(&last).copied() {
+            if self.can_merge(blocks, &pblocks, blocks_state) {
+                self.remove_chink_blocks(pblocks);
+                blocks.merge_to_tail(&pblocks);
+            }
         }
-        self.chink_blocks.insert(blocks.clone(), blocks_state);
+        self.chink_blocks.insert(*blocks, blocks_state);
         self.chink_blocks_start
-            .insert(blocks.first_block_id(), blocks.clone());
+            .insert(blocks.first_block_id(), *blocks);
         self.chink_blocks_end
             .insert(blocks.last_block_id(), blocks.to_owned());
     }
 
+    // Only FREE blocks are merged, USED blocks are looked up as they are by release.
+    fn can_merge(&self, blocks: &Blocks, other: &Blocks, blocks_state: BlocksState) -> bool {
+        blocks_state == FREE
+            && self.chink_blocks.get(other) == Some(&FREE)
+            && blocks.count().checked_add(other.count()).is_some()
+    }
+
     fn take_free_chink_blocks(&self, needed_blocks: BlocksLen) -> Option<&Blocks> {
-        let mut it = self.chink_blocks.iter();
-        while let Some(chink_blocks) = it.next() {
+        for chink_blocks in self.chink_blocks.iter() {
             if chink_blocks.0.count() >= needed_blocks && *chink_blocks.1 == FREE {
                 return Some(chink_blocks.0);
             }
@@ -183,16 +281,27 @@ impl Storage {
         self.chink_blocks_end.remove(&blocks.last_block_id());
     }
 
-    pub(crate) fn set_blocks_state(&mut self, blocks: &Blocks, blocks_state: BlocksState) {
-        if let Some(state) = self.chink_blocks.get_mut(blocks) {
-            *state = blocks_state;
+    // The blocks of a kv which batch seq overwrote or deleted, they are freed by release.
+    pub(crate) fn free_after(&mut self, seq: u64, blocks: Blocks) {
+        self.pending_free.push_back((seq, blocks));
+    }
+
+    // Frees the blocks of the batches up to durable_seq, so they can be reused.
+    pub(crate) fn release(&mut self, durable_seq: u64) {
+        while let Some(&(seq, mut blocks)) = self.pending_free.front() {
+            if seq > durable_seq {
+                break;
+            }
+            self.pending_free.pop_front();
+            self.remove_chink_blocks(blocks);
+            self.insert_chink_blocks(&mut blocks, FREE);
         }
     }
 }
 
-const KV_POS_SIZE: usize = 9;
+pub(crate) const KV_POS_SIZE: usize = 9;
 
-#[derive(Eq, PartialEq, Hash)]
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Debug)]
 pub(crate) struct KVpos {
     blocks: Blocks,
     value_pos: u16,
@@ -200,6 +309,24 @@ pub(crate) struct KVpos {
 }
 
 impl KVpos {
+    // kv data is the key followed by the value, value_pos is where the value starts.
+    pub(crate) fn new(blocks: Blocks, value_pos: u16, kv_size: u16) -> Self {
+        Self {
+            blocks,
+            value_pos,
+            kv_size,
+        }
+    }
+
+    pub(crate) fn blocks(&self) -> Blocks {
+        self.blocks
+    }
+
+    // The value is stored after the key, so its size is what follows value_pos.
+    pub(crate) fn value_size(&self) -> u16 {
+        self.kv_size - self.value_pos
+    }
+
     pub(crate) fn encode(&self) -> Vec<u8> {
         let mut data = Vec::new();
         let mut blocks_bytes = self.blocks.encode();
@@ -233,7 +360,7 @@ type BlockId = u32;
 type BlocksLen = u8;
 
 // consecutive blocks
-#[derive(Clone, Copy, Eq, PartialOrd, PartialEq, Debug, Hash)]
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Hash)]
 pub(crate) struct Blocks {
     start_block_id: BlockId,
     block_count: BlocksLen,
@@ -290,11 +417,19 @@ impl Blocks {
 
 impl Ord for Blocks {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.block_count.cmp(&other.block_count)
+        self.block_count
+            .cmp(&other.block_count)
+            .then(self.start_block_id.cmp(&other.start_block_id))
+    }
+}
+
+impl PartialOrd for Blocks {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
     }
 }
 
 type BlocksState = u8;
 
-const FREE: BlocksState = 0;
-const USED: BlocksState = 1;
+pub(crate) const FREE: BlocksState = 0;
+pub(crate) const USED: BlocksState = 1;
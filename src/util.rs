@@ -1,24 +1,25 @@
-use std::fs::File;
+use std::fs::{File, OpenOptions};
+use std::io;
 use std::os::unix::fs::FileExt;
-use std::path::Path;
-use std::{io, u16, u32, u64, u8};
 
-pub(crate) fn open_or_create_file(fpath: &'static str) -> File {
-    if Path::new(fpath).exists() {
-        File::open(fpath).expect(format!("open file {} error", fpath).as_str())
-    } else {
-        File::create(fpath).expect(format!("create file {} error", fpath).as_str())
-    }
+pub(crate) fn open_or_create_file(fpath: &str) -> io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(fpath)
 }
 
 pub(crate) fn read_at(file: &File, offset: u64, len: usize) -> io::Result<Vec<u8>> {
-    let buf = &mut Vec::with_capacity(len);
-    file.read_at(buf, offset)?;
-    Ok(buf.to_vec())
+    let mut buf = vec![0_u8; len];
+    file.read_exact_at(&mut buf, offset)?;
+    Ok(buf)
 }
 
 pub(crate) fn write_at(file: &mut File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
-    file.write_at(buf, offset)
+    file.write_all_at(buf, offset)?;
+    Ok(buf.len())
 }
 
 pub(crate) fn bytes_to_u8(data: &[u8]) -> u8 {
@@ -64,3 +65,8 @@ pub(crate) fn u64_to_bytes(u: u64) -> Vec<u8> {
     let u8_8: [u8; 8] = u.to_be_bytes();
     u8_8.to_vec()
 }
+
+// Makes the creation, removal or renaming of files in dir durable.
+pub(crate) fn sync_dir(dir: &str) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
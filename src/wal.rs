@@ -1,248 +1,1398 @@
-
-/*
-  The wal is hold on  and the development pause.
-*/
-use crate::storage::KVpos;
+use crate::option::WalRecovery;
 use crate::util::{
-    bytes_to_u64, bytes_to_u8, open_or_create_file, read_at, u64_to_bytes, write_at,
+    bytes_to_u32, bytes_to_u64, bytes_to_u8, open_or_create_file, read_at, sync_dir, u32_to_bytes,
+    u64_to_bytes, u8_to_bytes, write_at,
 };
-use std::fs::File;
-use std::io;
-use std::io::{Read, Write};
-use std::thread;
+use parking_lot::{Condvar, Mutex, MutexGuard};
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read};
+use std::os::unix::fs::FileExt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const MANIFEST_FILE: &str = "MANIFEST";
+const SEGMENT_EXT: &str = ".log";
 
+// The WAL is a directory of numbered segments, 000001.log, 000002.log, ...
+// Only the newest one, the active segment named by the manifest, is appended to.
+// When it is full a new segment is started and the manifest is switched to it.
+// The older segments are sealed, and deleted once their batches are durable in Storage.
+//
+// Writers may append from many threads at once. They queue their batches, and one of them,
+// the leader, writes all queued batches as one append with one fsync, then wakes the others.
+// Each batch is logged under the next sequence number, which becomes its id.
 pub(crate) struct Wal {
-    writing_file: LogFile,
-    read_only_file: LogFile,
-    max_size_per_file: u64,
+    queue: Mutex<Queue>,
+    stats: Mutex<WalStats>,
+    // Signalled when a group is written.
+    written: Condvar,
+    log: Mutex<Log>,
+    durable: Mutex<Durable>,
+    // Signalled when more batches are durable, or the WAL is closed.
+    synced: Condvar,
+}
+
+// How far the WAL is synced. Change readers never go past it.
+#[derive(Default)]
+struct Durable {
+    seq: u64,
+    closed: bool,
+}
+
+// The batches waiting for a leader, and the results of the written ones.
+#[derive(Default)]
+struct Queue {
+    pending: Vec<Pending>,
+    next_ticket: u64,
+    // Whether a writer is writing a group now.
+    leading: bool,
+    // The sequence number of each written ticket, taken by its writer once woken.
+    results: HashMap<u64, io::Result<u64>>,
+}
+
+// How much the WAL has written since it was opened.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct WalStats {
+    // Batches logged.
+    pub batches: u64,
+    // Groups written, each is one append.
+    pub groups: u64,
+    // Groups which were synced.
+    pub syncs: u64,
+}
+
+struct Pending {
+    ticket: u64,
+    data: Vec<u8>,
+    checkpoint: u64,
+    fsync: bool,
 }
 
 impl Wal {
-    pub(crate) fn new(f1: &'static str, f2: &'static str, max_size_per_file: u64) -> Self {
-        let mut lf1 = LogFile::new(f1);
-        let lf2 = LogFile::new(f2);
-        let mut wf = lf1.0;
-        let mut rf = lf2.0;
-
-        let lf1_state = lf1.1;
-        let lf2_state = lf2.1;
-        if lf1_state == READ_ONLY && lf2_state == READ_ONLY {
-            wf.set_writing_state();
-        } else if lf2_state == WRITING {
-            std::mem::swap(&mut wf, &mut rf);
-        }
-
-        Wal {
-            writing_file: wf,
-            read_only_file: rf,
-            max_size_per_file,
-        }
+    pub(crate) fn new(
+        dir: &str,
+        max_size_per_file: u64,
+        recovery: WalRecovery,
+    ) -> io::Result<Self> {
+        let log = Log::open(dir, max_size_per_file, recovery)?;
+        // What a crash left in the page cache is made durable before anybody reads it.
+        log.active.file.sync_all()?;
+        let durable = Durable {
+            seq: log.last_seq,
+            closed: false,
+        };
+        Ok(Wal {
+            queue: Mutex::new(Queue::default()),
+            stats: Mutex::new(WalStats::default()),
+            written: Condvar::new(),
+            log: Mutex::new(log),
+            durable: Mutex::new(durable),
+            synced: Condvar::new(),
+        })
     }
 
-    pub(crate) fn recover(&mut self, last_ckpt: u64) -> io::Result<Vec<(Vec<KVpos>, Vec<u8>)>> {
+    // Decodes the batches which are not covered by the checkpoint last_ckpt yet, in order.
+    pub(crate) fn recover(&self, last_ckpt: u64) -> io::Result<Vec<BatchOps>> {
+        let log = self.log.lock();
         let mut result = Vec::new();
-        let wf_last_ckpt = self.writing_file.get_last_ckpt();
-        let rf_last_ckpt = self.read_only_file.get_last_ckpt();
-        if last_ckpt == wf_last_ckpt {
-            return Ok(result);
+        for segment in log.sealed.iter().chain(Some(&log.active)) {
+            if last_ckpt < segment.last_ckpt {
+                result.append(&mut segment.recover(last_ckpt)?);
+            }
         }
-        if last_ckpt < rf_last_ckpt {
-            let mut rf_result = self.read_only_file.recover(last_ckpt)?;
-            result.append(&mut rf_result);
+        Ok(result)
+    }
+
+    // Logs the batch and returns its sequence number, once the batch is written,
+    // and synced too if fsync is set. The id the batch was built with is not used.
+    pub(crate) fn append_wal(&self, batch_ops: &BatchOps, fsync: bool) -> io::Result<u64> {
+        let data = batch_ops.encode();
+        if data.len() > u32::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "wal record is too large",
+            ));
         }
-        let mut wf_result = self.writing_file.recover(last_ckpt)?;
-        result.append(&mut wf_result);
 
-        Ok(result)
+        let mut queue = self.queue.lock();
+        let ticket = queue.next_ticket;
+        queue.next_ticket += 1;
+        queue.pending.push(Pending {
+            ticket,
+            data,
+            checkpoint: batch_ops.checkpoint(),
+            fsync,
+        });
+        loop {
+            if let Some(result) = queue.results.remove(&ticket) {
+                return result;
+            }
+            if queue.leading {
+                self.written.wait(&mut queue);
+                continue;
+            }
+            // Nobody is writing, so this writer leads the next group, which holds its own batch.
+            queue.leading = true;
+            let mut group = std::mem::take(&mut queue.pending);
+            let results =
+                MutexGuard::unlocked(&mut queue, || self.log.lock().write_group(&mut group));
+            let fsync = group.iter().any(|pending| pending.fsync);
+            let mut stats = self.stats.lock();
+            stats.batches += group.len() as u64;
+            stats.groups += 1;
+            if fsync {
+                stats.syncs += 1;
+            }
+            drop(stats);
+            if fsync {
+                if let Some((_, Ok(seq))) = results.last() {
+                    self.set_durable(*seq);
+                }
+            }
+            queue.leading = false;
+            queue.results.extend(results);
+            self.written.notify_all();
+        }
+    }
+
+    // Syncs the active segment, the sealed ones were synced when they were sealed.
+    pub(crate) fn sync(&self) -> io::Result<()> {
+        let log = self.log.lock();
+        log.active.file.sync_all()?;
+        let seq = log.last_seq;
+        drop(log);
+        self.set_durable(seq);
+        Ok(())
+    }
+
+    // The sequence number of the last batch which is synced.
+    pub(crate) fn durable_seq(&self) -> u64 {
+        self.durable.lock().seq
+    }
+
+    fn set_durable(&self, seq: u64) {
+        let mut durable = self.durable.lock();
+        if seq > durable.seq {
+            durable.seq = seq;
+            self.synced.notify_all();
+        }
+    }
+
+    // Tells the change readers that no more batches come, they end once they read the durable ones.
+    pub(crate) fn close(&self) {
+        self.durable.lock().closed = true;
+        self.synced.notify_all();
+    }
+
+    // Reads the changes logged from the batch from_seq on, in order.
+    // It fails if the segment holding from_seq was deleted already.
+    pub(crate) fn subscribe(self: &Arc<Self>, from_seq: u64) -> io::Result<Changes> {
+        Ok(Changes {
+            batches: self.subscribe_batches(from_seq)?,
+            ready: VecDeque::new(),
+        })
+    }
+
+    // Reads the batches logged from from_seq on, in order, each under its sequence number.
+    pub(crate) fn subscribe_batches(self: &Arc<Self>, from_seq: u64) -> io::Result<Batches> {
+        let from_seq = from_seq.max(1);
+        let mut log = self.log.lock();
+        let first_seq = log.first_seq();
+        if from_seq < first_seq {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "changes from seq {} are reclaimed, the oldest kept is {}",
+                    from_seq, first_seq
+                ),
+            ));
+        }
+        let segment = log
+            .sealed
+            .iter()
+            .chain(Some(&log.active))
+            .rev()
+            .find(|segment| segment.first_seq <= from_seq)
+            .map_or(log.active.id, |segment| segment.id);
+        let next_seq = Arc::new(AtomicU64::new(from_seq));
+        let reader = log.next_reader;
+        log.next_reader += 1;
+        log.readers.insert(reader, next_seq.clone());
+        Ok(Batches {
+            wal: self.clone(),
+            dir: log.dir.clone(),
+            reader,
+            segment,
+            file: None,
+            offset: 0,
+            next_seq,
+        })
+    }
+
+    // Waits until the batch seq is durable or the WAL is closed, at most until deadline.
+    // Returns whether the batch is durable.
+    fn wait_durable(&self, seq: u64, deadline: Instant) -> bool {
+        let mut durable = self.durable.lock();
+        while durable.seq < seq && !durable.closed {
+            if self.synced.wait_until(&mut durable, deadline).timed_out() {
+                break;
+            }
+        }
+        durable.seq >= seq
+    }
+
+    #[inline]
+    pub(crate) fn is_closed(&self) -> bool {
+        self.durable.lock().closed
+    }
+
+    pub(crate) fn stats(&self) -> WalStats {
+        *self.stats.lock()
+    }
+
+    // The sequence number of the last logged batch.
+    pub(crate) fn last_seq(&self) -> u64 {
+        self.log.lock().last_seq
+    }
+
+    // Deletes the sealed segments whose batches are all covered by durable_ckpt,
+    // a checkpoint whose kv data and index image are synced already,
+    // and are all read by the live readers.
+    // Returns how many segments were deleted.
+    pub(crate) fn reclaim(&self, durable_ckpt: u64) -> io::Result<usize> {
+        self.log.lock().reclaim(durable_ckpt)
+    }
+
+    // Goes on with the batch seq in a new segment, as a follower restored to the batch
+    // before seq of its leader takes the leader's batches from there.
+    // The segments before are sealed, and reclaimed by the checkpoint of the restore.
+    pub(crate) fn restart_at(&self, seq: u64) -> io::Result<()> {
+        let mut log = self.log.lock();
+        if seq <= log.last_seq {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("wal at seq {} can't go back to seq {}", log.last_seq, seq),
+            ));
+        }
+        log.last_seq = seq - 1;
+        log.roll()?;
+        drop(log);
+        self.set_durable(seq - 1);
+        Ok(())
+    }
+
+    // The ids of the segments on disk, oldest first. The last one is the active segment.
+    #[cfg(test)]
+    pub(crate) fn segments(&self) -> Vec<u64> {
+        let log = self.log.lock();
+        log.sealed
+            .iter()
+            .chain(Some(&log.active))
+            .map(|segment| segment.id)
+            .collect()
+    }
+}
+
+// The segments of the WAL. Only the leader of a group appends to them.
+struct Log {
+    dir: String,
+    // The sealed segments, oldest first.
+    sealed: Vec<Segment>,
+    active: Segment,
+    max_size_per_file: u64,
+    last_seq: u64,
+    // The next seq of each live reader by its id, the segments from there on are not reclaimed.
+    readers: HashMap<u64, Arc<AtomicU64>>,
+    next_reader: u64,
+}
+
+impl Log {
+    fn open(dir: &str, max_size_per_file: u64, recovery: WalRecovery) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let ids = segment_ids(dir)?;
+        let manifest = match read_manifest(dir)? {
+            Some(manifest) => manifest,
+            None if ids.is_empty() => {
+                let manifest = Manifest {
+                    active_id: 1,
+                    first_seq: 1,
+                };
+                write_manifest(dir, manifest)?;
+                manifest
+            }
+            None => return Err(corrupt(&format!("wal dir {} has no manifest", dir))),
+        };
+
+        let mut sealed = Vec::new();
+        for id in ids {
+            if id > manifest.active_id {
+                // Started by a roll which crashed before the manifest was switched to it,
+                // so nothing was written into it.
+                fs::remove_file(segment_path(dir, id))?;
+            } else if id < manifest.active_id {
+                // A sealed segment was synced before the next one was started,
+                // a broken record in it is not a torn tail.
+                sealed.push(Segment::open(dir, id, WalRecovery::Fail)?);
+            }
+        }
+        let mut active = Segment::open(dir, manifest.active_id, recovery)?;
+        // The sealed segments may be gone already, the manifest keeps the numbering going.
+        let last_seq = active.last_seq.max(manifest.first_seq - 1);
+        if active.len == 0 {
+            active.first_seq = last_seq + 1;
+        }
+
+        Ok(Log {
+            dir: dir.to_string(),
+            sealed,
+            active,
+            max_size_per_file,
+            last_seq,
+            readers: HashMap::new(),
+            next_reader: 0,
+        })
     }
 
-    pub(crate) fn append_wal(
-        &mut self,
-        batch_op: BatchOp,
-        last_ckpt: u64,
-        fsync: bool,
-    ) -> io::Result<()> {
-        self.try_truncate_wal(last_ckpt);
-        let bytes_to_append = batch_op.encode();
-        let writing_file_len = self.writing_file.len()?;
-        if bytes_to_append.len() as u64 + writing_file_len > self.max_size_per_file {
-            self.switch_log_files();
+    // Writes the batches of a group into the active segment in order, as one append,
+    // and syncs it once if any of them asked for it.
+    // Returns the result for each ticket in the group.
+    fn write_group(&mut self, group: &mut [Pending]) -> Vec<(u64, io::Result<u64>)> {
+        let size: usize = group
+            .iter()
+            .map(|pending| SIZE_OF_FRAME_HEADER + pending.data.len())
+            .sum();
+        let mut records = Vec::with_capacity(size);
+        for (i, pending) in group.iter_mut().enumerate() {
+            // The id is the first field of the batch.
+            let seq = self.last_seq + 1 + i as u64;
+            pending.data[..8].copy_from_slice(&u64_to_bytes(seq));
+            frame_record(&pending.data, &mut records);
         }
-        self.writing_file
-            .append_file(bytes_to_append, last_ckpt, fsync)
+        let checkpoint = group
+            .iter()
+            .map(|pending| pending.checkpoint)
+            .max()
+            .unwrap_or(0);
+        let fsync = group.iter().any(|pending| pending.fsync);
+
+        let written = self.append(&mut records, checkpoint, fsync);
+        let results = group
+            .iter()
+            .enumerate()
+            .map(|(i, pending)| {
+                let result = match &written {
+                    Ok(()) => Ok(self.last_seq + 1 + i as u64),
+                    Err(e) => Err(io::Error::new(e.kind(), e.to_string())),
+                };
+                (pending.ticket, result)
+            })
+            .collect();
+        if written.is_ok() {
+            self.last_seq += group.len() as u64;
+            self.active.last_seq = self.last_seq;
+        }
+        results
+    }
+
+    fn append(&mut self, records: &mut [u8], last_ckpt: u64, fsync: bool) -> io::Result<()> {
+        let len = records.len() as u64;
+        if self.active.len > 0 && len + self.active.len > self.max_size_per_file {
+            self.roll()?;
+        }
+        self.active.append_file(records, last_ckpt, fsync)
+    }
+
+    // The sequence number of the oldest batch still kept.
+    fn first_seq(&self) -> u64 {
+        self.sealed.first().unwrap_or(&self.active).first_seq
     }
 
-    fn try_truncate_wal(&mut self, last_ckpt: u64) {
-        if self.read_only_file.last_ckpt <= last_ckpt {
-            // This place should spawn a thread to execute it.
-            self.read_only_file.truncate();
+    fn reclaim(&mut self, durable_ckpt: u64) -> io::Result<usize> {
+        let keep_seq = self
+            .readers
+            .values()
+            .map(|next_seq| next_seq.load(Ordering::SeqCst))
+            .min()
+            .unwrap_or(u64::MAX);
+        let mut deleted = 0;
+        while let Some(segment) = self.sealed.first() {
+            // The batches of a segment end where the next one starts.
+            let next_first_seq = self.sealed.get(1).unwrap_or(&self.active).first_seq;
+            if segment.last_ckpt > durable_ckpt || next_first_seq > keep_seq {
+                break;
+            }
+            fs::remove_file(segment_path(&self.dir, segment.id))?;
+            self.sealed.remove(0);
+            deleted += 1;
+        }
+        if deleted > 0 {
+            sync_dir(&self.dir)?;
         }
+        Ok(deleted)
     }
 
-    fn switch_log_files(&mut self) -> io::Result<usize> {
-        self.writing_file.set_readonly_state()?;
-        self.read_only_file.set_writing_state()?;
-        std::mem::swap(&mut self.writing_file, &mut self.read_only_file);
-        Ok(0)
+    // Seals the active segment and starts the next one.
+    // The new segment exists before the manifest names it, so a crash in between
+    // leaves an empty segment which is removed on open.
+    fn roll(&mut self) -> io::Result<()> {
+        self.active.file.sync_all()?;
+        let mut next = Segment::open(&self.dir, self.active.id + 1, WalRecovery::Fail)?;
+        next.first_seq = self.last_seq + 1;
+        sync_dir(&self.dir)?;
+        write_manifest(
+            &self.dir,
+            Manifest {
+                active_id: next.id,
+                first_seq: self.last_seq + 1,
+            },
+        )?;
+        let sealed = std::mem::replace(&mut self.active, next);
+        self.sealed.push(sealed);
+        Ok(())
     }
 }
 
-type Filestate = u8;
+#[inline]
+fn segment_path(dir: &str, id: u64) -> String {
+    format!("{}/{:06}{}", dir, id, SEGMENT_EXT)
+}
+
+// The ids of all segment files in dir, in order.
+fn segment_ids(dir: &str) -> io::Result<Vec<u64>> {
+    let mut ids = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        let id = name
+            .to_str()
+            .and_then(|name| name.strip_suffix(SEGMENT_EXT))
+            .filter(|stem| !stem.is_empty() && stem.bytes().all(|b| b.is_ascii_digit()))
+            .and_then(|stem| stem.parse().ok());
+        if let Some(id) = id {
+            ids.push(id);
+        }
+    }
+    ids.sort_unstable();
+    Ok(ids)
+}
 
-const READ_ONLY: Filestate = 0;
-const WRITING: Filestate = 1;
+// The manifest names the active segment and the sequence number its first batch gets:
+//
+// | active id (8) | first seq (8) | crc32c of both (4) |
+#[derive(Copy, Clone)]
+struct Manifest {
+    active_id: u64,
+    first_seq: u64,
+}
 
-const SIZE_OF_FILE_STATE: usize = 1; // Filestate type is u8.
-const SIZE_OF_CKPT: usize = 8; // checkpoint type is u64.
+const MANIFEST_SIZE: usize = 8 + 8 + 4;
 
-struct LogFile {
-    // The last last_ckpt in this log file.
+fn read_manifest(dir: &str) -> io::Result<Option<Manifest>> {
+    let data = match fs::read(format!("{}/{}", dir, MANIFEST_FILE)) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    if data.len() != MANIFEST_SIZE || crc32c::crc32c(&data[..16]) != bytes_to_u32(&data[16..]) {
+        return Err(corrupt(&format!("wal manifest in {} is broken", dir)));
+    }
+    let manifest = Manifest {
+        active_id: bytes_to_u64(&data[..8]),
+        first_seq: bytes_to_u64(&data[8..16]),
+    };
+    if manifest.first_seq == 0 {
+        return Err(corrupt(&format!("wal manifest in {} is broken", dir)));
+    }
+    Ok(Some(manifest))
+}
+
+// The manifest is written aside and renamed over the old one once synced.
+fn write_manifest(dir: &str, manifest: Manifest) -> io::Result<()> {
+    let fpath = format!("{}/{}", dir, MANIFEST_FILE);
+    let tmp_fpath = format!("{}.tmp", fpath);
+    let mut data = u64_to_bytes(manifest.active_id);
+    data.append(&mut u64_to_bytes(manifest.first_seq));
+    let crc = crc32c::crc32c(&data);
+    data.append(&mut u32_to_bytes(crc));
+    fs::write(&tmp_fpath, &data)?;
+    File::open(&tmp_fpath)?.sync_all()?;
+    fs::rename(&tmp_fpath, &fpath)?;
+    sync_dir(dir)
+}
+
+// A segment is a run of framed records:
+//
+// | len (4) | crc32c of len and payload (4) | payload (len) |
+//
+// A crash can leave the last record half written, its length or checksum won't match then.
+// The checksum covers the length too, so zeroed bytes at the end are not taken as records.
+const SIZE_OF_FRAME_HEADER: usize = 4 + 4;
+
+#[inline]
+fn record_crc(len_bytes: &[u8], payload: &[u8]) -> u32 {
+    crc32c::crc32c_append(crc32c::crc32c(len_bytes), payload)
+}
+
+// Appends data framed as one record to buf.
+fn frame_record(data: &[u8], buf: &mut Vec<u8>) {
+    let len_bytes = u32_to_bytes(data.len() as u32);
+    let crc = record_crc(&len_bytes, data);
+    buf.extend_from_slice(&len_bytes);
+    buf.extend_from_slice(&u32_to_bytes(crc));
+    buf.extend_from_slice(data);
+}
+
+struct Segment {
+    id: u64,
+    // The highest checkpoint of the batches in this segment.
     last_ckpt: u64,
+    // The ids of the first and the last batch in this segment.
+    // first_seq is the id the first batch gets if it is empty, last_seq is 0 then.
+    first_seq: u64,
+    last_seq: u64,
+    // The end of the last whole record, the next one is appended here.
+    len: u64,
     file: File,
 }
 
-impl LogFile {
-    fn new(fpath: &'static str) -> (Self, Filestate) {
-        let mut file = open_or_create_file(fpath);
-        let file_len = file.metadata().unwrap().len();
-        let mut state;
-        if file_len == 0 {
-            state = READ_ONLY;
-            write_at(&mut file, &mut [READ_ONLY], 0)
-                .expect(format!("init log_file {} with READONLY error", fpath).as_str());
-        } else {
-            let state_bytes = read_at(&mut file, 0, SIZE_OF_FILE_STATE)
-                .expect(format!("read state from log_file {} error", fpath).as_str());
-            state = bytes_to_u8(state_bytes.as_slice());
-        }
-        let mut last_ckpt;
-        if file_len < SIZE_OF_CKPT as u64 {
-            last_ckpt = 0;
-        } else {
-            let last_ckpt_bytes_offset = file_len - SIZE_OF_CKPT as u64;
-            let last_ckpt_bytes =
-                read_at(&file, last_ckpt_bytes_offset, SIZE_OF_CKPT).unwrap();
-            last_ckpt = bytes_to_u64(last_ckpt_bytes.as_slice());
-        }
-
-        (Self { last_ckpt, file }, state)
-    }
-
-    fn append_file(&mut self, mut data: Vec<u8>, last_ckpt: u64, fsync: bool) -> io::Result<()> {
-        data.append(&mut u64_to_bytes(last_ckpt));
-        self.file.write_all(data.as_mut_slice())?;
+impl Segment {
+    // Opens the segment id in dir, creating it if it is not there.
+    fn open(dir: &str, id: u64, recovery: WalRecovery) -> io::Result<Self> {
+        let fpath = segment_path(dir, id);
+        let file = open_or_create_file(&fpath)?;
+        let mut segment = Self {
+            id,
+            last_ckpt: 0,
+            first_seq: 0,
+            last_seq: 0,
+            len: file.metadata()?.len(),
+            file,
+        };
+        // A segment is read a record at a time, only its seqs and checkpoints are kept.
+        let mut records = Records::new(&segment.file, segment.len);
+        let (mut first_seq, mut last_seq, mut last_ckpt) = (0, 0, 0);
+        while let Some(record) = records.next_record()? {
+            last_seq = BatchOps::decode_id(&record)?;
+            if first_seq == 0 {
+                first_seq = last_seq;
+            }
+            last_ckpt = last_ckpt.max(BatchOps::decode_checkpoint(&record)?);
+        }
+        let end = records.end;
+        segment.first_seq = first_seq;
+        segment.last_seq = last_seq;
+        segment.last_ckpt = last_ckpt;
+        if end < segment.len {
+            match recovery {
+                WalRecovery::TruncateTail => {
+                    segment.file.set_len(end)?;
+                    segment.file.sync_all()?;
+                    segment.len = end;
+                }
+                WalRecovery::Fail => {
+                    return Err(corrupt(&format!(
+                        "wal segment {} has a broken record at offset {}",
+                        fpath, end
+                    )))
+                }
+            }
+        }
+        Ok(segment)
+    }
+
+    // Appends whole framed records.
+    fn append_file(&mut self, records: &mut [u8], last_ckpt: u64, fsync: bool) -> io::Result<()> {
+        write_at(&mut self.file, records, self.len)?;
         if fsync {
             self.file.sync_all()?;
         }
-        self.last_ckpt = last_ckpt;
+        self.len += records.len() as u64;
+        self.last_ckpt = self.last_ckpt.max(last_ckpt);
         Ok(())
     }
 
-    fn recover(&mut self, last_ckpt: u64) -> io::Result<Vec<(Vec<KVpos>, Vec<u8>)>> {
-        let result = Vec::new();
-        let mut file_data = self.read_all()?.split_off(SIZE_OF_FILE_STATE);
+    // Decodes the batches whose checkpoint is after last_ckpt.
+    // The broken tail is dropped when the segment is opened, so all records here are whole.
+    fn recover(&self, last_ckpt: u64) -> io::Result<Vec<BatchOps>> {
+        let mut records = Records::new(&self.file, self.len);
+        let mut result = Vec::new();
+        while let Some(record) = records.next_record()? {
+            if BatchOps::decode_checkpoint(&record)? > last_ckpt {
+                result.push(BatchOps::decode(&record)?);
+            }
+        }
+        Ok(result)
+    }
+}
 
-        let (old_kvpos_len_bytes, left) = file_data.split_at(8);
-        let (data_len_bytes, left2) = left.split_at(8);
-        let old_kvpos_len = bytes_to_u64(old_kvpos_len_bytes);
-        let data_len = bytes_to_u64(data_len_bytes);
+// How much of a segment is read at once when its records are read in order.
+const READ_BUF_SIZE: usize = 64 << 10;
 
-        Ok(result)
+// Reads a file from its own offset, so that the offset of the file is not moved.
+struct ReadFrom<'a> {
+    file: &'a File,
+    offset: u64,
+}
+
+impl Read for ReadFrom<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.file.read_at(buf, self.offset)?;
+        self.offset += n as u64;
+        Ok(n)
+    }
+}
+
+// The payloads of the records of a segment in order, read through a small buffer.
+// It stops at the first record which is cut short or fails its checksum.
+struct Records<'a> {
+    reader: BufReader<ReadFrom<'a>>,
+    // The length of the segment.
+    len: u64,
+    // The end of the last whole record read.
+    end: u64,
+}
+
+impl<'a> Records<'a> {
+    fn new(file: &'a File, len: u64) -> Self {
+        Records {
+            reader: BufReader::with_capacity(READ_BUF_SIZE, ReadFrom { file, offset: 0 }),
+            len,
+            end: 0,
+        }
+    }
+
+    fn next_record(&mut self) -> io::Result<Option<Vec<u8>>> {
+        if self.len - self.end < SIZE_OF_FRAME_HEADER as u64 {
+            return Ok(None);
+        }
+        let mut header = [0_u8; SIZE_OF_FRAME_HEADER];
+        self.reader.read_exact(&mut header)?;
+        let len = bytes_to_u32(&header[..4]) as u64;
+        let start = self.end + SIZE_OF_FRAME_HEADER as u64;
+        // A broken length is not taken for the size of a payload to read.
+        if self.len - start < len {
+            return Ok(None);
+        }
+        let mut payload = vec![0_u8; len as usize];
+        self.reader.read_exact(&mut payload)?;
+        if record_crc(&header[..4], &payload) != bytes_to_u32(&header[4..]) {
+            return Ok(None);
+        }
+        self.end = start + len;
+        Ok(Some(payload))
+    }
+}
+
+// Reads the record at offset, if it is whole and matches its checksum.
+fn read_record_at(file: &File, offset: u64, file_len: u64) -> io::Result<Option<Vec<u8>>> {
+    if file_len < offset + SIZE_OF_FRAME_HEADER as u64 {
+        return Ok(None);
+    }
+    let header = read_at(file, offset, SIZE_OF_FRAME_HEADER)?;
+    let len = bytes_to_u32(&header[..4]) as u64;
+    if file_len - offset - (SIZE_OF_FRAME_HEADER as u64) < len {
+        return Ok(None);
+    }
+    let payload = read_at(file, offset + SIZE_OF_FRAME_HEADER as u64, len as usize)?;
+    if record_crc(&header[..4], &payload) != bytes_to_u32(&header[4..]) {
+        return Ok(None);
+    }
+    Ok(Some(payload))
+}
+
+// One op of a logged batch, with the sequence number of the batch.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Change {
+    seq: u64,
+    ops: Ops,
+}
+
+impl Change {
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    pub fn op(&self) -> Operate {
+        self.ops.op
     }
 
-    fn read_all(&mut self) -> io::Result<Vec<u8>> {
-        let mut data = Vec::new();
-        self.file.read_to_end(&mut data)?;
-        Ok(data)
+    pub fn key(&self) -> &[u8] {
+        &self.ops.kv.key
     }
 
-    fn truncate(&mut self) {
-        self.file.set_len(SIZE_OF_FILE_STATE as u64);
-        self.last_ckpt = 0;
+    pub fn value(&self) -> &[u8] {
+        &self.ops.kv.value
     }
 
-    fn get_last_ckpt(&self) -> u64 {
-        self.last_ckpt
+    pub fn ops(&self) -> &Ops {
+        &self.ops
+    }
+}
+
+// Tails the WAL segments and yields each durable batch in order,
+// going on to the next segment when the one it reads is sealed.
+// While it lives, the segments from its next seq on are kept by checkpoints.
+pub(crate) struct Batches {
+    wal: Arc<Wal>,
+    dir: String,
+    // Its id among the readers of the log.
+    reader: u64,
+    // The segment being read, and where its next record starts.
+    segment: u64,
+    file: Option<File>,
+    offset: u64,
+    // The sequence number of the next batch to yield, shared with the log.
+    next_seq: Arc<AtomicU64>,
+}
+
+impl Batches {
+    // Returns the next batch, or None if none is durable within timeout.
+    pub(crate) fn poll(&mut self, timeout: Duration) -> io::Result<Option<BatchOps>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let durable = self.wal.durable_seq();
+            if self.next_seq() <= durable {
+                return self.read_next(durable).map(Some);
+            }
+            if !self.wal.wait_durable(self.next_seq(), deadline) {
+                return Ok(None);
+            }
+        }
+    }
+
+    // Whether the WAL is closed and every batch of it is read.
+    pub(crate) fn is_done(&self) -> bool {
+        self.wal.is_closed() && self.next_seq() > self.wal.durable_seq()
     }
 
-    fn set_writing_state(&mut self) -> io::Result<usize> {
-        write_at(&mut self.file, &mut [WRITING], 0)
+    // The sequence number of the next batch to yield.
+    #[inline]
+    pub(crate) fn next_seq(&self) -> u64 {
+        self.next_seq.load(Ordering::SeqCst)
     }
 
-    fn set_readonly_state(&mut self) -> io::Result<usize> {
-        write_at(&mut self.file, &mut [READ_ONLY], 0)
+    // Reads the batch next_seq, which is durable already.
+    fn read_next(&mut self, durable: u64) -> io::Result<BatchOps> {
+        loop {
+            if self.file.is_none() {
+                let fpath = segment_path(&self.dir, self.segment);
+                let file = File::open(&fpath).map_err(|e| {
+                    io::Error::new(
+                        e.kind(),
+                        format!("wal segment {} of seq {}: {}", fpath, self.next_seq(), e),
+                    )
+                })?;
+                self.file = Some(file);
+                self.offset = 0;
+            }
+            let file = self.file.as_ref().unwrap();
+            let file_len = file.metadata()?.len();
+            let payload = match read_record_at(file, self.offset, file_len)? {
+                Some(payload) => payload,
+                None => {
+                    // The durable batch is not here, so this segment was sealed before it.
+                    self.segment += 1;
+                    self.file = None;
+                    continue;
+                }
+            };
+            let seq = BatchOps::decode_id(&payload)?;
+            if seq > durable {
+                return Err(corrupt(&format!(
+                    "wal has seq {} where durable seq {} should be",
+                    seq,
+                    self.next_seq()
+                )));
+            }
+            self.offset += (SIZE_OF_FRAME_HEADER + payload.len()) as u64;
+            if seq == self.next_seq() {
+                self.next_seq.store(seq + 1, Ordering::SeqCst);
+                return BatchOps::decode(&payload);
+            }
+        }
     }
+}
 
-    fn len(&self) -> io::Result<u64> {
-        let metadata = self.file.metadata()?;
-        Ok(metadata.len())
+impl Drop for Batches {
+    fn drop(&mut self) {
+        self.wal.log.lock().readers.remove(&self.reader);
     }
 }
 
-pub(crate) struct AllOps(Vec<BatchOps>);
+// Tails the WAL segments and yields the op of each durable batch in order.
+// The iterator waits for more batches, and ends once the WAL is closed and all are read.
+pub struct Changes {
+    batches: Batches,
+    ready: VecDeque<Change>,
+}
+
+impl Changes {
+    // Returns the next change, or None if none is durable within timeout.
+    pub fn poll(&mut self, timeout: Duration) -> io::Result<Option<Change>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(change) = self.ready.pop_front() {
+                return Ok(Some(change));
+            }
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            match self.batches.poll(timeout)? {
+                Some(batch) => {
+                    let seq = batch.id;
+                    self.ready
+                        .extend(batch.ops.into_iter().map(|ops| Change { seq, ops }));
+                }
+                None => return Ok(None),
+            }
+        }
+    }
 
-pub(crate) struct BatchOps{
+    // The sequence number of the next batch to read.
+    pub fn next_seq(&self) -> u64 {
+        self.batches.next_seq()
+    }
+}
+
+impl Iterator for Changes {
+    type Item = io::Result<Change>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.poll(Duration::from_secs(1)) {
+                Ok(Some(change)) => return Some(Ok(change)),
+                Err(e) => return Some(Err(e)),
+                Ok(None) => {
+                    if self.batches.is_done() {
+                        return None;
+                    }
+                }
+            }
+        }
+    }
+}
+
+// A batch of ops which is written into the log as one record.
+//
+// | id (8) | undo (8) | checkpoint (8) | ops count (4) | ops ... |
+//
+// Each op is length-prefixed, so keys and values may hold any bytes:
+//
+// | op (1) | key len (4) | key | value len (4) | value |
+//
+// A DELETE op has an empty value.
+//
+// checkpoint is the first checkpoint which covers the batch,
+// so the batches with a checkpoint after the last durable one are replayed on recovery.
+pub(crate) struct BatchOps {
     id: u64,
     ops: Vec<Ops>,
     undo: u64,
     checkpoint: u64,
 }
 
-impl BatchOps{
-    pub(crate) fn encode(&self) -> Vec<u8>{
-        let mut data = Vec::new();
-        let mut iter = self.ops.iter();
-        while let Some(ops) = iter.next() {
-            let ops_bytes = ops.encode();
-            data.append(bop_bytes);
+const SIZE_OF_BATCH_HEADER: usize = 8 + 8 + 8 + 4;
+
+impl BatchOps {
+    pub(crate) fn new(id: u64, undo: u64, checkpoint: u64) -> Self {
+        Self {
+            id,
+            ops: Vec::new(),
+            undo,
+            checkpoint,
         }
-        data
     }
 
-    pub(crate) fn decode(data: Vec<u8>) -> Self{
+    pub(crate) fn insert(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.ops.push(Ops {
+            op: INSERT,
+            kv: KVpair { key, value },
+        });
+    }
+
+    pub(crate) fn delete(&mut self, key: Vec<u8>) {
+        self.ops.push(Ops {
+            op: DELETE,
+            kv: KVpair {
+                key,
+                value: Vec::new(),
+            },
+        });
+    }
+
+    pub(crate) fn id(&self) -> u64 {
+        self.id
+    }
+
+    #[cfg(test)]
+    pub(crate) fn undo(&self) -> u64 {
+        self.undo
+    }
+
+    pub(crate) fn checkpoint(&self) -> u64 {
+        self.checkpoint
+    }
+
+    pub(crate) fn ops(&self) -> &[Ops] {
+        &self.ops
+    }
+
+    // Puts the batch under the checkpoint it is logged in.
+    #[inline]
+    pub(crate) fn set_checkpoint(&mut self, checkpoint: u64) {
+        self.checkpoint = checkpoint;
+    }
+
+    // Reads only the id of an encoded batch.
+    fn decode_id(mut data: &[u8]) -> io::Result<u64> {
+        Ok(bytes_to_u64(take(&mut data, 8)?))
+    }
+
+    // Reads only the checkpoint of an encoded batch.
+    fn decode_checkpoint(mut data: &[u8]) -> io::Result<u64> {
+        let data = &mut data;
+        take(data, 8 + 8)?;
+        Ok(bytes_to_u64(take(data, 8)?))
+    }
+
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let size: usize = self.ops.iter().map(|ops| ops.encoded_len()).sum();
+        let mut data = Vec::with_capacity(SIZE_OF_BATCH_HEADER + size);
+        data.append(&mut u64_to_bytes(self.id));
+        data.append(&mut u64_to_bytes(self.undo));
+        data.append(&mut u64_to_bytes(self.checkpoint));
+        data.append(&mut u32_to_bytes(self.ops.len() as u32));
+        for ops in self.ops.iter() {
+            ops.encode_into(&mut data);
+        }
+        data
+    }
 
+    pub(crate) fn decode(mut data: &[u8]) -> io::Result<Self> {
+        let data = &mut data;
+        let id = bytes_to_u64(take(data, 8)?);
+        let undo = bytes_to_u64(take(data, 8)?);
+        let checkpoint = bytes_to_u64(take(data, 8)?);
+        let count = bytes_to_u32(take(data, 4)?) as usize;
+        // Every op takes at least its header, a bad count must not allocate a lot.
+        let mut ops = Vec::with_capacity(count.min(data.len() / SIZE_OF_OPS_HEADER));
+        for _ in 0..count {
+            ops.push(Ops::decode(data)?);
+        }
+        if !data.is_empty() {
+            return Err(corrupt("trailing bytes after batch ops"));
+        }
+        Ok(Self {
+            id,
+            ops,
+            undo,
+            checkpoint,
+        })
     }
 }
 
-type Operate = u8;
-const INSERT: Operate = 0;
-const DELETE: Operate = 1;
+pub type Operate = u8;
+pub const INSERT: Operate = 0;
+pub const DELETE: Operate = 1;
 
-pub(crate) struct Ops{
+const SIZE_OF_OPS_HEADER: usize = 1 + 4 + 4;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Ops {
     op: Operate,
     kv: KVpair,
 }
 
-impl Ops{
-    pub(crate) fn encode(&self) -> Vec<u8>{
+impl Ops {
+    pub fn op(&self) -> Operate {
+        self.op
+    }
+
+    pub fn key(&self) -> &[u8] {
+        &self.kv.key
+    }
 
+    pub fn value(&self) -> &[u8] {
+        &self.kv.value
     }
 
-    pub(crate) fn decode(data: Vec<u8>) -> Self{
+    #[cfg(test)]
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(self.encoded_len());
+        self.encode_into(&mut data);
+        data
+    }
 
+    // Decodes one op from the front of data and moves data past it.
+    pub(crate) fn decode(data: &mut &[u8]) -> io::Result<Self> {
+        let op = bytes_to_u8(take(data, 1)?);
+        if op != INSERT && op != DELETE {
+            return Err(corrupt("unknown op type"));
+        }
+        let key_len = bytes_to_u32(take(data, 4)?) as usize;
+        let key = take(data, key_len)?.to_vec();
+        let value_len = bytes_to_u32(take(data, 4)?) as usize;
+        let value = take(data, value_len)?.to_vec();
+        Ok(Self {
+            op,
+            kv: KVpair { key, value },
+        })
+    }
+
+    #[inline]
+    fn encoded_len(&self) -> usize {
+        SIZE_OF_OPS_HEADER + self.kv.key.len() + self.kv.value.len()
+    }
+
+    fn encode_into(&self, data: &mut Vec<u8>) {
+        data.append(&mut u8_to_bytes(self.op));
+        data.append(&mut u32_to_bytes(self.kv.key.len() as u32));
+        data.extend_from_slice(&self.kv.key);
+        data.append(&mut u32_to_bytes(self.kv.value.len() as u32));
+        data.extend_from_slice(&self.kv.value);
     }
 }
 
-pub struct KVpair{
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct KVpair {
     key: Vec<u8>,
-    value: Vec<u8>
-}
-
-/*
-   fn to_bytes(multi_old_kvpos: Vec<KVpos>, mut data: Vec<u8>) -> Vec<u8> {
-        let mut old_kvpos_bytes = Vec::new();
-        let mut iter = multi_old_kvpos.iter();
-        while let Some(old_kvpos) = iter.next() {
-            let mut bytes = old_kvpos.to_bytes();
-            old_kvpos_bytes.append(&mut bytes);
-        }
-        let old_kvpos_bytes_len = old_kvpos_bytes.len() as u64;
-        let data_len = data.len() as u64;
-        let mut bytes_to_append = Vec::new();
-        bytes_to_append.append(&mut u64_to_bytes(old_kvpos_bytes_len));
-        bytes_to_append.append(&mut u64_to_bytes(data_len));
-        bytes_to_append.append(&mut old_kvpos_bytes);
-        bytes_to_append.append(&mut data);
-        bytes_to_append
-    }
-*/
\ No newline at end of file
+    value: Vec<u8>,
+}
+
+#[inline]
+fn corrupt(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[inline]
+fn take<'a>(data: &mut &'a [u8], n: usize) -> io::Result<&'a [u8]> {
+    if data.len() < n {
+        return Err(corrupt("wal record is truncated"));
+    }
+    let (head, tail) = data.split_at(n);
+    *data = tail;
+    Ok(head)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::OpenOptions;
+    use std::thread;
+
+    // The dir of a fresh wal under target/test-wal/{name}.
+    fn wal_dir(name: &str) -> String {
+        let dir = format!("target/test-wal/{}", name);
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn segment(dir: &str, id: u64) -> String {
+        format!("{}/{:06}.log", dir, id)
+    }
+
+    fn batch(id: u64, n: usize) -> BatchOps {
+        let mut batch = BatchOps::new(id, 0, id);
+        for i in 0..n {
+            batch.insert(format!("key-{}-{}", id, i).into_bytes(), vec![id as u8; i]);
+        }
+        batch
+    }
+
+    fn file_len(fpath: &str) -> u64 {
+        fs::metadata(fpath).unwrap().len()
+    }
+
+    fn round_trip(batch: &BatchOps) -> BatchOps {
+        let back = BatchOps::decode(&batch.encode()).unwrap();
+        assert_eq!(
+            (back.id(), back.undo(), back.checkpoint()),
+            (batch.id(), batch.undo(), batch.checkpoint())
+        );
+        assert_eq!(back.ops(), batch.ops());
+        back
+    }
+
+    #[test]
+    fn empty_batch_and_empty_keys() {
+        let batch = BatchOps::new(1, 0, 0);
+        assert!(round_trip(&batch).ops().is_empty());
+
+        let mut batch = BatchOps::new(u64::MAX, u64::MAX - 1, 7);
+        batch.insert(vec![], vec![]);
+        batch.insert(vec![], b"value of the empty key".to_vec());
+        batch.delete(vec![]);
+        let back = round_trip(&batch);
+        let ops: Vec<(u8, &[u8], &[u8])> = back
+            .ops()
+            .iter()
+            .map(|ops| (ops.op(), ops.key(), ops.value()))
+            .collect();
+        assert_eq!(
+            ops,
+            vec![
+                (INSERT, &b""[..], &b""[..]),
+                (INSERT, &b""[..], &b"value of the empty key"[..]),
+                (DELETE, &b""[..], &b""[..]),
+            ]
+        );
+    }
+
+    #[test]
+    fn huge_and_binary_keys() {
+        let mut batch = BatchOps::new(42, 1024, 3);
+        // Larger than any u16 length.
+        let huge_key: Vec<u8> = (0..200_000_u32).map(|i| (i % 251) as u8).collect();
+        let huge_value = vec![0xab; 1 << 20];
+        batch.insert(huge_key.clone(), huge_value.clone());
+        // Every byte value, including ones which look like headers.
+        let binary: Vec<u8> = (0..=255_u8).collect();
+        batch.insert(binary.clone(), binary.iter().rev().copied().collect());
+        batch.insert(vec![0; 9], vec![1, 0, 0, 0, 0]);
+        batch.delete(binary.clone());
+        batch.delete(huge_key.clone());
+
+        let back = round_trip(&batch);
+        assert_eq!(back.ops().len(), 5);
+        assert_eq!(back.ops()[0].key(), huge_key.as_slice());
+        assert_eq!(back.ops()[0].value(), huge_value.as_slice());
+        assert_eq!(back.ops()[1].key(), binary.as_slice());
+        assert_eq!(back.ops()[4].op(), DELETE);
+    }
+
+    #[test]
+    fn broken_records_fail_to_decode() {
+        let mut batch = BatchOps::new(9, 0, 1);
+        batch.insert(b"key".to_vec(), b"value".to_vec());
+        batch.delete(b"other".to_vec());
+        let data = batch.encode();
+
+        for len in 0..data.len() {
+            assert!(BatchOps::decode(&data[..len]).is_err(), "len {}", len);
+        }
+        let mut trailing = data.clone();
+        trailing.push(0);
+        assert!(BatchOps::decode(&trailing).is_err());
+
+        // The first op starts right after the 28 bytes of header.
+        let mut bad_op = data.clone();
+        bad_op[28] = 7;
+        assert!(BatchOps::decode(&bad_op).is_err());
+
+        // A huge ops count does not allocate before failing.
+        let mut bad_count = data;
+        bad_count[24..28].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(BatchOps::decode(&bad_count).is_err());
+
+        let mut ops = &batch.ops()[0].encode()[..];
+        assert_eq!(Ops::decode(&mut ops).unwrap(), batch.ops()[0]);
+        assert!(ops.is_empty());
+    }
+
+    // Each record is its 8 bytes of header and the batch.
+    #[test]
+    fn torn_tail_is_truncated_or_fails() {
+        let dir = wal_dir("torn_tail");
+        let f1 = &segment(&dir, 1);
+        let batches: Vec<BatchOps> = (1..=3).map(|id| batch(id, 10)).collect();
+        let mut ends = vec![0_u64];
+        let wal = Wal::new(&dir, 1 << 20, WalRecovery::Fail).unwrap();
+        for b in batches.iter() {
+            wal.append_wal(b, true).unwrap();
+            ends.push(ends.last().unwrap() + 8 + b.encode().len() as u64);
+        }
+        drop(wal);
+        assert_eq!(file_len(f1), ends[3]);
+        // Whole records open fine with either policy.
+        Wal::new(&dir, 1 << 20, WalRecovery::Fail).unwrap();
+
+        // A record cut short by a crash.
+        let file = OpenOptions::new().read(true).write(true).open(f1).unwrap();
+        file.set_len(ends[3] - 3).unwrap();
+        assert!(Wal::new(&dir, 1 << 20, WalRecovery::Fail).is_err());
+        assert_eq!(file_len(f1), ends[3] - 3);
+        let wal = Wal::new(&dir, 1 << 20, WalRecovery::TruncateTail).unwrap();
+        assert_eq!(file_len(f1), ends[2]);
+
+        // New records go right after the last whole one.
+        wal.append_wal(&batches[2], true).unwrap();
+        drop(wal);
+        assert_eq!(file_len(f1), ends[3]);
+
+        // A flipped bit in the payload of the second record drops it and all after it.
+        let mut byte = [0_u8];
+        file.read_exact_at(&mut byte, ends[1] + 20).unwrap();
+        file.write_all_at(&[byte[0] ^ 0x10], ends[1] + 20).unwrap();
+        assert!(Wal::new(&dir, 1 << 20, WalRecovery::Fail).is_err());
+        Wal::new(&dir, 1 << 20, WalRecovery::TruncateTail).unwrap();
+        assert_eq!(file_len(f1), ends[1]);
+
+        // Zeroed space after the records, as left by a preallocating filesystem.
+        file.write_all_at(&[0; 64], ends[1]).unwrap();
+        assert!(Wal::new(&dir, 1 << 20, WalRecovery::Fail).is_err());
+        Wal::new(&dir, 1 << 20, WalRecovery::TruncateTail).unwrap();
+        assert_eq!(file_len(f1), ends[1]);
+
+        // A length running past the end of the file.
+        file.write_all_at(&[0xff, 0xff, 0xff, 0x00, 1, 2, 3, 4, 5], ends[1])
+            .unwrap();
+        Wal::new(&dir, 1 << 20, WalRecovery::TruncateTail).unwrap();
+        assert_eq!(file_len(f1), ends[1]);
+    }
+
+    fn ids(batches: &[BatchOps]) -> Vec<u64> {
+        batches.iter().map(|b| b.id()).collect()
+    }
+
+    // batch(id) is logged at checkpoint id, so checkpoint c covers the batches up to c.
+    #[test]
+    fn segments_roll_and_are_reclaimed_after_checkpoint() {
+        let dir = wal_dir("segments_roll");
+        let wal = Wal::new(&dir, 2048, WalRecovery::Fail).unwrap();
+        for id in 1..=20 {
+            wal.append_wal(&batch(id, 10), true).unwrap();
+        }
+        let segments = wal.segments();
+        assert!(segments.len() > 2, "{:?}", segments);
+        assert_eq!(segments, (1..=segments.len() as u64).collect::<Vec<_>>());
+        for id in segments.iter() {
+            assert!(file_len(&segment(&dir, *id)) <= 2048);
+        }
+        drop(wal);
+
+        // All segments are read back in order, from any checkpoint.
+        let wal = Wal::new(&dir, 2048, WalRecovery::Fail).unwrap();
+        assert_eq!(wal.segments(), segments);
+        assert_eq!(ids(&wal.recover(0).unwrap()), (1..=20).collect::<Vec<_>>());
+        assert_eq!(
+            ids(&wal.recover(13).unwrap()),
+            (14..=20).collect::<Vec<_>>()
+        );
+        assert!(wal.recover(20).unwrap().is_empty());
+
+        // Nothing is durable yet, nothing is deleted.
+        assert_eq!(wal.reclaim(0).unwrap(), 0);
+        assert_eq!(wal.segments(), segments);
+
+        // Only the sealed segments wholly covered by the checkpoint go.
+        let per_segment = 2048 / (8 + batch(1, 10).encode().len() as u64);
+        assert_eq!(wal.reclaim(per_segment).unwrap(), 1);
+        assert_eq!(wal.segments(), segments[1..].to_vec());
+        assert!(!std::path::Path::new(&segment(&dir, 1)).exists());
+        assert_eq!(
+            ids(&wal.recover(per_segment).unwrap()),
+            (per_segment + 1..=20).collect::<Vec<_>>()
+        );
+
+        // The active segment is kept even when all of it is covered.
+        wal.reclaim(20).unwrap();
+        assert_eq!(wal.segments(), vec![*segments.last().unwrap()]);
+        assert!(wal.recover(20).unwrap().is_empty());
+        drop(wal);
+        let wal = Wal::new(&dir, 2048, WalRecovery::Fail).unwrap();
+        assert_eq!(wal.segments(), vec![*segments.last().unwrap()]);
+    }
+
+    #[test]
+    fn segments_after_a_crash() {
+        let dir = wal_dir("segments_crash");
+        let wal = Wal::new(&dir, 1024, WalRecovery::TruncateTail).unwrap();
+        for id in 1..=10 {
+            wal.append_wal(&batch(id, 8), true).unwrap();
+        }
+        let segments = wal.segments();
+        let active = *segments.last().unwrap();
+        drop(wal);
+
+        // A roll which crashed before switching the manifest leaves an empty segment.
+        fs::write(segment(&dir, active + 1), b"").unwrap();
+        let wal = Wal::new(&dir, 1024, WalRecovery::TruncateTail).unwrap();
+        assert_eq!(wal.segments(), segments);
+        assert!(!std::path::Path::new(&segment(&dir, active + 1)).exists());
+        assert_eq!(ids(&wal.recover(0).unwrap()), (1..=10).collect::<Vec<_>>());
+        drop(wal);
+
+        // A sealed segment is never cut short, a broken record in it fails the open.
+        let sealed = segment(&dir, segments[0]);
+        let len = file_len(&sealed);
+        OpenOptions::new()
+            .write(true)
+            .open(&sealed)
+            .unwrap()
+            .set_len(len - 1)
+            .unwrap();
+        assert!(Wal::new(&dir, 1024, WalRecovery::TruncateTail).is_err());
+        assert_eq!(file_len(&sealed), len - 1);
+
+        // Segments without a manifest are not taken as a fresh wal.
+        let dir = wal_dir("segments_no_manifest");
+        Wal::new(&dir, 1024, WalRecovery::Fail).unwrap();
+        fs::remove_file(format!("{}/MANIFEST", dir)).unwrap();
+        assert!(Wal::new(&dir, 1024, WalRecovery::Fail).is_err());
+    }
+
+    // Writers on many threads share the appends and fsyncs, and each gets its own sequence number.
+    #[test]
+    fn group_commit_from_many_writers() {
+        let dir = wal_dir("group_commit");
+        let wal = Arc::new(Wal::new(&dir, 64 << 10, WalRecovery::Fail).unwrap());
+        let handles: Vec<_> = (0..8)
+            .map(|t| {
+                let wal = wal.clone();
+                thread::spawn(move || {
+                    (0..250)
+                        .map(|i| {
+                            let mut batch = BatchOps::new(0, 0, 1);
+                            batch.insert(format!("{}/{}", t, i).into_bytes(), vec![t; i % 50]);
+                            (wal.append_wal(&batch, true).unwrap(), i)
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        let mut keys = vec![Vec::new(); 2001];
+        for (t, handle) in handles.into_iter().enumerate() {
+            let seqs = handle.join().unwrap();
+            // A writer waits for its batch, so its own batches are logged in order.
+            assert!(seqs.windows(2).all(|w| w[0].0 < w[1].0));
+            for (seq, i) in seqs {
+                assert!(keys[seq as usize].is_empty(), "seq {} given twice", seq);
+                keys[seq as usize] = format!("{}/{}", t, i).into_bytes();
+            }
+        }
+        assert!(keys[1..].iter().all(|key| !key.is_empty()));
+
+        let stats = wal.stats();
+        assert_eq!(stats.batches, 2000);
+        assert_eq!(stats.syncs, stats.groups);
+        assert!(stats.groups < stats.batches, "{:?}", stats);
+        assert_eq!(wal.last_seq(), 2000);
+        assert!(wal.segments().len() > 1);
+        drop(wal);
+
+        // The log holds the batches in sequence order, under their sequence numbers.
+        let wal = Wal::new(&dir, 64 << 10, WalRecovery::Fail).unwrap();
+        let batches = wal.recover(0).unwrap();
+        assert_eq!(ids(&batches), (1..=2000).collect::<Vec<_>>());
+        for batch in batches.iter() {
+            assert_eq!(batch.ops()[0].key(), keys[batch.id() as usize].as_slice());
+        }
+    }
+
+    #[test]
+    fn sequence_survives_reclaimed_segments() {
+        let dir = wal_dir("sequence_survives");
+        let wal = Wal::new(&dir, 1024, WalRecovery::Fail).unwrap();
+        assert_eq!(wal.last_seq(), 0);
+        for id in 1..=30 {
+            assert_eq!(wal.append_wal(&batch(id, 5), false).unwrap(), id);
+        }
+        // Every batch is covered, so only the active segment is left.
+        wal.reclaim(30).unwrap();
+        assert_eq!(wal.segments().len(), 1);
+        drop(wal);
+
+        let wal = Wal::new(&dir, 1024, WalRecovery::Fail).unwrap();
+        assert_eq!(wal.last_seq(), 30);
+        assert_eq!(wal.append_wal(&batch(31, 5), false).unwrap(), 31);
+    }
+}
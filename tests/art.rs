@@ -0,0 +1,511 @@
+use std::collections::BTreeMap;
+use tigadb::art::ArtTree;
+
+mod common;
+use common::Rng;
+
+impl Rng {
+    // Short keys over a small alphabet collide and share paths a lot,
+    // long keys over all bytes spread into Node48 and Node256.
+    fn key(&mut self) -> Vec<u8> {
+        let len = self.below(8) as usize;
+        let narrow = self.below(2) == 0;
+        (0..len)
+            .map(|_| {
+                if narrow {
+                    b'a' + self.below(4) as u8
+                } else {
+                    self.below(256) as u8
+                }
+            })
+            .collect()
+    }
+}
+
+fn value(i: u64) -> (u8, u64, u64) {
+    ((i % 256) as u8, i, i * 2)
+}
+
+#[test]
+fn insert_get_remove() {
+    let mut tree = ArtTree::default();
+    assert!(tree.get(b"abc").copied().is_none());
+
+    assert_eq!(tree.insert(b"abc".to_vec(), value(1)), None);
+    assert_eq!(tree.insert(b"ab".to_vec(), value(2)), None);
+    assert_eq!(tree.insert(b"".to_vec(), value(3)), None);
+    assert_eq!(tree.insert(b"abc".to_vec(), value(4)), Some(value(1)));
+
+    assert_eq!(tree.get(b"abc").copied(), Some(value(4)));
+    assert_eq!(tree.get(b"ab").copied(), Some(value(2)));
+    assert_eq!(tree.get(b"").copied(), Some(value(3)));
+    assert_eq!(tree.get(b"a").copied(), None);
+    assert_eq!(tree.get(b"abcd").copied(), None);
+
+    assert_eq!(tree.remove(b"a"), None);
+    assert_eq!(tree.remove(b"ab"), Some(value(2)));
+    assert_eq!(tree.get(b"abc").copied(), Some(value(4)));
+    assert_eq!(tree.remove(b"abc"), Some(value(4)));
+    assert_eq!(tree.remove(b""), Some(value(3)));
+    assert!(tree.is_empty());
+}
+
+#[test]
+fn grow_and_shrink_every_node_type() {
+    let mut tree = ArtTree::default();
+    for b in 0..=255_u8 {
+        tree.insert(vec![b, b], value(b as u64));
+    }
+    for b in 0..=255_u8 {
+        assert_eq!(tree.get(&[b, b]).copied(), Some(value(b as u64)));
+    }
+    for b in (0..=255_u8).rev() {
+        assert_eq!(tree.remove(&[b, b]), Some(value(b as u64)));
+        for left in 0..b {
+            assert_eq!(tree.get(&[left, left]).copied(), Some(value(left as u64)));
+        }
+    }
+    assert!(tree.is_empty());
+}
+
+#[test]
+fn random_ops_match_btreemap() {
+    for seed in 1..=16_u64 {
+        let mut rng = Rng(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15));
+        let mut tree = ArtTree::default();
+        let mut model = BTreeMap::new();
+
+        for i in 0..4000 {
+            let key = rng.key();
+            match rng.below(10) {
+                0..=5 => {
+                    assert_eq!(
+                        tree.insert(key.clone(), value(i)),
+                        model.insert(key, value(i))
+                    );
+                }
+                6..=8 => assert_eq!(tree.remove(&key), model.remove(&key)),
+                _ => assert_eq!(tree.get(&key).copied(), model.get(&key).copied()),
+            }
+        }
+
+        for (key, v) in model.iter() {
+            assert_eq!(tree.get(key).copied(), Some(*v));
+        }
+        let keys: Vec<Vec<u8>> = model.keys().cloned().collect();
+        for key in keys {
+            assert_eq!(tree.remove(&key), model.remove(&key));
+            assert_eq!(tree.get(&key).copied(), None);
+        }
+        assert!(tree.is_empty());
+    }
+}
+
+#[test]
+fn long_shared_prefixes_match_btreemap() {
+    let mut rng = Rng(0xDEAD_BEEF);
+    let mut tree = ArtTree::default();
+    let mut model = BTreeMap::new();
+
+    // Hierarchical keys share paths much longer than the inline prefix,
+    // and some of them are prefixes of others.
+    for i in 0..6000 {
+        let mut key = format!(
+            "tenant/{}/user/{}/",
+            rng.below(3),
+            rng.below(20) * 1_000_000_007
+        )
+        .into_bytes();
+        key.truncate(rng.below(key.len() as u64 + 1) as usize);
+        key.extend((0..rng.below(3)).map(|_| b'a' + rng.below(3) as u8));
+        if rng.below(3) == 0 {
+            assert_eq!(tree.remove(&key), model.remove(&key));
+        } else {
+            assert_eq!(
+                tree.insert(key.clone(), value(i)),
+                model.insert(key, value(i))
+            );
+        }
+    }
+
+    for (key, v) in model.iter() {
+        assert_eq!(tree.get(key).copied(), Some(*v));
+        let mut missing = key.clone();
+        missing.push(b'#');
+        assert_eq!(tree.get(&missing).copied(), None);
+    }
+    let keys: Vec<Vec<u8>> = model.keys().cloned().collect();
+    for key in keys.iter().step_by(2) {
+        assert_eq!(tree.remove(key), model.remove(key));
+    }
+    for key in keys.iter() {
+        assert_eq!(tree.get(key).copied(), model.get(key).copied());
+        let mut probe = key.clone();
+        probe.truncate(rng.below(key.len() as u64 + 1) as usize);
+        let walked: Vec<&[u8]> = tree.seek(&probe).take(5).map(|(k, _)| k).collect();
+        let expected: Vec<&[u8]> = model.range(probe..).take(5).map(|(k, _)| &k[..]).collect();
+        assert_eq!(walked, expected);
+    }
+}
+
+#[test]
+fn iter_and_seek_in_order() {
+    let mut rng = Rng(0x1234_5678);
+    let mut tree = ArtTree::default();
+    let mut model = BTreeMap::new();
+    for i in 0..3000 {
+        let key = rng.key();
+        if rng.below(4) == 0 {
+            tree.remove(&key);
+            model.remove(&key);
+        } else {
+            tree.insert(key.clone(), value(i));
+            model.insert(key, value(i));
+        }
+    }
+
+    let walked: Vec<(Vec<u8>, (u8, u64, u64))> =
+        tree.iter().map(|(k, v)| (k.to_vec(), *v)).collect();
+    let expected: Vec<(Vec<u8>, (u8, u64, u64))> =
+        model.iter().map(|(k, v)| (k.clone(), *v)).collect();
+    assert_eq!(walked, expected);
+
+    for _ in 0..500 {
+        let key = rng.key();
+        let walked: Vec<(Vec<u8>, (u8, u64, u64))> = tree
+            .seek(&key)
+            .take(20)
+            .map(|(k, v)| (k.to_vec(), *v))
+            .collect();
+        let expected: Vec<(Vec<u8>, (u8, u64, u64))> = model
+            .range(key..)
+            .take(20)
+            .map(|(k, v)| (k.clone(), *v))
+            .collect();
+        assert_eq!(walked, expected);
+    }
+
+    assert!(ArtTree::<u64>::default().iter().next().is_none());
+    assert!(ArtTree::<u64>::default().seek(b"a").next().is_none());
+}
+
+#[test]
+fn reverse_iter_and_seek_for_prev() {
+    let mut rng = Rng(0x8765_4321);
+    let mut tree = ArtTree::default();
+    let mut model = BTreeMap::new();
+    assert!(tree.first().is_none());
+    assert!(tree.last().is_none());
+    assert!(tree.seek_for_prev(b"a").next().is_none());
+
+    for i in 0..3000 {
+        let key = rng.key();
+        if rng.below(4) == 0 {
+            tree.remove(&key);
+            model.remove(&key);
+        } else {
+            tree.insert(key.clone(), value(i));
+            model.insert(key, value(i));
+        }
+    }
+
+    let first = model.iter().next().map(|(k, v)| (k.as_slice(), v));
+    let last = model.iter().next_back().map(|(k, v)| (k.as_slice(), v));
+    assert_eq!(tree.first(), first);
+    assert_eq!(tree.last(), last);
+
+    let walked: Vec<(Vec<u8>, (u8, u64, u64))> =
+        tree.iter_rev().map(|(k, v)| (k.to_vec(), *v)).collect();
+    let expected: Vec<(Vec<u8>, (u8, u64, u64))> =
+        model.iter().rev().map(|(k, v)| (k.clone(), *v)).collect();
+    assert_eq!(walked, expected);
+
+    for _ in 0..500 {
+        let key = rng.key();
+        let walked: Vec<&[u8]> = tree.seek_for_prev(&key).take(20).map(|(k, _)| k).collect();
+        let expected: Vec<&[u8]> = model
+            .range(..=key)
+            .rev()
+            .take(20)
+            .map(|(k, _)| &k[..])
+            .collect();
+        assert_eq!(walked, expected);
+    }
+}
+
+#[test]
+fn owned_values() {
+    let mut tree: ArtTree<String> = ArtTree::default();
+    for i in 0..100 {
+        tree.insert(
+            format!("route/{:02}", i).into_bytes(),
+            format!("backend-{}", i),
+        );
+    }
+    assert_eq!(tree.get(b"route/42"), Some(&"backend-42".to_string()));
+    assert_eq!(
+        tree.insert(b"route/42".to_vec(), "moved".to_string()),
+        Some("backend-42".to_string())
+    );
+    assert_eq!(tree.remove(b"route/42"), Some("moved".to_string()));
+    let values: Vec<&String> = tree.seek(b"route/97").map(|(_, v)| v).collect();
+    assert_eq!(values, vec!["backend-97", "backend-98", "backend-99"]);
+}
+
+#[test]
+fn memory_usage_by_node_type() {
+    let mut tree = ArtTree::default();
+    assert_eq!(tree.memory_usage(), Default::default());
+
+    // One root under which the first byte fans out to 256 children,
+    // and the child of byte b fans out to b % 64 + 1 leaves.
+    for b in 0..=255_u8 {
+        for c in 0..=(b % 64) {
+            tree.insert(vec![b, c], value(b as u64));
+        }
+    }
+    let usage = tree.memory_usage();
+    let fanouts = (0..=255_usize).map(|b| b % 64 + 1);
+    assert_eq!(
+        usage.node256,
+        1 + fanouts.clone().filter(|n| *n > 48).count()
+    );
+    assert_eq!(
+        usage.node48,
+        fanouts.clone().filter(|n| (17..=48).contains(n)).count()
+    );
+    assert_eq!(
+        usage.node16,
+        fanouts.clone().filter(|n| (5..=16).contains(n)).count()
+    );
+    assert_eq!(
+        usage.node4,
+        fanouts.clone().filter(|n| (2..=4).contains(n)).count()
+    );
+    assert_eq!(usage.leaves, fanouts.sum::<usize>());
+    assert!(usage.used_bytes > usage.leaves * 2);
+
+    // Removed nodes are kept for reuse instead of going back to the allocator.
+    for b in 0..=255_u8 {
+        for c in 0..=(b % 64) {
+            tree.remove(&[b, c]);
+        }
+    }
+    let usage = tree.memory_usage();
+    assert_eq!(usage.leaves, 0);
+    assert_eq!(usage.used_bytes, 0);
+    assert!(usage.free_bytes > 0);
+}
+
+#[test]
+fn snapshots_keep_their_view() {
+    let mut rng = Rng(0x5EED_5EED);
+    let mut tree = ArtTree::default();
+    let mut model = BTreeMap::new();
+    let mut snapshots = Vec::new();
+
+    for round in 0..8 {
+        for i in 0..1000 {
+            let key = rng.key();
+            if rng.below(3) == 0 {
+                assert_eq!(tree.remove(&key), model.remove(&key));
+            } else {
+                let v = value(round * 1000 + i);
+                assert_eq!(tree.insert(key.clone(), v), model.insert(key, v));
+            }
+        }
+        snapshots.push((tree.snapshot(), model.clone()));
+    }
+    // Dropping a snapshot gives its nodes back to the live tree.
+    snapshots.remove(3);
+
+    for (snapshot, model) in snapshots.iter() {
+        let walked: Vec<(Vec<u8>, (u8, u64, u64))> =
+            snapshot.iter().map(|(k, v)| (k.to_vec(), *v)).collect();
+        let expected: Vec<(Vec<u8>, (u8, u64, u64))> =
+            model.iter().map(|(k, v)| (k.clone(), *v)).collect();
+        assert_eq!(walked, expected);
+        for (key, v) in model.iter() {
+            assert_eq!(snapshot.get(key), Some(v));
+        }
+        let reversed: Vec<&[u8]> = snapshot.iter_rev().map(|(k, _)| k).collect();
+        assert_eq!(reversed.len(), model.len());
+    }
+
+    let keys: Vec<Vec<u8>> = model.keys().cloned().collect();
+    for key in keys.iter() {
+        assert_eq!(tree.remove(key), model.remove(key));
+    }
+    assert!(tree.is_empty());
+    let (last, last_model) = snapshots.last().unwrap();
+    assert_eq!(last.iter().count(), last_model.len());
+    assert_eq!(last.clone().first(), last.first());
+}
+
+#[test]
+fn bulk_load_sorted_pairs() {
+    let mut rng = Rng(0xB01C_10AD);
+    let mut model = BTreeMap::new();
+    for i in 0..5000 {
+        model.insert(rng.key(), value(i));
+    }
+    let tree = ArtTree::from_sorted_iter(model.iter().map(|(k, v)| (k.clone(), *v))).unwrap();
+    let mut inserted = ArtTree::default();
+    for (key, v) in model.iter() {
+        inserted.insert(key.clone(), *v);
+    }
+
+    let walked: Vec<(Vec<u8>, (u8, u64, u64))> =
+        tree.iter().map(|(k, v)| (k.to_vec(), *v)).collect();
+    let expected: Vec<(Vec<u8>, (u8, u64, u64))> =
+        model.iter().map(|(k, v)| (k.clone(), *v)).collect();
+    assert_eq!(walked, expected);
+    // Same shape as inserting the keys one by one.
+    let (bulk, one_by_one) = (tree.memory_usage(), inserted.memory_usage());
+    assert_eq!(
+        (
+            bulk.node4,
+            bulk.node16,
+            bulk.node48,
+            bulk.node256,
+            bulk.leaves
+        ),
+        (
+            one_by_one.node4,
+            one_by_one.node16,
+            one_by_one.node48,
+            one_by_one.node256,
+            one_by_one.leaves
+        )
+    );
+
+    // The loaded tree takes writes like any other.
+    let mut tree = tree;
+    for (key, _) in model.iter().step_by(3) {
+        assert!(tree.remove(key).is_some());
+    }
+    assert_eq!(tree.iter().count(), model.len() - model.len().div_ceil(3));
+
+    assert!(ArtTree::<u64>::from_sorted_iter(vec![]).unwrap().is_empty());
+    let unsorted = vec![(b"b".to_vec(), 1), (b"a".to_vec(), 2)];
+    assert!(ArtTree::from_sorted_iter(unsorted).is_err());
+    let duplicated = vec![(b"a".to_vec(), 1), (b"a".to_vec(), 2)];
+    assert!(ArtTree::from_sorted_iter(duplicated).is_err());
+}
+
+#[test]
+fn longest_prefix_match_against_model() {
+    let mut rng = Rng(0x10C4_1F1E);
+    let mut tree = ArtTree::default();
+    let mut model = BTreeMap::new();
+    for i in 0..3000 {
+        let key = rng.key();
+        tree.insert(key.clone(), value(i));
+        model.insert(key, value(i));
+    }
+    // Long routes sharing paths longer than the inline prefix.
+    let base = b"routes/eu-west/cluster-0001/".to_vec();
+    for len in [0, 7, 12, 20, base.len()].iter() {
+        tree.insert(base[..*len].to_vec(), value(*len as u64));
+        model.insert(base[..*len].to_vec(), value(*len as u64));
+    }
+
+    let mut queries: Vec<Vec<u8>> = (0..3000).map(|_| rng.key()).collect();
+    queries.extend(model.keys().take(500).map(|k| {
+        let mut q = k.clone();
+        q.extend(rng.key());
+        q
+    }));
+    queries.push(b"routes/eu-west/cluster-0001/node/9".to_vec());
+    queries.push(b"routes/eu-west/cluster-0002".to_vec());
+    queries.push(b"routes/eu-wes".to_vec());
+    for q in queries.iter() {
+        let expected = (0..=q.len())
+            .rev()
+            .find_map(|n| model.get_key_value(&q[..n]))
+            .map(|(k, v)| (k.as_slice(), v));
+        assert_eq!(tree.longest_prefix_match(q), expected, "{:?}", q);
+    }
+
+    let mut tree = ArtTree::default();
+    assert_eq!(tree.longest_prefix_match(b"a"), None);
+    tree.insert(b"abc".to_vec(), 1);
+    assert_eq!(tree.longest_prefix_match(b"ab"), None);
+    assert_eq!(
+        tree.longest_prefix_match(b"abcd"),
+        Some((b"abc".as_ref(), &1))
+    );
+}
+
+#[test]
+fn counts_ranks_and_nth_match_model() {
+    let mut rng = Rng(0xC0_0D7);
+    let mut tree = ArtTree::default();
+    let mut model = BTreeMap::new();
+    let check = |tree: &ArtTree<(u8, u64, u64)>,
+                 model: &BTreeMap<Vec<u8>, (u8, u64, u64)>,
+                 rng: &mut Rng| {
+        assert_eq!(tree.len(), model.len());
+        let keys: Vec<&Vec<u8>> = model.keys().collect();
+        for (i, key) in keys.iter().enumerate().step_by(7) {
+            assert_eq!(tree.rank(key), i);
+            assert_eq!(tree.nth(i), Some((key.as_slice(), &model[*key])));
+        }
+        assert_eq!(tree.nth(model.len()), None);
+        for _ in 0..200 {
+            let (mut a, mut b) = (rng.key(), rng.key());
+            if a > b {
+                std::mem::swap(&mut a, &mut b);
+            }
+            assert_eq!(tree.rank(&a), model.range(..a.clone()).count());
+            assert_eq!(
+                tree.count_range(a.clone()..b.clone()),
+                model.range(a.clone()..b.clone()).count()
+            );
+            assert_eq!(
+                tree.count_range(a.clone()..=b.clone()),
+                model.range(a.clone()..=b.clone()).count()
+            );
+            assert_eq!(
+                tree.count_range(a.clone()..),
+                model.range(a.clone()..).count()
+            );
+            assert_eq!(
+                tree.count_range(..=b.clone()),
+                model.range(..=b.clone()).count()
+            );
+        }
+        assert_eq!(tree.count_range(..), model.len());
+    };
+
+    for i in 0..4000 {
+        let key = rng.key();
+        tree.insert(key.clone(), value(i));
+        model.insert(key, value(i));
+    }
+    // Paths longer than the inline prefix.
+    for i in 0..200 {
+        let key = format!("routes/eu-west/cluster-{:04}/node", i % 37).into_bytes();
+        let key = key[..key.len() - (i % 5) as usize].to_vec();
+        tree.insert(key.clone(), value(i));
+        model.insert(key, value(i));
+    }
+    check(&tree, &model, &mut rng);
+
+    let snapshot = tree.snapshot();
+    let before = model.clone();
+    for _ in 0..3000 {
+        let key = rng.key();
+        assert_eq!(tree.remove(&key), model.remove(&key));
+    }
+    for key in before.keys().step_by(4) {
+        assert_eq!(tree.remove(key), model.remove(key));
+    }
+    check(&tree, &model, &mut rng);
+    check(&snapshot, &before, &mut rng);
+
+    let bulk = ArtTree::from_sorted_iter(model.iter().map(|(k, v)| (k.clone(), *v))).unwrap();
+    check(&bulk, &model, &mut rng);
+    assert_eq!(ArtTree::<u64>::default().len(), 0);
+    assert_eq!(ArtTree::<u64>::default().rank(b"a"), 0);
+}
@@ -0,0 +1,197 @@
+use std::fs;
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tigadb::db::{Change, Changes, DB, DELETE, INSERT};
+use tigadb::option::{Option, SyncPolicy};
+
+mod common;
+use common::{dir, fresh_with, open_with};
+
+type Expected = Vec<(u64, u8, Vec<u8>, Vec<u8>)>;
+
+// Writes n ops from the i-th on, and returns the change each one logged.
+fn write(db: &DB, from: usize, n: usize) -> Expected {
+    let mut expected = Vec::new();
+    for i in from..from + n {
+        let key = format!("key/{:02}", i % 37).into_bytes();
+        if i % 4 == 3 {
+            let logged = db.get(&key).unwrap().is_some();
+            db.delete(&key).unwrap();
+            // Deleting a missing key logs nothing.
+            if logged {
+                expected.push((db.last_seq(), DELETE, key, vec![]));
+            }
+        } else {
+            let value = vec![i as u8; i % 90];
+            db.put(key.clone(), value.clone()).unwrap();
+            expected.push((db.last_seq(), INSERT, key, value));
+        }
+    }
+    expected
+}
+
+fn fields(change: Change) -> (u64, u8, Vec<u8>, Vec<u8>) {
+    (
+        change.seq(),
+        change.op(),
+        change.key().to_vec(),
+        change.value().to_vec(),
+    )
+}
+
+fn read(changes: &mut Changes, n: usize) -> Expected {
+    (0..n)
+        .map(|_| {
+            let change = changes.poll(Duration::from_secs(10)).unwrap();
+            fields(change.expect("timed out waiting for a change"))
+        })
+        .collect()
+}
+
+#[test]
+fn changes_follow_writes_across_segment_rolls() {
+    let name = "changes_follow_writes";
+    let opt = Option {
+        limit_per_file: 2048,
+        ..Option::default()
+    };
+    let db = fresh_with(name, opt);
+    let expected = write(&db, 0, 300);
+    assert!(
+        fs::read_dir(format!("{}/meta/wal", dir(name)))
+            .unwrap()
+            .count()
+            > 3
+    );
+
+    // From the start, and from the middle of the log.
+    let mut changes = db.subscribe_changes(0).unwrap();
+    assert_eq!(read(&mut changes, expected.len()), expected);
+    let from = expected[150].0;
+    let mut changes = db.subscribe_changes(from).unwrap();
+    let tail: Expected = expected.iter().filter(|c| c.0 >= from).cloned().collect();
+    assert_eq!(read(&mut changes, tail.len()), tail);
+
+    // A reader tails the writes as they come, across new segments.
+    let mut changes = db.subscribe_changes(db.last_seq() + 1).unwrap();
+    let (count_tx, count_rx) = mpsc::channel();
+    let reader = thread::spawn(move || {
+        let mut seen = Vec::new();
+        let mut count = None;
+        while count != Some(seen.len()) {
+            if let Some(change) = changes.poll(Duration::from_millis(10)).unwrap() {
+                seen.push(fields(change));
+            }
+            count = count.or_else(|| count_rx.try_recv().ok());
+        }
+        seen
+    });
+    let expected = write(&db, 300, 300);
+    let n = expected.len();
+    count_tx.send(n).unwrap();
+    assert_eq!(reader.join().unwrap(), expected);
+
+    // Once the db is closed the reader ends after the last change.
+    let mut changes = db.subscribe_changes(expected[n - 10].0).unwrap();
+    drop(db);
+    let rest: Vec<_> = changes.by_ref().map(|c| fields(c.unwrap())).collect();
+    assert_eq!(rest, expected[n - 10..].to_vec());
+}
+
+// With a lazy sync policy a change is only seen after a sync.
+#[test]
+fn changes_wait_for_durable_commits() {
+    let opt = Option {
+        sync: SyncPolicy::Never,
+        ..Option::default()
+    };
+    let db = fresh_with("changes_wait_for_durable", opt);
+    let mut changes = db.subscribe_changes(1).unwrap();
+    db.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+    db.put(b"b".to_vec(), b"2".to_vec()).unwrap();
+    assert_eq!(changes.poll(Duration::from_millis(50)).unwrap(), None);
+
+    db.sync().unwrap();
+    let change = changes.poll(Duration::from_secs(1)).unwrap().unwrap();
+    assert_eq!((change.seq(), change.key()), (1, &b"a"[..]));
+    let change = changes.poll(Duration::from_secs(1)).unwrap().unwrap();
+    assert_eq!((change.seq(), change.key()), (2, &b"b"[..]));
+
+    // A background sync wakes a waiting reader too.
+    let opt = Option {
+        sync: SyncPolicy::Interval(10),
+        ..Option::default()
+    };
+    let db = fresh_with("changes_wait_for_interval_sync", opt);
+    let mut changes = db.subscribe_changes(1).unwrap();
+    db.put(b"c".to_vec(), b"3".to_vec()).unwrap();
+    let change = changes.poll(Duration::from_secs(10)).unwrap().unwrap();
+    assert_eq!(fields(change), (1, INSERT, b"c".to_vec(), b"3".to_vec()));
+}
+
+#[test]
+fn changes_before_a_checkpoint_are_reclaimed() {
+    let name = "changes_reclaimed";
+    let opt = Option {
+        limit_per_file: 1024,
+        ..Option::default()
+    };
+    let db = fresh_with(name, opt);
+    write(&db, 0, 200);
+    db.checkpoint().unwrap();
+    assert!(db.subscribe_changes(1).is_err());
+
+    // The sequence numbers go on after a restart, and the kept changes can still be read.
+    let last = db.last_seq();
+    drop(db);
+    let db = open_with(name, opt);
+    assert_eq!(db.last_seq(), last);
+    let mut changes = db.subscribe_changes(last).unwrap();
+    let expected = write(&db, 200, 20);
+    let change = changes.poll(Duration::from_secs(1)).unwrap().unwrap();
+    assert_eq!(change.seq(), last);
+    assert_eq!(read(&mut changes, expected.len()), expected);
+}
+
+// A subscriber which lags behind keeps the segments it has still to read,
+// they are reclaimed by the background checkpoints once it is dropped.
+#[test]
+fn lagging_changes_keep_their_segments() {
+    let name = "lagging_changes_keep_their_segments";
+    let opt = Option {
+        limit_per_file: 1024,
+        checkpoint_interval_ms: 5,
+        ..Option::default()
+    };
+    let db = fresh_with(name, opt);
+    let first = format!("{}/meta/wal/000001.log", dir(name));
+    let mut changes = db.subscribe_changes(1).unwrap();
+    let mut expected = write(&db, 0, 50);
+    assert_eq!(read(&mut changes, 10), expected[..10].to_vec());
+
+    let start = Instant::now();
+    let mut i = 50;
+    while db.checkpoint_stats().checkpoints < 3 {
+        assert!(start.elapsed() < Duration::from_secs(20), "timed out");
+        expected.extend(write(&db, i, 10));
+        i += 10;
+        thread::sleep(Duration::from_millis(1));
+    }
+    assert_eq!(db.checkpoint_stats().failures, 0);
+    assert!(Path::new(&first).exists());
+    assert_eq!(
+        read(&mut changes, expected.len() - 10),
+        expected[10..].to_vec()
+    );
+
+    drop(changes);
+    while Path::new(&first).exists() {
+        assert!(start.elapsed() < Duration::from_secs(20), "timed out");
+        write(&db, i, 10);
+        i += 10;
+        thread::sleep(Duration::from_millis(1));
+    }
+    assert!(db.subscribe_changes(1).is_err());
+}
@@ -0,0 +1,65 @@
+// Helpers shared by the test files, each of them has `mod common;`.
+// Not every test file uses every helper.
+#![allow(dead_code)]
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use tigadb::db::DB;
+use tigadb::option::Option;
+
+// xorshift64*, enough randomness for the tests without extra dependencies.
+pub struct Rng(pub u64);
+
+impl Rng {
+    pub fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
+
+// The dir of the db name, its files are under meta and kv in it.
+pub fn dir(name: &str) -> String {
+    format!("target/test-db/{}", name)
+}
+
+// Opens the db left in the dir of name by an earlier open, or a new one.
+pub fn try_open_with(name: &str, opt: Option) -> io::Result<DB> {
+    let dir = dir(name);
+    // Option takes 'static dirs, each open leaks its two short strings.
+    let opt = Option {
+        meta_dir: Box::leak(format!("{}/meta", dir).into_boxed_str()),
+        kv_dir: Box::leak(format!("{}/kv", dir).into_boxed_str()),
+        ..opt
+    };
+    DB::new(opt)
+}
+
+pub fn open_with(name: &str, opt: Option) -> DB {
+    try_open_with(name, opt).unwrap()
+}
+
+pub fn open(name: &str) -> DB {
+    open_with(name, Option::default())
+}
+
+// Removes what an earlier run left in the dir of name and opens a new db there.
+pub fn fresh_with(name: &str, opt: Option) -> DB {
+    let _ = fs::remove_dir_all(dir(name));
+    open_with(name, opt)
+}
+
+pub fn fresh(name: &str) -> DB {
+    fresh_with(name, Option::default())
+}
+
+// All the pairs in the db.
+pub fn contents(db: &DB) -> BTreeMap<Vec<u8>, Vec<u8>> {
+    db.scan(..).map(|kv| kv.unwrap()).collect()
+}
@@ -0,0 +1,238 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use tigadb::concurrent_art::ConcurrentArtTree;
+
+mod common;
+use common::Rng;
+
+impl Rng {
+    // Keys share long paths, some of them are prefixes of others,
+    // and the last bytes spread out to fill every node type.
+    fn key(&mut self) -> Vec<u8> {
+        let mut key = format!("tenant/{}/user/", self.below(3)).into_bytes();
+        key.truncate(self.below(key.len() as u64 + 1) as usize);
+        let len = self.below(4);
+        key.extend((0..len).map(|_| {
+            if self.below(2) == 0 {
+                b'a' + self.below(4) as u8
+            } else {
+                self.below(256) as u8
+            }
+        }));
+        key
+    }
+}
+
+// A value tells which key and which write it comes from,
+// so that a reader can check it never sees another key's value.
+fn value(key: &[u8], write: u64) -> (u64, u64) {
+    let hash = key.iter().fold(0xcbf2_9ce4_8422_2325_u64, |h, b| {
+        (h ^ *b as u64).wrapping_mul(0x100_0000_01b3)
+    });
+    (hash, write)
+}
+
+#[test]
+fn single_thread_matches_btreemap() {
+    for seed in 1..=8_u64 {
+        let mut rng = Rng(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15));
+        let tree = ConcurrentArtTree::default();
+        let mut model = BTreeMap::new();
+        for i in 0..6000 {
+            let key = rng.key();
+            match rng.below(10) {
+                0..=5 => assert_eq!(
+                    tree.insert(key.clone(), value(&key, i)),
+                    model.insert(key.clone(), value(&key, i))
+                ),
+                6..=8 => assert_eq!(tree.remove(&key), model.remove(&key)),
+                _ => assert_eq!(tree.get(&key), model.get(&key).copied()),
+            }
+        }
+        for (key, v) in model.iter() {
+            assert_eq!(tree.get(key), Some(*v));
+        }
+        let keys: Vec<Vec<u8>> = model.keys().cloned().collect();
+        for key in keys {
+            assert_eq!(tree.remove(&key), model.remove(&key));
+            assert_eq!(tree.get(&key), None);
+        }
+    }
+}
+
+#[test]
+fn writers_on_own_keys_with_readers() {
+    const WRITERS: u64 = 4;
+    const READERS: u64 = 4;
+    let tree = Arc::new(ConcurrentArtTree::default());
+    let stop = Arc::new(AtomicBool::new(false));
+
+    // Each writer owns the keys starting with its id, and keeps a model of them.
+    let writers: Vec<_> = (0..WRITERS)
+        .map(|id| {
+            let tree = tree.clone();
+            thread::spawn(move || {
+                let mut rng = Rng(0x1234_5678 + id);
+                let mut model = BTreeMap::new();
+                for i in 0..20_000 {
+                    let mut key = vec![b'0' + id as u8];
+                    key.extend(rng.key());
+                    if rng.below(3) == 0 {
+                        assert_eq!(tree.remove(&key), model.remove(&key));
+                    } else {
+                        assert_eq!(
+                            tree.insert(key.clone(), value(&key, i)),
+                            model.insert(key.clone(), value(&key, i))
+                        );
+                    }
+                }
+                model
+            })
+        })
+        .collect();
+
+    let readers: Vec<_> = (0..READERS)
+        .map(|id| {
+            let tree = tree.clone();
+            let stop = stop.clone();
+            thread::spawn(move || {
+                let mut rng = Rng(0x8765_4321 + id);
+                let mut found = 0;
+                while !stop.load(Ordering::Relaxed) {
+                    let mut key = vec![b'0' + rng.below(WRITERS) as u8];
+                    key.extend(rng.key());
+                    if let Some((hash, _)) = tree.get(&key) {
+                        assert_eq!(hash, value(&key, 0).0);
+                        found += 1;
+                    }
+                }
+                found
+            })
+        })
+        .collect();
+
+    let models: Vec<BTreeMap<Vec<u8>, (u64, u64)>> =
+        writers.into_iter().map(|w| w.join().unwrap()).collect();
+    stop.store(true, Ordering::Relaxed);
+    for reader in readers {
+        reader.join().unwrap();
+    }
+
+    for model in models.iter() {
+        for (key, v) in model.iter() {
+            assert_eq!(tree.get(key), Some(*v));
+        }
+    }
+}
+
+#[test]
+fn writers_race_on_shared_keys() {
+    const THREADS: u64 = 8;
+    let tree = Arc::new(ConcurrentArtTree::default());
+
+    // All threads insert and remove the same keys, then insert their own ones.
+    let threads: Vec<_> = (0..THREADS)
+        .map(|id| {
+            let tree = tree.clone();
+            thread::spawn(move || {
+                let mut rng = Rng(0xDEAD_BEEF);
+                for i in 0..5000 {
+                    let key = rng.key();
+                    if (i + id) % 3 == 0 {
+                        tree.remove(&key);
+                    } else {
+                        tree.insert(key.clone(), value(&key, id));
+                    }
+                    if let Some((hash, _)) = tree.get(&key) {
+                        assert_eq!(hash, value(&key, 0).0);
+                    }
+                }
+                let mut rng = Rng(0xC0FFEE + id);
+                let keys: Vec<Vec<u8>> = (0..2000)
+                    .map(|_| {
+                        let mut key = rng.key();
+                        key.extend_from_slice(&id.to_be_bytes());
+                        key
+                    })
+                    .collect();
+                for key in keys.iter() {
+                    tree.insert(key.clone(), value(key, id));
+                }
+                keys
+            })
+        })
+        .collect();
+
+    for (id, thread) in threads.into_iter().enumerate() {
+        for key in thread.join().unwrap() {
+            assert_eq!(tree.get(&key), Some(value(&key, id as u64)));
+        }
+    }
+}
+
+#[test]
+fn churn_keeps_memory_flat() {
+    const THREADS: u64 = 4;
+    let tree = Arc::new(ConcurrentArtTree::default());
+    let empty = tree.memory_usage();
+    assert_eq!(empty.node256, 1);
+
+    let mut rng = Rng(0xF1A7);
+    let kept: BTreeSet<Vec<u8>> = (0..3000)
+        .map(|_| {
+            let mut key = rng.key();
+            key.push(b'k');
+            key
+        })
+        .collect();
+    for key in kept.iter() {
+        tree.insert(key.clone(), value(key, 0));
+    }
+    let base = tree.memory_usage();
+
+    // The threads add keys among the kept ones and remove them all again,
+    // so the nodes they grew, split or added must all be gone after each round.
+    for round in 0..5 {
+        let threads: Vec<_> = (0..THREADS)
+            .map(|id| {
+                let tree = tree.clone();
+                thread::spawn(move || {
+                    let mut rng = Rng(0xC4A2 + round * THREADS + id);
+                    let mut keys = Vec::new();
+                    for i in 0..5000 {
+                        let mut key = rng.key();
+                        key.push(id as u8);
+                        if rng.below(4) == 0 {
+                            tree.remove(&key);
+                        } else {
+                            tree.insert(key.clone(), value(&key, i));
+                            keys.push(key);
+                        }
+                    }
+                    for key in keys {
+                        tree.remove(&key);
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let usage = tree.memory_usage();
+        assert_eq!(usage.leaves, base.leaves);
+        assert!(
+            usage.used_bytes <= base.used_bytes + base.used_bytes / 10,
+            "round {}: {:?} after {:?}",
+            round,
+            usage,
+            base
+        );
+    }
+
+    for key in kept.iter() {
+        assert_eq!(tree.remove(key), Some(value(key, 0)));
+    }
+    assert_eq!(tree.memory_usage(), empty);
+}
@@ -0,0 +1,536 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::os::unix::fs::FileExt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tigadb::db::DB;
+use tigadb::option::{Option, SyncPolicy, WalRecovery};
+
+mod common;
+use common::{contents, dir, fresh, fresh_with, open, open_with, try_open_with};
+
+fn collect(
+    scan: impl Iterator<Item = std::io::Result<(Vec<u8>, Vec<u8>)>>,
+) -> Vec<(Vec<u8>, Vec<u8>)> {
+    scan.map(|kv| kv.unwrap()).collect()
+}
+
+#[test]
+fn put_get_delete() {
+    let db = fresh("put_get_delete");
+    db.put(b"k1".to_vec(), b"v1".to_vec()).unwrap();
+    db.put(b"k2".to_vec(), vec![7; 3000]).unwrap();
+    db.put(b"k1".to_vec(), b"v1-new".to_vec()).unwrap();
+    assert_eq!(db.get(b"k1").unwrap(), Some(b"v1-new".to_vec()));
+    assert_eq!(db.get(b"k2").unwrap(), Some(vec![7; 3000]));
+
+    db.delete(b"k1").unwrap();
+    assert_eq!(db.get(b"k1").unwrap(), None);
+    // The freed blocks are reused and must not clobber live values.
+    db.put(b"k3".to_vec(), b"v3".to_vec()).unwrap();
+    assert_eq!(db.get(b"k3").unwrap(), Some(b"v3".to_vec()));
+    assert_eq!(db.get(b"k2").unwrap(), Some(vec![7; 3000]));
+}
+
+// Overwritten meta entries are reused, and the blocks freed before a restart are reused after it.
+#[test]
+fn meta_and_free_blocks_are_rebuilt_on_open() {
+    let name = "meta_and_free_blocks_are_rebuilt_on_open";
+    let meta_fpath = format!("{}/meta/kv.meta", dir(name));
+    let data_fpath = format!("{}/kv/kv.data", dir(name));
+    let db = fresh(name);
+    for round in 0..20 {
+        for i in 0..50 {
+            let key = format!("key/{:02}", i).into_bytes();
+            db.put(key, vec![round as u8; 600]).unwrap();
+        }
+    }
+    // The header, the entries of the live kvs and one dropped entry waiting to be reused.
+    assert!(fs::metadata(&meta_fpath).unwrap().len() <= 4 + 9 * 51);
+    db.checkpoint().unwrap();
+    drop(db);
+
+    let db = open(name);
+    assert_eq!(fs::metadata(&meta_fpath).unwrap().len(), 4 + 9 * 50);
+    assert_eq!(db.get(b"key/07").unwrap(), Some(vec![19; 600]));
+    for i in 0..50 {
+        db.delete(format!("key/{:02}", i).as_bytes()).unwrap();
+    }
+    let data_len = fs::metadata(&data_fpath).unwrap().len();
+    drop(db);
+
+    let db = open(name);
+    for i in 0..50 {
+        let key = format!("other/{:02}", i).into_bytes();
+        db.put(key, vec![i as u8; 600]).unwrap();
+    }
+    assert!(fs::metadata(&data_fpath).unwrap().len() <= data_len);
+    assert_eq!(db.get(b"other/42").unwrap(), Some(vec![42; 600]));
+    drop(db);
+
+    let db = open(name);
+    assert_eq!(db.scan(..).count(), 50);
+    assert_eq!(db.get(b"other/42").unwrap(), Some(vec![42; 600]));
+}
+
+#[test]
+fn scan_ranges_and_prefixes() {
+    let db = fresh("scan_ranges_and_prefixes");
+    let mut model = BTreeMap::new();
+    for tenant in 0..3 {
+        for user in 0..40 {
+            let key = format!("tenant/{}/user/{:03}", tenant, user).into_bytes();
+            let value = format!("value-{}-{}", tenant, user).into_bytes();
+            db.put(key.clone(), value.clone()).unwrap();
+            model.insert(key, value);
+        }
+    }
+    db.delete(b"tenant/1/user/007").unwrap();
+    model.remove(b"tenant/1/user/007".as_ref());
+
+    let expected: Vec<(Vec<u8>, Vec<u8>)> =
+        model.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+    assert_eq!(collect(db.scan(..)), expected);
+
+    let start = b"tenant/0/user/035".to_vec();
+    let end = b"tenant/1/user/010".to_vec();
+    let expected: Vec<(Vec<u8>, Vec<u8>)> = model
+        .range(start.clone()..end.clone())
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+    assert_eq!(collect(db.scan(start.clone()..end.clone())), expected);
+    assert_eq!(
+        collect(db.scan(start.clone()..=end.clone())).len(),
+        expected.len() + 1
+    );
+
+    let prefix = b"tenant/1/";
+    let expected: Vec<(Vec<u8>, Vec<u8>)> = model
+        .iter()
+        .filter(|(k, _)| k.starts_with(prefix))
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+    assert_eq!(expected.len(), 39);
+    assert_eq!(collect(db.scan_prefix(prefix)), expected);
+    assert_eq!(collect(db.scan_prefix(b"tenant/9")), vec![]);
+    assert_eq!(collect(db.scan_prefix(b"")).len(), model.len());
+}
+
+#[test]
+fn scan_in_reverse_and_from_both_ends() {
+    let db = fresh("scan_in_reverse_and_from_both_ends");
+    for user in 0..5 {
+        for event in 0..30 {
+            let key = format!("events/user{}/{:04}", user, event).into_bytes();
+            db.put(key, format!("event-{}", event).into_bytes())
+                .unwrap();
+        }
+    }
+
+    // The latest 3 events of user2.
+    let latest: Vec<Vec<u8>> = db
+        .scan_rev(b"events/user2/".to_vec()..b"events/user3/".to_vec())
+        .take(3)
+        .map(|kv| kv.unwrap().0)
+        .collect();
+    assert_eq!(
+        latest,
+        vec![
+            b"events/user2/0029".to_vec(),
+            b"events/user2/0028".to_vec(),
+            b"events/user2/0027".to_vec(),
+        ]
+    );
+    assert_eq!(collect(db.scan_prefix(b"events/user4/").rev()).len(), 30);
+
+    // Both ends meet in the middle without yielding a key twice.
+    let mut scan = db.scan_prefix(b"events/user1/");
+    let mut keys = Vec::new();
+    let mut tail = Vec::new();
+    while let Some(kv) = scan.next() {
+        keys.push(kv.unwrap().0);
+        match scan.next_back() {
+            Some(kv) => tail.push(kv.unwrap().0),
+            None => break,
+        }
+    }
+    tail.reverse();
+    keys.append(&mut tail);
+    let expected: Vec<Vec<u8>> = (0..30)
+        .map(|event| format!("events/user1/{:04}", event).into_bytes())
+        .collect();
+    assert_eq!(keys, expected);
+}
+
+#[test]
+fn longest_prefix_match_routes() {
+    let db = fresh("longest_prefix_match_routes");
+    for (route, backend) in [
+        ("/", "root"),
+        ("/api/", "api"),
+        ("/api/v2/", "api-v2"),
+        ("/static", "cdn"),
+    ]
+    .iter()
+    {
+        db.put(route.as_bytes().to_vec(), backend.as_bytes().to_vec())
+            .unwrap();
+    }
+    let route = |path: &str| {
+        db.longest_prefix_match(path.as_bytes())
+            .unwrap()
+            .map(|(k, v)| (String::from_utf8(k).unwrap(), String::from_utf8(v).unwrap()))
+    };
+    assert_eq!(
+        route("/api/v2/users"),
+        Some(("/api/v2/".into(), "api-v2".into()))
+    );
+    assert_eq!(route("/api/v1/users"), Some(("/api/".into(), "api".into())));
+    assert_eq!(
+        route("/static/app.js"),
+        Some(("/static".into(), "cdn".into()))
+    );
+    assert_eq!(route("/index.html"), Some(("/".into(), "root".into())));
+    assert_eq!(route("index.html"), None);
+}
+
+#[test]
+fn count_range_and_approximate_size() {
+    let db = fresh("count_range_and_approximate_size");
+    for tenant in 0..4 {
+        for user in 0..500 {
+            let key = format!("tenant/{}/user/{:04}", tenant, user).into_bytes();
+            // Values of tenant t are 10 * (t + 1) bytes long.
+            db.put(key, vec![b'v'; 10 * (tenant + 1)]).unwrap();
+        }
+    }
+    db.put(b"tenant/2".to_vec(), vec![]).unwrap();
+
+    assert_eq!(db.count_range(..), 2001);
+    assert_eq!(
+        db.count_range(b"tenant/1/user/0100".to_vec()..b"tenant/1/user/0200".to_vec()),
+        100
+    );
+    assert_eq!(
+        db.count_range(b"tenant/1/user/0100".to_vec()..=b"tenant/1/user/0200".to_vec()),
+        101
+    );
+    assert_eq!(db.count_range(b"tenant/3".to_vec()..), 500);
+    assert_eq!(db.count_range(b"tenant/9".to_vec()..), 0);
+
+    // Uniform values make the sampled estimate exact.
+    assert_eq!(db.approximate_size(b"tenant/0/"), 500 * 10);
+    assert_eq!(db.approximate_size(b"tenant/3/user/01"), 100 * 40);
+    assert_eq!(db.approximate_size(b"tenant/2/"), 500 * 30);
+    assert_eq!(db.approximate_size(b"tenant/7/"), 0);
+    // Few keys are summed exactly.
+    assert_eq!(db.approximate_size(b"tenant/1/user/000"), 10 * 20);
+    // Mixed values are estimated within a few percent.
+    let total = 500 * (10 + 20 + 30 + 40);
+    let estimate = db.approximate_size(b"tenant/");
+    assert!(estimate * 100 > total * 95 && estimate * 100 < total * 105);
+
+    db.delete(b"tenant/0/user/0000").unwrap();
+    assert_eq!(db.count_range(..), 2000);
+    assert_eq!(db.approximate_size(b"tenant/0/"), 499 * 10);
+}
+
+#[test]
+fn shared_across_threads() {
+    fn assert_send_sync<T: Send + Sync + 'static>() {}
+    assert_send_sync::<DB>();
+
+    let db = Arc::new(fresh("shared_across_threads"));
+    let writer = {
+        let db = db.clone();
+        thread::spawn(move || {
+            for i in 0..100 {
+                let key = format!("key/{:03}", i).into_bytes();
+                db.put(key, vec![i as u8; 10]).unwrap();
+            }
+        })
+    };
+    writer.join().unwrap();
+
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let db = db.clone();
+            thread::spawn(move || {
+                assert_eq!(db.get(b"key/042").unwrap(), Some(vec![42; 10]));
+                db.scan_prefix(b"key/").count()
+            })
+        })
+        .collect();
+    for reader in readers {
+        assert_eq!(reader.join().unwrap(), 100);
+    }
+}
+
+// Writers on many threads share the WAL appends and fsyncs through one db,
+// while checkpoints are taken in between and readers scan.
+#[test]
+fn concurrent_writers_share_syncs() {
+    const WRITERS: usize = 8;
+    const PUTS: usize = 200;
+    let name = "concurrent_writers_share_syncs";
+    let opt = Option {
+        sync: SyncPolicy::Always,
+        checkpoint_interval_ms: 5,
+        ..Option::default()
+    };
+    let db = Arc::new(fresh_with(name, opt));
+    let writers: Vec<_> = (0..WRITERS)
+        .map(|t| {
+            let db = db.clone();
+            thread::spawn(move || {
+                for i in 0..PUTS {
+                    let key = format!("{}/{:03}", t, i).into_bytes();
+                    db.put(key, vec![t as u8; i % 100]).unwrap();
+                    if i % 10 == 9 {
+                        db.delete(format!("{}/{:03}", t, i - 1).as_bytes()).unwrap();
+                    }
+                }
+            })
+        })
+        .collect();
+    let checkpoints = {
+        let db = db.clone();
+        thread::spawn(move || {
+            for _ in 0..5 {
+                db.checkpoint().unwrap();
+                thread::sleep(Duration::from_millis(2));
+            }
+        })
+    };
+    let reader = {
+        let db = db.clone();
+        thread::spawn(move || {
+            for _ in 0..20 {
+                // Each key of a scan is in order, whatever is written meanwhile.
+                let keys: Vec<Vec<u8>> = db.scan(..).map(|kv| kv.unwrap().0).collect();
+                assert!(keys.windows(2).all(|w| w[0] < w[1]));
+            }
+        })
+    };
+    for writer in writers {
+        writer.join().unwrap();
+    }
+    checkpoints.join().unwrap();
+    reader.join().unwrap();
+
+    let batches = (WRITERS * (PUTS + PUTS / 10)) as u64;
+    let stats = db.wal_stats();
+    assert_eq!(stats.batches, batches);
+    assert_eq!(db.last_seq(), batches);
+    assert!(stats.syncs < stats.batches, "{:?}", stats);
+
+    let mut expected = BTreeMap::new();
+    for t in 0..WRITERS {
+        for i in 0..PUTS {
+            if i % 10 != 8 {
+                expected.insert(
+                    format!("{}/{:03}", t, i).into_bytes(),
+                    vec![t as u8; i % 100],
+                );
+            }
+        }
+    }
+    assert_eq!(contents(&db), expected);
+    drop(db);
+    assert_eq!(contents(&open_with(name, opt)), expected);
+}
+
+#[test]
+fn restart_from_index_image() {
+    let name = "restart_from_index_image";
+    let db = fresh(name);
+    assert_eq!(db.last_checkpoint(), 0);
+    let mut model = BTreeMap::new();
+    for i in 0..2000 {
+        let key = format!("tenant/{}/user/{:05}", i % 7, i).into_bytes();
+        let value = format!("value-{}", i).into_bytes();
+        db.put(key.clone(), value.clone()).unwrap();
+        model.insert(key, value);
+    }
+    // Binary and empty keys survive too.
+    for key in [vec![], vec![0], vec![0, 0xff], vec![0xff; 300]].iter() {
+        db.put(key.clone(), key.clone()).unwrap();
+        model.insert(key.clone(), key.clone());
+    }
+    assert_eq!(db.checkpoint().unwrap(), 1);
+    drop(db);
+
+    let db = open(name);
+    assert_eq!(db.last_checkpoint(), 1);
+    let expected: Vec<(Vec<u8>, Vec<u8>)> =
+        model.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+    assert_eq!(collect(db.scan(..)), expected);
+
+    // A newer image replaces the older one.
+    db.delete(b"tenant/3/user/00003").unwrap();
+    db.put(b"tenant/9".to_vec(), b"new".to_vec()).unwrap();
+    assert_eq!(db.checkpoint().unwrap(), 2);
+    drop(db);
+
+    let db = open(name);
+    assert_eq!(db.last_checkpoint(), 2);
+    assert_eq!(db.get(b"tenant/3/user/00003").unwrap(), None);
+    assert_eq!(db.get(b"tenant/9").unwrap(), Some(b"new".to_vec()));
+    assert_eq!(db.scan(..).count(), model.len());
+}
+
+#[test]
+fn corrupt_index_image_fails_open() {
+    let name = "corrupt_index_image_fails_open";
+    let db = fresh(name);
+    for i in 0..100 {
+        db.put(format!("key/{:03}", i).into_bytes(), vec![1; 10])
+            .unwrap();
+    }
+    db.checkpoint().unwrap();
+    drop(db);
+
+    // A flipped bit in a kv position still decodes, only the checksum catches it.
+    let fpath = format!("{}/meta/kv.index", dir(name));
+    let file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&fpath)
+        .unwrap();
+    let len = file.metadata().unwrap().len();
+    let mut byte = [0_u8];
+    file.read_exact_at(&mut byte, len - 8).unwrap();
+    file.write_all_at(&[byte[0] ^ 0x01], len - 8).unwrap();
+    assert!(try_open_with(name, Option::default()).is_err());
+    file.write_all_at(&byte, len - 8).unwrap();
+    assert_eq!(open(name).get(b"key/099").unwrap(), Some(vec![1; 10]));
+
+    file.set_len(len - 3).unwrap();
+    assert!(try_open_with(name, Option::default()).is_err());
+}
+
+#[test]
+fn torn_wal_fails_open_under_fail_policy() {
+    let name = "torn_wal_fails_open_under_fail_policy";
+    let db = fresh(name);
+    for i in 0..10 {
+        db.put(format!("key/{:03}", i).into_bytes(), vec![1; 10])
+            .unwrap();
+    }
+    drop(db);
+
+    let fpath = format!("{}/meta/wal/000001.log", dir(name));
+    let len = fs::metadata(&fpath).unwrap().len();
+    fs::OpenOptions::new()
+        .write(true)
+        .open(&fpath)
+        .unwrap()
+        .set_len(len - 3)
+        .unwrap();
+    let fail = Option {
+        wal_recovery: WalRecovery::Fail,
+        ..Option::default()
+    };
+    assert!(try_open_with(name, fail).is_err());
+    // The files are left as they are, the default policy drops the torn batch.
+    let db = open(name);
+    assert_eq!(db.get(b"key/008").unwrap(), Some(vec![1; 10]));
+    assert_eq!(db.get(b"key/009").unwrap(), None);
+}
+
+#[test]
+fn every_sync_policy_keeps_writes() {
+    let policies = [
+        SyncPolicy::Always,
+        SyncPolicy::EveryN(1),
+        SyncPolicy::EveryN(16),
+        SyncPolicy::Interval(5),
+        SyncPolicy::Never,
+    ];
+    for (i, policy) in policies.iter().enumerate() {
+        let name = format!("every_sync_policy_keeps_writes_{}", i);
+        let opt = Option {
+            sync: *policy,
+            ..Option::default()
+        };
+        let db = fresh_with(&name, opt);
+        for j in 0..100 {
+            db.put(format!("key/{:03}", j).into_bytes(), vec![j as u8; j])
+                .unwrap();
+            if j % 3 == 0 {
+                db.delete(format!("key/{:03}", j / 2).as_bytes()).unwrap();
+            }
+        }
+        db.sync().unwrap();
+        let expected = collect(db.scan(..));
+        if let SyncPolicy::Interval(ms) = policy {
+            thread::sleep(Duration::from_millis(ms * 4));
+        }
+        db.put(b"last".to_vec(), b"1".to_vec()).unwrap();
+        drop(db);
+
+        let db = open_with(&name, opt);
+        assert_eq!(
+            db.get(b"last").unwrap(),
+            Some(b"1".to_vec()),
+            "{:?}",
+            policy
+        );
+        db.delete(b"last").unwrap();
+        assert_eq!(collect(db.scan(..)), expected, "{:?}", policy);
+    }
+}
+
+// Dropping the db stops the flusher at once, it does not wait out the interval.
+#[test]
+fn flusher_stops_on_drop() {
+    let name = "flusher_stops_on_drop";
+    let opt = Option {
+        sync: SyncPolicy::Interval(60_000),
+        ..Option::default()
+    };
+    let db = fresh_with(name, opt);
+    db.put(b"key".to_vec(), b"value".to_vec()).unwrap();
+    let start = Instant::now();
+    drop(db);
+    assert!(start.elapsed() < Duration::from_secs(10));
+    assert_eq!(
+        open_with(name, opt).get(b"key").unwrap(),
+        Some(b"value".to_vec())
+    );
+}
+
+static SYNC_ERRORS: AtomicUsize = AtomicUsize::new(0);
+
+fn count_sync_error(_: &std::io::Error) {
+    SYNC_ERRORS.fetch_add(1, Ordering::SeqCst);
+}
+
+// Background syncs are counted apart from the writes, which return once they are applied.
+#[test]
+fn background_syncs_are_counted() {
+    let name = "background_syncs_are_counted";
+    let opt = Option {
+        sync: SyncPolicy::EveryN(8),
+        on_sync_error: Some(count_sync_error),
+        ..Option::default()
+    };
+    let db = fresh_with(name, opt);
+    let start = Instant::now();
+    let mut i = 0;
+    while db.sync_stats().syncs < 3 {
+        assert!(start.elapsed() < Duration::from_secs(20), "timed out");
+        db.put(format!("key/{:05}", i).into_bytes(), vec![1; 100])
+            .unwrap();
+        i += 1;
+    }
+    let before = db.sync_stats().syncs;
+    db.sync().unwrap();
+    let stats = db.sync_stats();
+    assert!(stats.syncs > before);
+    assert_eq!(stats.failures, 0, "{:?}", stats);
+    assert_eq!(stats.last_error, None);
+    assert_eq!(SYNC_ERRORS.load(Ordering::SeqCst), 0);
+}
@@ -0,0 +1,143 @@
+use std::cmp::Ordering;
+use std::fmt::Debug;
+use tigadb::key::{decode_key, encode_key, KeyDecode, KeyEncode};
+
+// Every pair of values must compare the same way as their encodings,
+// and every value must decode back to itself.
+fn check_order<T>(values: &[T], cmp: impl Fn(&T, &T) -> Ordering)
+where
+    T: KeyEncode + KeyDecode + Debug,
+{
+    for a in values.iter() {
+        let ea = encode_key(a);
+        let back: T = decode_key(&ea).unwrap();
+        assert_eq!(encode_key(&back), ea, "{:?}", a);
+        for b in values.iter() {
+            assert_eq!(ea.cmp(&encode_key(b)), cmp(a, b), "{:?} vs {:?}", a, b);
+        }
+    }
+}
+
+#[test]
+fn integers_keep_their_order() {
+    check_order(&[0_u8, 1, 0x7f, 0x80, u8::MAX], Ord::cmp);
+    check_order(
+        &[0_u64, 1, 255, 256, 1 << 40, u64::MAX - 1, u64::MAX],
+        Ord::cmp,
+    );
+    check_order(&[i8::MIN, -1, 0, 1, i8::MAX], Ord::cmp);
+    check_order(&[i16::MIN, -300, -1, 0, 1, 300, i16::MAX], Ord::cmp);
+    check_order(&[i32::MIN, -70000, -1, 0, 1, 70000, i32::MAX], Ord::cmp);
+    check_order(
+        &[
+            i64::MIN,
+            i64::MIN + 1,
+            -(1 << 40),
+            -1,
+            0,
+            1,
+            1 << 40,
+            i64::MAX,
+        ],
+        Ord::cmp,
+    );
+    assert_eq!(encode_key(&0x0102_u16), vec![1, 2]);
+    assert_eq!(encode_key(&-1_i32), vec![0x7f, 0xff, 0xff, 0xff]);
+}
+
+#[test]
+fn floats_keep_their_order() {
+    let values = [
+        f64::NEG_INFINITY,
+        f64::MIN,
+        -1e10,
+        -1.5,
+        -f64::MIN_POSITIVE,
+        -0.0,
+        0.0,
+        f64::MIN_POSITIVE,
+        1.0,
+        1.5,
+        1e10,
+        f64::MAX,
+        f64::INFINITY,
+    ];
+    check_order(&values, |a, b| a.total_cmp(b));
+    let values = [
+        f32::NEG_INFINITY,
+        -2.5_f32,
+        -0.0,
+        0.0,
+        1e-30,
+        2.5,
+        f32::INFINITY,
+    ];
+    check_order(&values, |a, b| a.total_cmp(b));
+
+    let nan: f64 = decode_key(&encode_key(&f64::NAN)).unwrap();
+    assert!(nan.is_nan());
+    assert!(encode_key(&f64::NAN) > encode_key(&f64::INFINITY));
+}
+
+#[test]
+fn strings_and_bytes_keep_their_order() {
+    let strings: Vec<String> = [
+        "", "\0", "\0\0", "\0a", "a", "a\0", "a\0b", "a\x01", "ab", "b", "é",
+    ]
+    .iter()
+    .map(|s| s.to_string())
+    .collect();
+    check_order(&strings, Ord::cmp);
+
+    let bytes: Vec<Vec<u8>> = vec![
+        vec![],
+        vec![0],
+        vec![0, 0],
+        vec![0, 0xff],
+        vec![1],
+        vec![0xff],
+        vec![0xff, 0],
+        vec![0xff, 0xff],
+    ];
+    check_order(&bytes, Ord::cmp);
+    assert_eq!(encode_key("a\0"), vec![b'a', 0, 0xff, 0, 1]);
+}
+
+#[test]
+fn tuples_keep_their_order() {
+    let mut tuples = Vec::new();
+    for tenant in [0_u64, 1, 256].iter() {
+        for ts in [i64::MIN, -5, 0, 5].iter() {
+            for name in ["", "a", "a\0", "ab", "b"].iter() {
+                tuples.push((*tenant, *ts, name.to_string()));
+            }
+        }
+    }
+    check_order(&tuples, Ord::cmp);
+
+    // A string part does not run into the part after it.
+    let pairs: Vec<(String, u8)> = vec![
+        ("a".to_string(), 0xff),
+        ("a\0".to_string(), 0),
+        ("ab".to_string(), 0),
+    ];
+    check_order(&pairs, Ord::cmp);
+
+    let key = encode_key(&(7_u32, "user", -3_i16, 2.5_f32, b"\0\x01".as_ref()));
+    let back: (u32, String, i16, f32, Vec<u8>) = decode_key(&key).unwrap();
+    assert_eq!(back, (7, "user".to_string(), -3, 2.5, vec![0, 1]));
+}
+
+#[test]
+fn bad_keys_fail_to_decode() {
+    assert!(decode_key::<u64>(&[1, 2, 3]).is_err());
+    assert!(decode_key::<u16>(&[1, 2, 3]).is_err());
+    assert!(decode_key::<String>(b"abc").is_err());
+    assert!(decode_key::<String>(&[b'a', 0, 7]).is_err());
+    assert!(decode_key::<String>(&[0xc3, 0x28, 0, 1]).is_err());
+    assert!(decode_key::<(u8, String)>(&[1]).is_err());
+    assert_eq!(
+        decode_key::<(u8, String)>(&[1, b'x', 0, 1]).unwrap(),
+        (1, "x".to_string())
+    );
+}
@@ -0,0 +1,575 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::path::Path;
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use tigadb::db::DB;
+use tigadb::option::{Option, SyncPolicy};
+
+mod common;
+use common::{contents, dir, fresh, open, open_with, Rng};
+
+type Model = BTreeMap<Vec<u8>, Vec<u8>>;
+
+enum Op {
+    Put(Vec<u8>, Vec<u8>),
+    Delete(Vec<u8>),
+}
+
+// The same seed gives the same ops, so a test can redo what a writer did.
+fn ops(seed: u64, n: usize) -> Vec<Op> {
+    let mut rng = Rng(seed);
+    (0..n)
+        .map(|i| {
+            let key = format!("user/{:02}", rng.below(60)).into_bytes();
+            if rng.below(10) < 7 {
+                let len = rng.below(300) as usize;
+                Op::Put(key, vec![i as u8; len])
+            } else {
+                Op::Delete(key)
+            }
+        })
+        .collect()
+}
+
+fn do_op(db: &DB, op: &Op) {
+    match op {
+        Op::Put(key, value) => db.put(key.clone(), value.clone()).unwrap(),
+        Op::Delete(key) => db.delete(key).unwrap(),
+    }
+}
+
+fn model_op(model: &mut Model, op: &Op) {
+    match op {
+        Op::Put(key, value) => model.insert(key.clone(), value.clone()),
+        Op::Delete(key) => model.remove(key),
+    };
+}
+
+// The ids of the WAL segments on disk, in order.
+fn segments(name: &str) -> Vec<u64> {
+    let mut ids: Vec<u64> = fs::read_dir(format!("{}/meta/wal", dir(name)))
+        .unwrap()
+        .filter_map(|entry| {
+            let name = entry.unwrap().file_name().into_string().unwrap();
+            name.strip_suffix(".log")?.parse().ok()
+        })
+        .collect();
+    ids.sort_unstable();
+    ids
+}
+
+fn copy_dir(from: &Path, to: &Path) {
+    fs::create_dir_all(to).unwrap();
+    for entry in fs::read_dir(from).unwrap() {
+        let entry = entry.unwrap();
+        let target = to.join(entry.file_name());
+        if entry.file_type().unwrap().is_dir() {
+            copy_dir(&entry.path(), &target);
+        } else {
+            fs::copy(entry.path(), target).unwrap();
+        }
+    }
+}
+
+// The files of a db besides its WAL.
+const STORAGE_FILES: [&str; 3] = ["kv/kv.data", "meta/kv.meta", "meta/kv.index"];
+
+// Puts the given files of the db from in the place of those of the db to.
+// A file from does not have yet is removed from to.
+fn copy_files(from: &str, to: &str, files: &[&str]) {
+    for file in files {
+        let source = format!("{}/{}", dir(from), file);
+        let target = format!("{}/{}", dir(to), file);
+        fs::create_dir_all(Path::new(&target).parent().unwrap()).unwrap();
+        let _ = fs::remove_file(&target);
+        if Path::new(&source).exists() {
+            fs::copy(&source, &target).unwrap();
+        }
+    }
+}
+
+// A crash can stop the WAL at any byte, the db must come back as it was
+// after the last whole batch, and go on from there.
+#[test]
+fn crash_at_random_wal_offsets() {
+    let name = "crash_at_random_wal_offsets";
+    let _ = fs::remove_dir_all(dir(name));
+    let ops = ops(0x0C7A_54ED, 300);
+    let wal = format!("{}/meta/wal/000001.log", dir(name));
+
+    let db = open(name);
+    let mut models = vec![Model::new()];
+    let mut ends = vec![fs::metadata(&wal).unwrap().len()];
+    for op in ops.iter() {
+        do_op(&db, op);
+        let mut model = models.last().unwrap().clone();
+        model_op(&mut model, op);
+        models.push(model);
+        ends.push(fs::metadata(&wal).unwrap().len());
+    }
+    drop(db);
+
+    let mut rng = Rng(0x0FF5E7);
+    let end = *ends.last().unwrap();
+    let mut offsets: Vec<u64> = (0..40).map(|_| rng.below(end + 1)).collect();
+    offsets.extend_from_slice(&[0, 1, ends[1], ends[1] + 1, end - 1, end]);
+    let crashed = format!("{}-crashed", name);
+    for offset in offsets {
+        let _ = fs::remove_dir_all(dir(&crashed));
+        copy_dir(Path::new(&dir(name)), Path::new(&dir(&crashed)));
+        let wal = format!("{}/meta/wal/000001.log", dir(&crashed));
+        OpenOptions::new()
+            .write(true)
+            .open(&wal)
+            .unwrap()
+            .set_len(offset)
+            .unwrap();
+
+        // The ops whose record ends by offset survive.
+        let applied = ends.iter().skip(1).filter(|e| **e <= offset).count();
+        let db = open(&crashed);
+        assert_eq!(contents(&db), models[applied], "offset {}", offset);
+
+        db.put(b"after/crash".to_vec(), b"1".to_vec()).unwrap();
+        drop(db);
+        let db = open(&crashed);
+        let mut expected = models[applied].clone();
+        expected.insert(b"after/crash".to_vec(), b"1".to_vec());
+        assert_eq!(contents(&db), expected, "offset {}", offset);
+    }
+}
+
+// The meta file may be cut anywhere too, even in its header,
+// the db opens with what it had and its new writes take no block of a kept kv.
+#[test]
+fn crash_at_random_meta_offsets() {
+    let name = "crash_at_random_meta_offsets";
+    let _ = fs::remove_dir_all(dir(name));
+    let ops = ops(0x3E7A_C0DE, 300);
+    let mut model = Model::new();
+    let db = open(name);
+    for (i, op) in ops.iter().enumerate() {
+        do_op(&db, op);
+        model_op(&mut model, op);
+        if i == 150 {
+            db.checkpoint().unwrap();
+        }
+    }
+    drop(db);
+    let meta = format!("{}/meta/kv.meta", dir(name));
+    let end = fs::metadata(&meta).unwrap().len();
+
+    let mut rng = Rng(0x3E7A);
+    let mut offsets: Vec<u64> = (0..30).map(|_| rng.below(end + 1)).collect();
+    offsets.extend_from_slice(&[0, 1, 3, 4, 5, end - 1, end]);
+    let crashed = format!("{}-crashed", name);
+    for offset in offsets {
+        let _ = fs::remove_dir_all(dir(&crashed));
+        copy_dir(Path::new(&dir(name)), Path::new(&dir(&crashed)));
+        let meta = format!("{}/meta/kv.meta", dir(&crashed));
+        OpenOptions::new()
+            .write(true)
+            .open(&meta)
+            .unwrap()
+            .set_len(offset)
+            .unwrap();
+
+        let db = open(&crashed);
+        assert_eq!(contents(&db), model, "offset {}", offset);
+        let mut expected = model.clone();
+        for (i, key) in model.keys().enumerate().filter(|(i, _)| i % 2 == 0) {
+            db.put(key.clone(), vec![0xC7; i * 7 % 600]).unwrap();
+            expected.insert(key.clone(), vec![0xC7; i * 7 % 600]);
+        }
+        db.put(b"after/crash".to_vec(), vec![0xC8; 900]).unwrap();
+        expected.insert(b"after/crash".to_vec(), vec![0xC8; 900]);
+        assert_eq!(contents(&db), expected, "offset {}", offset);
+        drop(db);
+        let db = open(&crashed);
+        assert_eq!(contents(&db), expected, "offset {}", offset);
+    }
+}
+
+// The WAL is read a buffer at a time on open, records which straddle two reads
+// come back whole, and one cut short at the end is dropped.
+#[test]
+fn wal_records_across_read_buffers() {
+    let name = "wal_records_across_read_buffers";
+    let db = fresh(name);
+    let mut models = vec![Model::new()];
+    for i in 0..20_usize {
+        let mut model = models.last().unwrap().clone();
+        let key = format!("big/{:02}", i % 7).into_bytes();
+        let value = vec![i as u8; 20_000 + i * 2311];
+        db.put(key.clone(), value.clone()).unwrap();
+        model.insert(key, value);
+        models.push(model);
+    }
+    drop(db);
+    let db = open(name);
+    assert_eq!(contents(&db), models[20]);
+    drop(db);
+
+    let wal = format!("{}/meta/wal/000001.log", dir(name));
+    let file = OpenOptions::new().write(true).open(&wal).unwrap();
+    file.set_len(file.metadata().unwrap().len() - 1000).unwrap();
+    drop(file);
+    let db = open(name);
+    assert_eq!(contents(&db), models[19]);
+}
+
+// Small segments roll often, they stay until a checkpoint covers them.
+#[test]
+fn wal_segments_are_deleted_after_checkpoint() {
+    let name = "wal_segments_are_deleted_after_checkpoint";
+    let _ = fs::remove_dir_all(dir(name));
+    let opt = Option {
+        limit_per_file: 4096,
+        ..Option::default()
+    };
+    let ops = ops(0x5E6_3E47, 400);
+
+    let db = open_with(name, opt);
+    let mut model = Model::new();
+    for op in ops[..200].iter() {
+        do_op(&db, op);
+        model_op(&mut model, op);
+    }
+    let before = segments(name);
+    assert!(before.len() > 3, "{:?}", before);
+    // A reopen replays every segment.
+    drop(db);
+    let db = open_with(name, opt);
+    assert_eq!(contents(&db), model);
+    assert_eq!(segments(name), before);
+
+    db.checkpoint().unwrap();
+    assert_eq!(segments(name), vec![*before.last().unwrap()]);
+    for op in ops[200..].iter() {
+        do_op(&db, op);
+        model_op(&mut model, op);
+    }
+    let after = segments(name);
+    assert_eq!(after[0], *before.last().unwrap());
+    assert!(after.len() > 1);
+    drop(db);
+
+    // The numbering goes on from the newest segment.
+    let db = open_with(name, opt);
+    assert_eq!(contents(&db), model);
+    assert_eq!(segments(name), after);
+}
+
+// Writes ops until cond holds, or fails after a while.
+fn write_until(
+    db: &DB,
+    model: &mut Model,
+    ops: &mut impl Iterator<Item = Op>,
+    cond: impl Fn(&DB) -> bool,
+) {
+    let start = Instant::now();
+    while !cond(db) {
+        assert!(start.elapsed() < Duration::from_secs(20), "timed out");
+        let op = ops.next().unwrap();
+        do_op(db, &op);
+        model_op(model, &op);
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+#[test]
+fn background_checkpoints_reclaim_wal() {
+    let name = "background_checkpoints_reclaim_wal";
+    let _ = fs::remove_dir_all(dir(name));
+    let opt = Option {
+        limit_per_file: 4096,
+        checkpoint_interval_ms: 20,
+        ..Option::default()
+    };
+    let mut ops = ops(0xBAC6_0C4E, 100_000).into_iter();
+    let db = open_with(name, opt);
+    let mut model = Model::new();
+    write_until(&db, &mut model, &mut ops, |db| {
+        let stats = db.checkpoint_stats();
+        stats.checkpoints >= 3 && stats.reclaimed_segments > 0
+    });
+    let stats = db.checkpoint_stats();
+    assert_eq!(stats.failures, 0, "{:?}", stats);
+    assert!(db.last_checkpoint() >= 3);
+    // Only the segments after the last checkpoints are left.
+    assert!(segments(name)[0] > 1);
+    drop(db);
+
+    let db = open_with(name, opt);
+    assert_eq!(contents(&db), model);
+}
+
+static CHECKPOINT_ERRORS: AtomicUsize = AtomicUsize::new(0);
+
+fn count_checkpoint_error(_: &std::io::Error) {
+    CHECKPOINT_ERRORS.fetch_add(1, Ordering::SeqCst);
+}
+
+#[test]
+fn background_checkpoint_errors_are_reported() {
+    let name = "background_checkpoint_errors_are_reported";
+    let _ = fs::remove_dir_all(dir(name));
+    let opt = Option {
+        limit_per_file: 4096,
+        checkpoint_interval_ms: 10,
+        on_checkpoint_error: Some(count_checkpoint_error),
+        ..Option::default()
+    };
+    let mut ops = ops(0xE4404, 100_000).into_iter();
+    let db = open_with(name, opt);
+    let mut model = Model::new();
+
+    // The image cannot be written while a dir is in the place of its temp file.
+    let tmp = format!("{}/meta/kv.index.tmp", dir(name));
+    fs::create_dir_all(&tmp).unwrap();
+    write_until(&db, &mut model, &mut ops, |db| {
+        db.checkpoint_stats().failures >= 3
+    });
+    let stats = db.checkpoint_stats();
+    assert!(stats.last_error.is_some());
+    assert_eq!(stats.checkpoints, 0);
+    assert_eq!(db.last_checkpoint(), 0);
+    assert!(CHECKPOINT_ERRORS.load(Ordering::SeqCst) >= 3);
+    // Nothing was reclaimed, the WAL still holds every batch.
+    assert_eq!(segments(name)[0], 1);
+    assert!(db.checkpoint().is_err());
+
+    // A restart replays the batches of the checkpoints which failed.
+    drop(db);
+    let db = open_with(name, opt);
+    assert_eq!(contents(&db), model);
+
+    fs::remove_dir(&tmp).unwrap();
+    write_until(&db, &mut model, &mut ops, |db| {
+        db.checkpoint_stats().checkpoints >= 2
+    });
+    assert!(db.last_checkpoint() > 0);
+    drop(db);
+    let db = open_with(name, opt);
+    assert_eq!(contents(&db), model);
+}
+
+// A backup may bring back the kv data, the meta and the index image of some time ago,
+// next to a WAL which went on. The WAL holds every batch after that image, it is replayed over them.
+#[test]
+fn older_storage_files_replay_the_newer_wal() {
+    let name = "older_storage_files_replay_the_newer_wal";
+    let _ = fs::remove_dir_all(dir(name));
+    let ops = ops(0x01D_F11E5, 600);
+
+    let db = open(name);
+    let mut model = Model::new();
+    let mut copies = Vec::new();
+    for (i, op) in ops.iter().enumerate() {
+        if i % 150 == 0 {
+            let copy = format!("{}-old-{}", name, copies.len());
+            let _ = fs::remove_dir_all(dir(&copy));
+            copy_files(name, &copy, &STORAGE_FILES);
+            copies.push(copy);
+        }
+        do_op(&db, op);
+        model_op(&mut model, op);
+        if i % 100 == 99 {
+            db.checkpoint().unwrap();
+        }
+    }
+    drop(db);
+    // One segment holds the whole WAL, so the checkpoints reclaimed nothing.
+    assert_eq!(segments(name), vec![1]);
+
+    let restored = format!("{}-restored", name);
+    for copy in copies.iter() {
+        let _ = fs::remove_dir_all(dir(&restored));
+        copy_dir(Path::new(&dir(name)), Path::new(&dir(&restored)));
+        copy_files(copy, &restored, &STORAGE_FILES);
+        let db = open(&restored);
+        assert_eq!(contents(&db), model, "{}", copy);
+
+        db.put(b"after/restore".to_vec(), b"1".to_vec()).unwrap();
+        drop(db);
+        let mut expected = model.clone();
+        expected.insert(b"after/restore".to_vec(), b"1".to_vec());
+        assert_eq!(contents(&open(&restored)), expected, "{}", copy);
+    }
+}
+
+// Without syncs the kv data may get to disk ahead of the WAL, the meta and the index image.
+// The db comes back as the WAL left it, whatever the later writes put in the kv data.
+#[test]
+fn newer_kv_data_than_wal() {
+    let name = "newer_kv_data_than_wal";
+    let _ = fs::remove_dir_all(dir(name));
+    let opt = Option {
+        sync: SyncPolicy::Never,
+        ..Option::default()
+    };
+    let ops = ops(0x0E1_DA7A, 600);
+
+    let db = open_with(name, opt);
+    let mut model = Model::new();
+    for op in ops[..200].iter() {
+        do_op(&db, op);
+        model_op(&mut model, op);
+    }
+    db.checkpoint().unwrap();
+    for op in ops[200..300].iter() {
+        do_op(&db, op);
+        model_op(&mut model, op);
+    }
+    let crashed = format!("{}-crashed", name);
+    let _ = fs::remove_dir_all(dir(&crashed));
+    copy_dir(Path::new(&dir(name)), Path::new(&dir(&crashed)));
+
+    // These writes are not synced, only their kv data gets to disk before the crash.
+    for op in ops[300..].iter() {
+        do_op(&db, op);
+    }
+    copy_files(name, &crashed, &STORAGE_FILES[..1]);
+    drop(db);
+
+    let db = open_with(&crashed, opt);
+    assert_eq!(contents(&db), model);
+}
+
+// The sizes of the WAL segments on disk, by id.
+fn segment_sizes(name: &str) -> BTreeMap<u64, u64> {
+    segments(name)
+        .into_iter()
+        .map(|id| {
+            let path = format!("{}/meta/wal/{:06}.log", dir(name), id);
+            (id, fs::metadata(path).unwrap().len())
+        })
+        .collect()
+}
+
+// With lazy syncs the kv data may get to disk before the WAL does. After a crash
+// the index image of the last checkpoint must still read the values it points at,
+// so the blocks freed after it are not written over before their batches are durable.
+#[test]
+fn unsynced_writes_keep_checkpointed_blocks() {
+    let name = "unsynced_writes_keep_checkpointed_blocks";
+    let _ = fs::remove_dir_all(dir(name));
+    let opt = Option {
+        sync: SyncPolicy::Never,
+        ..Option::default()
+    };
+    let db = open_with(name, opt);
+    let mut model = Model::new();
+    for i in 0..20 {
+        let op = Op::Put(format!("user/{:02}", i).into_bytes(), vec![i as u8; 300]);
+        do_op(&db, &op);
+        model_op(&mut model, &op);
+    }
+    db.checkpoint().unwrap();
+    let synced = segment_sizes(name);
+
+    // The overwrites and deletes free the checkpointed blocks, the new keys want them.
+    for i in 0..20 {
+        let key = format!("user/{:02}", i).into_bytes();
+        if i % 2 == 0 {
+            do_op(&db, &Op::Delete(key));
+        } else {
+            do_op(&db, &Op::Put(key, vec![0xEE; 300]));
+        }
+    }
+    for i in 0..40 {
+        let op = Op::Put(format!("new/{:02}", i).into_bytes(), vec![0xDD; 300]);
+        do_op(&db, &op);
+    }
+    drop(db);
+
+    // The crash keeps the kv data but loses the WAL after the checkpoint.
+    let crashed = format!("{}-crashed", name);
+    let _ = fs::remove_dir_all(dir(&crashed));
+    copy_dir(Path::new(&dir(name)), Path::new(&dir(&crashed)));
+    for id in segments(&crashed) {
+        let wal = format!("{}/meta/wal/{:06}.log", dir(&crashed), id);
+        match synced.get(&id) {
+            Some(len) => OpenOptions::new()
+                .write(true)
+                .open(&wal)
+                .unwrap()
+                .set_len(*len)
+                .unwrap(),
+            None => fs::remove_file(&wal).unwrap(),
+        }
+    }
+    let db = open_with(&crashed, opt);
+    assert_eq!(contents(&db), model);
+}
+
+const WRITER_ENV: &str = "TIGADB_CRASH_WRITER";
+const WRITER_OPS: usize = 3000;
+
+// Run as a child process by killed_writer_recovers_a_prefix, it writes until it is killed.
+// Background checkpoints run too, so the kill may also hit one of them.
+fn run_writer(name: &str, seed: u64) {
+    let opt = Option {
+        checkpoint_interval_ms: 5,
+        ..Option::default()
+    };
+    let db = open_with(name, opt);
+    for (i, op) in ops(seed, WRITER_OPS).iter().enumerate() {
+        do_op(&db, op);
+        if i % 97 == 96 {
+            db.checkpoint().unwrap();
+        }
+    }
+    loop {
+        thread::sleep(Duration::from_secs(1));
+    }
+}
+
+#[test]
+fn killed_writer_recovers_a_prefix() {
+    if let Ok(arg) = env::var(WRITER_ENV) {
+        let (name, seed) = arg.split_once(':').unwrap();
+        run_writer(name, seed.parse().unwrap());
+        return;
+    }
+
+    let mut rng = Rng(0x5EED_C0DE);
+    let mut written = 0;
+    for round in 0..4 {
+        let name = format!("killed_writer_{}", round);
+        let _ = fs::remove_dir_all(dir(&name));
+        let seed = rng.next() | 1;
+        let mut child = Command::new(env::current_exe().unwrap())
+            .args(["killed_writer_recovers_a_prefix", "--exact", "--nocapture"])
+            .env(WRITER_ENV, format!("{}:{}", name, seed))
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_millis(50 + rng.below(300)));
+        child.kill().unwrap();
+        child.wait().unwrap();
+
+        // Whatever was written, it is the state after some first ops.
+        let recovered = contents(&open(&name));
+        let mut model = Model::new();
+        let mut found = recovered == model;
+        for op in ops(seed, WRITER_OPS).iter() {
+            if found {
+                break;
+            }
+            model_op(&mut model, op);
+            found = recovered == model;
+        }
+        assert!(
+            found,
+            "round {} recovered a state no prefix of ops gives",
+            round
+        );
+        written += recovered.len();
+    }
+    // The writers got to write something before being killed.
+    assert!(written > 0);
+}
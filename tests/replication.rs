@@ -0,0 +1,314 @@
+use std::io::{self, ErrorKind};
+use std::os::unix::net::UnixStream;
+use std::sync::mpsc;
+use std::time::Duration;
+use tigadb::db::DB;
+use tigadb::option::Option;
+use tigadb::replication::{
+    channel_pair, ship, ChannelTransport, Follower, Transport, UnixTransport,
+};
+
+mod common;
+use common::{contents, fresh_with, open_with};
+
+const WAIT: Duration = Duration::from_secs(10);
+
+// Small segments, so that the WAL rolls and a checkpoint reclaims some of it.
+fn opt(read_only: bool) -> Option {
+    Option {
+        limit_per_file: 4096,
+        read_only,
+        ..Option::default()
+    }
+}
+
+fn write(db: &DB, from: usize, n: usize) {
+    for i in from..from + n {
+        let key = format!("key/{:02}", i % 41).into_bytes();
+        if i % 5 == 4 {
+            db.delete(&key).unwrap();
+        } else {
+            db.put(key, vec![i as u8; i % 70]).unwrap();
+        }
+    }
+}
+
+// The follower gets what the leader wrote before and after it joined.
+fn follow<T: Transport + 'static>(name: &str, leader_end: T, follower_end: T) {
+    let leader = fresh_with(&format!("{}_leader", name), opt(false));
+    write(&leader, 0, 150);
+    let shipper = ship(&leader, leader_end);
+
+    let db = fresh_with(&format!("{}_follower", name), opt(true));
+    let mut follower = Follower::new(db, follower_end).unwrap();
+    follower.catch_up(leader.last_seq(), WAIT).unwrap();
+    assert_eq!(contents(follower.db()), contents(&leader));
+    assert_eq!(follower.applied_checkpoint(), 0);
+
+    leader.checkpoint().unwrap();
+    write(&leader, 150, 150);
+    follower.catch_up(leader.last_seq(), WAIT).unwrap();
+    assert_eq!(follower.applied_seq(), leader.last_seq());
+    assert_eq!(follower.applied_checkpoint(), 1);
+    assert_eq!(contents(follower.db()), contents(&leader));
+
+    // Once the leader is closed, the shipper ends after sending everything.
+    drop(leader);
+    shipper.join().unwrap().unwrap();
+}
+
+#[test]
+fn follower_over_channel() {
+    let (leader_end, follower_end) = channel_pair();
+    follow("follower_over_channel", leader_end, follower_end);
+}
+
+#[test]
+fn follower_over_unix_socket() {
+    let (a, b) = UnixStream::pair().unwrap();
+    follow(
+        "follower_over_unix_socket",
+        UnixTransport::new(a),
+        UnixTransport::new(b),
+    );
+}
+
+#[test]
+fn follower_restarts_and_is_promoted() {
+    let leader_name = "follower_restarts_leader";
+    let follower_name = "follower_restarts_follower";
+    let leader = fresh_with(leader_name, opt(false));
+    write(&leader, 0, 100);
+
+    let (leader_end, follower_end) = channel_pair();
+    let shipper = ship(&leader, leader_end);
+    let mut follower = Follower::new(fresh_with(follower_name, opt(true)), follower_end).unwrap();
+    follower.catch_up(leader.last_seq(), WAIT).unwrap();
+    // The follower has the leader checkpoints 1 and 2 whole once it has a batch of the 3rd.
+    leader.checkpoint().unwrap();
+    leader.checkpoint().unwrap();
+    write(&leader, 100, 20);
+    follower.catch_up(leader.last_seq(), WAIT).unwrap();
+    assert_eq!(follower.applied_checkpoint(), 2);
+    drop(follower);
+    // The shipper fails once it has something to send to the gone follower.
+    write(&leader, 120, 30);
+    assert!(shipper.join().unwrap().is_err());
+
+    // A restarted follower asks for the batches after its last one,
+    // and has the leader checkpoints it had.
+    let (leader_end, follower_end) = channel_pair();
+    let shipper = ship(&leader, leader_end);
+    let mut follower = Follower::new(open_with(follower_name, opt(true)), follower_end).unwrap();
+    assert_eq!(follower.applied_checkpoint(), 2);
+    write(&leader, 150, 100);
+    follower.catch_up(leader.last_seq(), WAIT).unwrap();
+    assert_eq!(contents(follower.db()), contents(&leader));
+    // The follower writes its index image under the leader checkpoint.
+    leader.checkpoint().unwrap();
+    write(&leader, 250, 10);
+    follower.catch_up(leader.last_seq(), WAIT).unwrap();
+    assert_eq!(follower.db().checkpoint().unwrap(), 3);
+    assert_eq!(follower.db().last_checkpoint(), 3);
+
+    // The leader goes away and the follower takes over.
+    let expected = contents(&leader);
+    let last_seq = leader.last_seq();
+    drop(leader);
+    shipper.join().unwrap().unwrap();
+    let db = follower.promote();
+    assert!(!db.is_read_only());
+    db.put(b"after/promote".to_vec(), b"1".to_vec()).unwrap();
+    assert_eq!(db.last_seq(), last_seq + 1);
+    // Its checkpoints go on after the ones of the leader.
+    assert_eq!(db.checkpoint().unwrap(), 4);
+    drop(db);
+
+    let db = open_with(follower_name, opt(false));
+    let mut expected = expected;
+    expected.insert(b"after/promote".to_vec(), b"1".to_vec());
+    assert_eq!(contents(&db), expected);
+    assert_eq!(db.last_seq(), last_seq + 1);
+    assert_eq!(db.last_checkpoint(), 4);
+}
+
+#[test]
+fn follower_needs_a_read_only_db() {
+    let db = fresh_with("read_only_db", opt(true));
+    assert_eq!(
+        db.put(b"a".to_vec(), b"1".to_vec()).unwrap_err().kind(),
+        ErrorKind::PermissionDenied
+    );
+    assert_eq!(
+        db.delete(b"a").unwrap_err().kind(),
+        ErrorKind::PermissionDenied
+    );
+
+    let (_, follower_end) = channel_pair();
+    let writable = fresh_with("writable_follower", opt(false));
+    assert!(Follower::new(writable, follower_end).is_err());
+}
+
+// A follower with batches the leader never logged, as when the leader came back
+// from an older backup, is refused instead of waiting for batches which differ.
+#[test]
+fn follower_ahead_of_the_leader_is_refused() {
+    let first = fresh_with("ahead_first_leader", opt(false));
+    write(&first, 0, 50);
+    let (leader_end, follower_end) = channel_pair();
+    let shipper = ship(&first, leader_end);
+    let mut follower =
+        Follower::new(fresh_with("ahead_follower", opt(true)), follower_end).unwrap();
+    follower.catch_up(first.last_seq(), WAIT).unwrap();
+    let last_seq = first.last_seq();
+    drop(first);
+    shipper.join().unwrap().unwrap();
+
+    let older = fresh_with("ahead_older_leader", opt(false));
+    write(&older, 0, 20);
+    let (leader_end, follower_end) = channel_pair();
+    let shipper = ship(&older, leader_end);
+    drop(follower);
+    let db = open_with("ahead_follower", opt(true));
+    let mut follower = Follower::new(db, follower_end).unwrap();
+    let e = follower.replay(WAIT).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::InvalidInput);
+    assert_eq!(
+        shipper.join().unwrap().unwrap_err().kind(),
+        ErrorKind::InvalidInput
+    );
+    assert_eq!(follower.applied_seq(), last_seq);
+}
+
+// A follower which starts after the leader reclaimed the first batches,
+// or comes back after it reclaimed the ones it lacks, gets a snapshot of the leader first.
+#[test]
+fn follower_restores_a_snapshot_after_leader_checkpoint() {
+    let leader = fresh_with("restore_leader", opt(false));
+    write(&leader, 0, 200);
+    leader.checkpoint().unwrap();
+    assert!(leader.subscribe_changes(1).is_err());
+
+    let follower_name = "restore_follower";
+    let (leader_end, follower_end) = channel_pair();
+    let shipper = ship(&leader, leader_end);
+    let mut follower = Follower::new(fresh_with(follower_name, opt(true)), follower_end).unwrap();
+    follower.catch_up(leader.last_seq(), WAIT).unwrap();
+    assert_eq!(contents(follower.db()), contents(&leader));
+    assert!(follower.applied_checkpoint() > 1);
+    write(&leader, 200, 50);
+    follower.catch_up(leader.last_seq(), WAIT).unwrap();
+    assert_eq!(contents(follower.db()), contents(&leader));
+    drop(follower);
+    write(&leader, 250, 10);
+    assert!(shipper.join().unwrap().is_err());
+
+    // The leader goes on and reclaims what the follower lacks,
+    // the follower has keys the leader has deleted since.
+    write(&leader, 260, 300);
+    leader.checkpoint().unwrap();
+    let follower_seq = open_with(follower_name, opt(true)).last_seq();
+    assert!(leader.subscribe_changes(follower_seq + 1).is_err());
+
+    let (leader_end, follower_end) = channel_pair();
+    let shipper = ship(&leader, leader_end);
+    let mut follower = Follower::new(open_with(follower_name, opt(true)), follower_end).unwrap();
+    follower.catch_up(leader.last_seq(), WAIT).unwrap();
+    assert_eq!(contents(follower.db()), contents(&leader));
+    write(&leader, 560, 50);
+    follower.catch_up(leader.last_seq(), WAIT).unwrap();
+    assert_eq!(contents(follower.db()), contents(&leader));
+
+    // The restore is durable, the follower comes back where it was.
+    drop(follower);
+    let db = open_with(follower_name, opt(true));
+    assert_eq!(db.last_seq(), leader.last_seq());
+    assert_eq!(contents(&db), contents(&leader));
+    drop(db);
+    drop(leader);
+    shipper.join().unwrap().unwrap();
+}
+
+// Stops the shipper at the first message of each kind in stops, until it is let go.
+struct Gate {
+    inner: ChannelTransport,
+    stops: Vec<u8>,
+    stopped: mpsc::Sender<()>,
+    go: mpsc::Receiver<()>,
+}
+
+impl Transport for Gate {
+    fn send(&mut self, msg: &[u8]) -> io::Result<()> {
+        if self.stops.first() == msg.first() {
+            self.stops.remove(0);
+            self.stopped.send(()).unwrap();
+            self.go.recv().unwrap();
+        }
+        self.inner.send(msg)
+    }
+
+    fn recv(&mut self, timeout: Duration) -> io::Result<std::option::Option<Vec<u8>>> {
+        self.inner.recv(timeout)
+    }
+}
+
+// The snapshot is the leader as of one batch, whatever is written while it is sent.
+#[test]
+fn snapshot_is_taken_at_one_batch() {
+    let leader = fresh_with("snapshot_leader", opt(false));
+    for i in 0..500 {
+        let key = format!("key/{:03}", i).into_bytes();
+        leader.put(key, vec![i as u8; 200]).unwrap();
+    }
+    leader.checkpoint().unwrap();
+    let expected = contents(&leader);
+    let seq = leader.last_seq();
+
+    // The messages of the kv pairs start with 4, and those of the batches with 2.
+    let (leader_end, follower_end) = channel_pair();
+    let (stopped_tx, stopped) = mpsc::channel();
+    let (go, go_rx) = mpsc::channel();
+    let gate = Gate {
+        inner: leader_end,
+        stops: vec![4, 2],
+        stopped: stopped_tx,
+        go: go_rx,
+    };
+    let shipper = ship(&leader, gate);
+    let mut follower =
+        Follower::new(fresh_with("snapshot_follower", opt(true)), follower_end).unwrap();
+
+    // The writes free the blocks of the snapshot, and take them over if they can.
+    stopped.recv().unwrap();
+    for i in 0..500 {
+        let key = format!("key/{:03}", i).into_bytes();
+        if i % 2 == 0 {
+            leader.delete(&key).unwrap();
+        }
+        leader
+            .put(format!("new/{:03}", i).into_bytes(), vec![0xEE; 200])
+            .unwrap();
+    }
+    go.send(()).unwrap();
+    stopped.recv().unwrap();
+    follower.catch_up(seq, WAIT).unwrap();
+    assert_eq!(follower.applied_seq(), seq);
+    assert_eq!(contents(follower.db()), expected);
+    let checkpoint = follower.applied_checkpoint();
+    assert!(checkpoint > 1);
+
+    // The restored follower has no batch in its WAL yet, it comes back from its index image.
+    drop(follower);
+    go.send(()).unwrap();
+    assert!(shipper.join().unwrap().is_err());
+    let (leader_end, follower_end) = channel_pair();
+    let shipper = ship(&leader, leader_end);
+    let db = open_with("snapshot_follower", opt(true));
+    let mut follower = Follower::new(db, follower_end).unwrap();
+    assert_eq!(follower.applied_seq(), seq);
+    assert_eq!(follower.applied_checkpoint(), checkpoint);
+    follower.catch_up(leader.last_seq(), WAIT).unwrap();
+    assert_eq!(contents(follower.db()), contents(&leader));
+    drop(leader);
+    shipper.join().unwrap().unwrap();
+}